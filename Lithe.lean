@@ -49,6 +49,7 @@ import Lithe.Runtime.Dispatch
 import Lithe.Runtime.Registry
 import Lithe.Runtime.AppRegistry
 import Lithe.Runtime.WSRegistry
+import Lithe.Runtime.Notify
 import Lithe.App
 
 import Lithe.Middleware.RequestId
@@ -6,21 +6,47 @@ import Lithe.Http.Status
 
 namespace Lithe
 
-def wireVersion : UInt8 := 1
-def streamWireVersion : UInt8 := 1
-
-private def byteArrayOfList (xs : List UInt8) : ByteArray :=
-  xs.foldl (fun acc b => acc.push b) ByteArray.empty
+/-- Original encoding: `u32` length prefixes and literal header names. -/
+def wireVersionV1 : UInt8 := 1
+/-- Compact encoding: varint length prefixes and indexed common header names. -/
+def wireVersion : UInt8 := 2
+def streamWireVersionV1 : UInt8 := 1
+def streamWireVersion : UInt8 := 2
+
+/--
+Header names encoded as an index in v2; index `i + 1` on the wire names
+`headerTable[i]`, and `0` introduces a literal name. Must match `HEADER_TABLE`
+in the shim's `wire.rs`.
+-/
+def headerTable : Array String := #[
+  "host", "user-agent", "accept", "accept-encoding", "accept-language",
+  "connection", "content-type", "content-length", "cookie", "set-cookie",
+  "cache-control", "authorization", "referer", "origin", "upgrade",
+  "location", "date", "etag", "last-modified", "if-none-match",
+  "if-modified-since", "x-forwarded-for", "x-request-id", "transfer-encoding",
+  "content-encoding", "vary", "server", "sec-websocket-key",
+  "sec-websocket-version", "sec-websocket-accept", "access-control-allow-origin",
+  "x-lithe-ws-id"
+]
+
+@[inline] def headerIndex? (name : String) : Option Nat :=
+  headerTable.findIdx? (· == name.toLower)
 
 structure Writer where
   buf : ByteArray
+  /-- Whether to use the v2 encoding. -/
+  compact : Bool := true
+  deriving Inhabited
 
 namespace Writer
 
 @[inline] def empty : Writer := { buf := ByteArray.empty }
 
+@[inline] def ofVersion (version : UInt8) : Writer :=
+  { buf := ByteArray.empty, compact := version != wireVersionV1 }
+
 @[inline] def writeU8 (w : Writer) (b : UInt8) : Writer :=
-  { buf := w.buf.push b }
+  { w with buf := w.buf.push b }
 
 private def u16Bytes (n : UInt16) : UInt8 × UInt8 :=
   let v := n.toNat
@@ -43,10 +69,20 @@ private def u32Bytes (n : UInt32) : UInt8 × UInt8 × UInt8 × UInt8 :=
   ((w.writeU8 b1).writeU8 b2 |>.writeU8 b3).writeU8 b4
 
 @[inline] def writeRaw (w : Writer) (b : ByteArray) : Writer :=
-  { buf := b.toList.foldl (fun acc x => acc.push x) w.buf }
+  { w with buf := w.buf ++ b }
+
+partial def writeVarint (w : Writer) (n : Nat) : Writer :=
+  if n < 128 then
+    w.writeU8 (UInt8.ofNat n)
+  else
+    (w.writeU8 (UInt8.ofNat (n % 128 + 128))).writeVarint (n / 128)
+
+/-- A length or count: `u32` in v1, varint in v2. -/
+@[inline] def writeLen (w : Writer) (n : Nat) : Writer :=
+  if w.compact then w.writeVarint n else w.writeU32 (UInt32.ofNat n)
 
 @[inline] def writeBytes (w : Writer) (b : ByteArray) : Writer :=
-  (w.writeU32 (UInt32.ofNat b.size)).writeRaw b
+  (w.writeLen b.size).writeRaw b
 
 @[inline] def writeString (w : Writer) (s : String) : Writer :=
   w.writeBytes (stringToBytes s)
@@ -56,20 +92,42 @@ private def u32Bytes (n : UInt32) : UInt8 × UInt8 × UInt8 × UInt8 :=
   | none => w.writeU8 0
   | some v => (w.writeU8 1).writeString v
 
+@[inline] def writeHeaderName (w : Writer) (name : String) : Writer :=
+  if w.compact then
+    match headerIndex? name with
+    | some i => w.writeVarint (i + 1)
+    | none => (w.writeVarint 0).writeString name
+  else
+    w.writeString name
+
+/-- Writes `headers` then `raw` as one header block. -/
+@[inline] def writeHeaders (w : Writer) (headers : Array (String × String)) (raw : RawHeaders := #[]) : Writer :=
+  let w := headers.foldl (init := w.writeLen (headers.size + raw.size)) (fun acc h =>
+    acc.writeHeaderName h.fst |>.writeString h.snd
+  )
+  raw.foldl (init := w) (fun acc h =>
+    acc.writeHeaderName h.fst |>.writeBytes h.snd
+  )
+
 end Writer
 
 structure Reader where
-  rest : List UInt8
+  data : ByteArray
+  pos  : Nat := 0
+  /-- Whether the input uses the v2 encoding. -/
+  compact : Bool := true
 
 namespace Reader
 
 @[inline] def ofByteArray (b : ByteArray) : Reader :=
-  { rest := b.toList }
+  { data := b }
+
 
 @[inline] def readU8 (r : Reader) : Except String (UInt8 × Reader) :=
-  match r.rest with
-  | [] => throw "unexpected eof"
-  | b :: rest => pure (b, { rest := rest })
+  if h : r.pos < r.data.size then
+    pure (r.data[r.pos]'h, { r with pos := r.pos + 1 })
+  else
+    throw "unexpected eof"
 
 @[inline] def readU16 (r : Reader) : Except String (UInt16 × Reader) := do
   let (b1, r) ← readU8 r
@@ -85,25 +143,46 @@ namespace Reader
   let v := b1.toNat * 16777216 + b2.toNat * 65536 + b3.toNat * 256 + b4.toNat
   pure (UInt32.ofNat v, r)
 
-private partial def takeN? (n : Nat) (xs : List UInt8) : Option (List UInt8 × List UInt8) :=
-  if n = 0 then
-    some ([], xs)
+/-- Reads the version byte and switches to the matching encoding. -/
+@[inline] def readVersion (v1 v2 : UInt8) (what : String) (r : Reader) : Except String (UInt8 × Reader) := do
+  let (ver, r) ← readU8 r
+  if ver == v1 then
+    pure (ver, { r with compact := false })
+  else if ver == v2 then
+    pure (ver, { r with compact := true })
+  else
+    throw s!"unsupported {what} version {ver.toNat}"
+
+private partial def readVarintLoop (r : Reader) (shift acc : Nat) : Except String (Nat × Reader) := do
+  if shift > 63 then
+    throw "varint too long"
+  let (b, r) ← readU8 r
+  let acc := acc + (b.toNat % 128) * 2 ^ shift
+  if b < 128 then
+    pure (acc, r)
+  else
+    readVarintLoop r (shift + 7) acc
+
+@[inline] def readVarint (r : Reader) : Except String (Nat × Reader) :=
+  readVarintLoop r 0 0
+
+/-- A length or count: `u32` in v1, varint in v2. -/
+@[inline] def readLen (r : Reader) : Except String (Nat × Reader) := do
+  if r.compact then
+    readVarint r
   else
-    match xs with
-    | [] => none
-    | x :: rest =>
-        match takeN? (n - 1) rest with
-        | some (taken, remaining) => some (x :: taken, remaining)
-        | none => none
+    let (n, r) ← readU32 r
+    pure (n.toNat, r)
 
 @[inline] def readRaw (n : Nat) (r : Reader) : Except String (ByteArray × Reader) :=
-  match takeN? n r.rest with
-  | some (taken, rest) => pure (byteArrayOfList taken, { rest := rest })
-  | none => throw "unexpected eof"
+  if r.pos + n ≤ r.data.size then
+    pure (r.data.extract r.pos (r.pos + n), { r with pos := r.pos + n })
+  else
+    throw "unexpected eof"
 
 @[inline] def readBytes (r : Reader) : Except String (ByteArray × Reader) := do
-  let (len, r) ← readU32 r
-  readRaw len.toNat r
+  let (len, r) ← readLen r
+  readRaw len r
 
 @[inline] def readString (r : Reader) : Except String (String × Reader) := do
   let (bytes, r) ← readBytes r
@@ -121,21 +200,54 @@ private partial def takeN? (n : Nat) (xs : List UInt8) : Option (List UInt8 × L
   else
     throw "invalid option flag"
 
+@[inline] def readHeaderName (r : Reader) : Except String (String × Reader) := do
+  if r.compact then
+    let (idx, r) ← readVarint r
+    if idx == 0 then
+      readString r
+    else
+      match headerTable[idx - 1]? with
+      | some name => pure (name, r)
+      | none => throw s!"unknown header index {idx}"
+  else
+    readString r
+
 private partial def readHeadersLoop
     (n : Nat) (r : Reader) (acc : Array (String × String)) :
     Except String (Array (String × String) × Reader) := do
   if n = 0 then
     pure (acc, r)
   else
-    let (k, r) ← readString r
+    let (k, r) ← readHeaderName r
     let (v, r) ← readString r
     readHeadersLoop (n - 1) r (acc.push (k, v))
 
 @[inline] def readHeaders (n : Nat) (r : Reader) : Except String (Array (String × String) × Reader) :=
   readHeadersLoop n r #[]
 
+private partial def readRawHeadersLoop (n : Nat) (r : Reader) (acc : RawHeaders) :
+    Except String (RawHeaders × Reader) := do
+  if n = 0 then
+    pure (acc, r)
+  else
+    let (k, r) ← readHeaderName r
+    let (v, r) ← readBytes r
+    readRawHeadersLoop (n - 1) r (acc.push (k, v))
+
+/-- Like `readHeaders`, but values may be any bytes. -/
+@[inline] def readRawHeaders (n : Nat) (r : Reader) : Except String (RawHeaders × Reader) :=
+  readRawHeadersLoop n r #[]
+
 end Reader
 
+/-- Splits raw headers into those with UTF-8 values and the rest, keeping order. -/
+def splitRawHeaders (raw : RawHeaders) : Headers × RawHeaders :=
+  raw.foldl (init := (#[], #[])) (fun (text, bytes) (k, v) =>
+    match bytesToString? v with
+    | some s => (text.push (k, s), bytes)
+    | none => (text, bytes.push (k, v))
+  )
+
 structure WireRequest where
   method  : Method
   path    : String
@@ -143,11 +255,39 @@ structure WireRequest where
   headers : Array (String × String)
   body    : ByteArray
   remote  : Option String := none
+  metadata : RequestMeta := {}
+  /-- All headers as received; when set, encoded instead of `headers`. -/
+  rawHeaders : RawHeaders := #[]
+
+/-!
+The v2 request ends with a metadata section: a count, then `(tag, bytes)`
+entries. Decoders skip unknown tags, so fields can be added without a new
+wire version. Tags must match `META_*` in the shim's `wire.rs`.
+-/
+def metaConnId : Nat := 1
+def metaLocalAddr : Nat := 2
+def metaScheme : Nat := 3
+def metaHttpVersion : Nat := 4
+def metaRawUri : Nat := 5
+def metaTlsVersion : Nat := 6
+def metaTlsCipher : Nat := 7
+def metaTlsSni : Nat := 8
+def metaTlsAlpn : Nat := 9
+def metaClientSubject : Nat := 10
+/-- Repeated once per subject alternative name. -/
+def metaClientSan : Nat := 11
+def metaClientFingerprint : Nat := 12
+def metaProxyPeer : Nat := 13
+def metaProxyAuthority : Nat := 14
+def metaProxyUniqueId : Nat := 15
+/-- Varint nanoseconds on `IO.monoNanosNow`'s clock. -/
+def metaDeadline : Nat := 16
 
 structure WireResponse where
   status  : Nat
   headers : Array (String × String)
   body    : ByteArray
+  rawHeaders : RawHeaders := #[]
 
 namespace WireRequest
 
@@ -158,6 +298,8 @@ namespace WireRequest
   , headers := wr.headers
   , body := wr.body
   , remote := wr.remote
+  , metadata := wr.metadata
+  , rawHeaders := wr.rawHeaders
   }
 
 end WireRequest
@@ -168,79 +310,199 @@ namespace WireResponse
   { status := resp.status.code.toNat
   , headers := resp.headers
   , body := resp.body
+  , rawHeaders := resp.rawHeaders
   }
 
 @[inline] def toResponse (wr : WireResponse) : Response :=
   { status := Status.ofCode (UInt16.ofNat wr.status)
   , headers := wr.headers
   , body := wr.body
+  , rawHeaders := wr.rawHeaders
   }
 
 end WireResponse
 
-@[inline] def encodeWireRequest (req : WireRequest) : ByteArray :=
-  let w := Writer.empty
-  let w := w.writeU8 wireVersion
+private def writeRequestMeta (w : Writer) (m : RequestMeta) : Writer :=
+  let entries : Array (Nat × ByteArray) := #[]
+  let entries := match m.connId with
+    | some id => entries.push (metaConnId, (Writer.empty.writeVarint id.toNat).buf)
+    | none => entries
+  let entries := match m.localAddr with
+    | some addr => entries.push (metaLocalAddr, stringToBytes addr)
+    | none => entries
+  let entries := entries.push (metaScheme, stringToBytes m.scheme)
+  let entries := entries.push (metaHttpVersion, stringToBytes m.httpVersion)
+  let entries := match m.rawUri with
+    | some uri => entries.push (metaRawUri, stringToBytes uri)
+    | none => entries
+  let optional : Array (Nat × Option String) :=
+    #[(metaTlsVersion, m.tlsVersion), (metaTlsCipher, m.tlsCipher), (metaTlsSni, m.tlsSni), (metaTlsAlpn, m.tlsAlpn)]
+  let entries := optional.foldl (init := entries) (fun acc (tag, v) =>
+    match v with
+    | some s => acc.push (tag, stringToBytes s)
+    | none => acc
+  )
+  let entries := match m.clientCert with
+    | some cert =>
+        let entries := entries.push (metaClientSubject, stringToBytes cert.subject)
+        let entries := cert.sans.foldl (init := entries) (fun acc san => acc.push (metaClientSan, stringToBytes san))
+        entries.push (metaClientFingerprint, stringToBytes cert.fingerprint)
+    | none => entries
+  let entries := match m.proxy with
+    | some proxy =>
+        let entries := entries.push (metaProxyPeer, stringToBytes proxy.peer)
+        let optional : Array (Nat × Option String) :=
+          #[(metaProxyAuthority, proxy.authority), (metaProxyUniqueId, proxy.uniqueId)]
+        optional.foldl (init := entries) (fun acc (tag, v) =>
+          match v with
+          | some s => acc.push (tag, stringToBytes s)
+          | none => acc
+        )
+    | none => entries
+  let entries := match m.deadlineNanos with
+    | some d => entries.push (metaDeadline, (Writer.empty.writeVarint d).buf)
+    | none => entries
+  entries.foldl (init := w.writeVarint entries.size) (fun acc (tag, value) =>
+    (acc.writeVarint tag).writeBytes value
+  )
+
+private def applyMeta (m : RequestMeta) (tag : Nat) (value : ByteArray) : Except String RequestMeta := do
+  let str : String → Except String String := fun field =>
+    match bytesToString? value with
+    | some s => pure s
+    | none => throw s!"invalid utf-8 in {field}"
+  if tag == metaConnId then
+    let (id, _) ← Reader.readVarint (Reader.ofByteArray value)
+    pure { m with connId := some (UInt64.ofNat id) }
+  else if tag == metaLocalAddr then
+    pure { m with localAddr := some (← str "local address") }
+  else if tag == metaScheme then
+    pure { m with scheme := (← str "scheme") }
+  else if tag == metaHttpVersion then
+    pure { m with httpVersion := (← str "http version") }
+  else if tag == metaRawUri then
+    pure { m with rawUri := some (← str "raw uri") }
+  else if tag == metaTlsVersion then
+    pure { m with tlsVersion := some (← str "tls version") }
+  else if tag == metaTlsCipher then
+    pure { m with tlsCipher := some (← str "tls cipher") }
+  else if tag == metaTlsSni then
+    pure { m with tlsSni := some (← str "tls server name") }
+  else if tag == metaTlsAlpn then
+    pure { m with tlsAlpn := some (← str "tls alpn") }
+  else if tag == metaClientSubject then
+    let cert := m.clientCert.getD {}
+    pure { m with clientCert := some { cert with subject := (← str "client subject") } }
+  else if tag == metaClientSan then
+    let cert := m.clientCert.getD {}
+    pure { m with clientCert := some { cert with sans := cert.sans.push (← str "client san") } }
+  else if tag == metaClientFingerprint then
+    let cert := m.clientCert.getD {}
+    pure { m with clientCert := some { cert with fingerprint := (← str "client fingerprint") } }
+  else if tag == metaProxyPeer then
+    let proxy := m.proxy.getD {}
+    pure { m with proxy := some { proxy with peer := (← str "proxy peer") } }
+  else if tag == metaProxyAuthority then
+    let proxy := m.proxy.getD {}
+    pure { m with proxy := some { proxy with authority := some (← str "proxy authority") } }
+  else if tag == metaProxyUniqueId then
+    let proxy := m.proxy.getD {}
+    pure { m with proxy := some { proxy with uniqueId := some (← str "proxy unique id") } }
+  else if tag == metaDeadline then
+    let (d, _) ← Reader.readVarint (Reader.ofByteArray value)
+    pure { m with deadlineNanos := some d }
+  else
+    pure m
+
+private partial def readMetaLoop (n : Nat) (r : Reader) (m : RequestMeta) : Except String (RequestMeta × Reader) := do
+  if n = 0 then
+    pure (m, r)
+  else
+    let (tag, r) ← Reader.readVarint r
+    let (value, r) ← Reader.readBytes r
+    let m ← applyMeta m tag value
+    readMetaLoop (n - 1) r m
+
+/-- The metadata section is optional: v1 and older v2 senders omit it. -/
+private def readRequestMeta (r : Reader) : Except String (RequestMeta × Reader) := do
+  if !r.compact || r.pos >= r.data.size then
+    pure ({}, r)
+  else
+    let (count, r) ← Reader.readVarint r
+    readMetaLoop count r {}
+
+@[inline] def encodeWireRequest (req : WireRequest) (version : UInt8 := wireVersion) : ByteArray :=
+  let w := Writer.ofVersion version
+  let w := w.writeU8 version
   let w := w.writeU8 req.method.toUInt8
+  let w :=
+    match req.method with
+    | .ext name => w.writeString name
+    | _ => w
   let w := w.writeString req.path
   let w := w.writeString req.query
-  let w := w.writeU32 (UInt32.ofNat req.headers.size)
-  let w := req.headers.foldl (init := w) (fun acc h =>
-    acc.writeString h.fst |>.writeString h.snd
-  )
+  let w := if req.rawHeaders.isEmpty then w.writeHeaders req.headers else w.writeHeaders #[] req.rawHeaders
   let w := w.writeBytes req.body
   let w := w.writeOptString req.remote
+  let w := if w.compact then writeRequestMeta w req.metadata else w
   w.buf
 
 @[inline] def decodeWireRequest (bytes : ByteArray) : Except String WireRequest := do
   let r := Reader.ofByteArray bytes
-  let (ver, r) ← Reader.readU8 r
-  if ver != wireVersion then
-    throw s!"unsupported wire version {ver.toNat}"
+  let (_, r) ← Reader.readVersion wireVersionV1 wireVersion "wire" r
   let (methodByte, r) ← Reader.readU8 r
-  let method ←
-    match Method.ofUInt8? methodByte with
-    | some m => pure m
-    | none => throw s!"unknown method {methodByte.toNat}"
+  let (method, r) ←
+    if methodByte == Method.extCode then
+      -- Taken exactly as sent: method names are case-sensitive.
+      let (name, r) ← Reader.readString r
+      if Method.isToken name then pure (.ext name, r) else throw s!"invalid method {name}"
+    else
+      match Method.ofUInt8? methodByte with
+      | some m => pure (m, r)
+      | none => throw s!"unknown method {methodByte.toNat}"
   let (path, r) ← Reader.readString r
   let (query, r) ← Reader.readString r
-  let (count, r) ← Reader.readU32 r
-  let (headers, r) ← Reader.readHeaders count.toNat r
+  let (count, r) ← Reader.readLen r
+  let (rawHeaders, r) ← Reader.readRawHeaders count r
   let (body, r) ← Reader.readBytes r
-  let (remote, _r) ← Reader.readOptString r
+  let (remote, r) ← Reader.readOptString r
+  let (metadata, _r) ← readRequestMeta r
   pure
     { method := method
     , path := path
     , query := query
-    , headers := headers
+    , headers := (splitRawHeaders rawHeaders).1
     , body := body
     , remote := remote
+    , metadata := metadata
+    , rawHeaders := rawHeaders
     }
 
-@[inline] def encodeWireResponse (resp : WireResponse) : ByteArray :=
-  let w := Writer.empty
-  let w := w.writeU8 wireVersion
+@[inline] def encodeWireResponse (resp : WireResponse) (version : UInt8 := wireVersion) : ByteArray :=
+  let w := Writer.ofVersion version
+  let w := w.writeU8 version
   let w := w.writeU16 (UInt16.ofNat resp.status)
-  let w := w.writeU32 (UInt32.ofNat resp.headers.size)
-  let w := resp.headers.foldl (init := w) (fun acc h =>
-    acc.writeString h.fst |>.writeString h.snd
-  )
+  let w := w.writeHeaders resp.headers resp.rawHeaders
   let w := w.writeBytes resp.body
   w.buf
 
+/--
+Decode a response. Like `Response`, the result keeps UTF-8 headers and the
+rest in separate lists, so order is only kept within each list.
+-/
 @[inline] def decodeWireResponse (bytes : ByteArray) : Except String WireResponse := do
   let r := Reader.ofByteArray bytes
-  let (ver, r) ← Reader.readU8 r
-  if ver != wireVersion then
-    throw s!"unsupported wire version {ver.toNat}"
+  let (_, r) ← Reader.readVersion wireVersionV1 wireVersion "wire" r
   let (status, r) ← Reader.readU16 r
-  let (count, r) ← Reader.readU32 r
-  let (headers, r) ← Reader.readHeaders count.toNat r
+  let (count, r) ← Reader.readLen r
+  let (raw, r) ← Reader.readRawHeaders count r
   let (body, _r) ← Reader.readBytes r
+  let (headers, rawHeaders) := splitRawHeaders raw
   pure
     { status := status.toNat
     , headers := headers
     , body := body
+    , rawHeaders := rawHeaders
     }
 
 inductive StreamMsg where
@@ -248,45 +510,42 @@ inductive StreamMsg where
   | chunk (body : ByteArray)
   | finish
 
-@[inline] def encodeStreamHead (status : UInt16) (headers : Array (String × String)) (isStream : Bool) (body : ByteArray) : ByteArray :=
-  let w := Writer.empty
-  let w := w.writeU8 streamWireVersion
+@[inline] def encodeStreamHead
+    (status : UInt16) (headers : Array (String × String)) (isStream : Bool) (body : ByteArray)
+    (version : UInt8 := streamWireVersion) (rawHeaders : RawHeaders := #[]) : ByteArray :=
+  let w := Writer.ofVersion version
+  let w := w.writeU8 version
   let w := w.writeU8 1
   let w := w.writeU16 status
   let w := w.writeU8 (if isStream then 1 else 0)
-  let w := w.writeU32 (UInt32.ofNat headers.size)
-  let w := headers.foldl (init := w) (fun acc h =>
-    acc.writeString h.fst |>.writeString h.snd
-  )
+  let w := w.writeHeaders headers rawHeaders
   let w := w.writeBytes body
   w.buf
 
-@[inline] def encodeStreamChunk (body : ByteArray) : ByteArray :=
-  let w := Writer.empty
-  let w := w.writeU8 streamWireVersion
+@[inline] def encodeStreamChunk (body : ByteArray) (version : UInt8 := streamWireVersion) : ByteArray :=
+  let w := Writer.ofVersion version
+  let w := w.writeU8 version
   let w := w.writeU8 2
   let w := w.writeBytes body
   w.buf
 
-@[inline] def encodeStreamEnd : ByteArray :=
-  let w := Writer.empty
-  let w := w.writeU8 streamWireVersion
+@[inline] def encodeStreamEnd (version : UInt8 := streamWireVersion) : ByteArray :=
+  let w := Writer.ofVersion version
+  let w := w.writeU8 version
   let w := w.writeU8 3
   w.buf
 
 @[inline] def decodeStreamMsg (bytes : ByteArray) : Except String StreamMsg := do
   let r := Reader.ofByteArray bytes
-  let (ver, r) ← Reader.readU8 r
-  if ver != streamWireVersion then
-    throw s!"unsupported stream wire version {ver.toNat}"
+  let (_, r) ← Reader.readVersion streamWireVersionV1 streamWireVersion "stream wire" r
   let (kind, r) ← Reader.readU8 r
   match kind.toNat with
   | 1 =>
       let (status, r) ← Reader.readU16 r
       let (isStreamByte, r) ← Reader.readU8 r
       let isStream := isStreamByte != 0
-      let (count, r) ← Reader.readU32 r
-      let (headers, r) ← Reader.readHeaders count.toNat r
+      let (count, r) ← Reader.readLen r
+      let (headers, r) ← Reader.readHeaders count r
       let (body, _r) ← Reader.readBytes r
       pure (StreamMsg.head status.toNat headers isStream body)
   | 2 =>
@@ -297,4 +556,60 @@ inductive StreamMsg where
   | _ =>
       throw s!"unknown stream message type {kind.toNat}"
 
+/-- Layout version of the `lithe_handshake` payload itself. -/
+def handshakeVersion : UInt8 := 1
+
+/-- Optional capability bits advertised in the handshake. -/
+def capBatchPoll : UInt32 := 1
+def capNotify : UInt32 := 2
+/-- Requests may carry `Method.ext` methods. -/
+def capExtMethods : UInt32 := 8
+/-- Request header values may be any bytes (see `Request.rawHeaders`). -/
+def capRawHeaders : UInt32 := 16
+
+/-- What this Lean library supports, reported to the shim at startup. -/
+structure Handshake where
+  libraryVersion : String
+  wireMin : UInt8 := wireVersionV1
+  wireMax : UInt8 := wireVersion
+  streamWireMin : UInt8 := streamWireVersionV1
+  streamWireMax : UInt8 := streamWireVersion
+  capabilities : UInt32 := 0
+  deriving Repr
+
+/--
+Encode a handshake. It always uses the v1 layout so that any shim can read it
+before a wire version is chosen.
+-/
+@[inline] def encodeHandshake (h : Handshake) : ByteArray :=
+  let w := Writer.ofVersion wireVersionV1
+  let w := w.writeU8 handshakeVersion
+  let w := w.writeString h.libraryVersion
+  let w := w.writeU8 h.wireMin
+  let w := w.writeU8 h.wireMax
+  let w := w.writeU8 h.streamWireMin
+  let w := w.writeU8 h.streamWireMax
+  let w := w.writeU32 h.capabilities
+  w.buf
+
+@[inline] def decodeHandshake (bytes : ByteArray) : Except String Handshake := do
+  let r := { Reader.ofByteArray bytes with compact := false }
+  let (version, r) ← Reader.readU8 r
+  if version != handshakeVersion then
+    throw s!"unsupported handshake version {version.toNat}"
+  let (libraryVersion, r) ← Reader.readString r
+  let (wireMin, r) ← Reader.readU8 r
+  let (wireMax, r) ← Reader.readU8 r
+  let (streamWireMin, r) ← Reader.readU8 r
+  let (streamWireMax, r) ← Reader.readU8 r
+  let (capabilities, _r) ← Reader.readU32 r
+  pure
+    { libraryVersion := libraryVersion
+    , wireMin := wireMin
+    , wireMax := wireMax
+    , streamWireMin := streamWireMin
+    , streamWireMax := streamWireMax
+    , capabilities := capabilities
+    }
+
 end Lithe
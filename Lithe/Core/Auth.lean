@@ -5,8 +5,9 @@ namespace Lithe
 
 /--
 Authentication information stored in the request context.
-- `scheme` identifies the auth mechanism ("api_key", "bearer", "session").
-- `token` holds the raw credential (API key, bearer token, session id).
+- `scheme` identifies the auth mechanism ("api_key", "bearer", "session", "mtls").
+- `token` holds the raw credential (API key, bearer token, session id,
+  client certificate fingerprint).
 - `subject` is an optional principal identifier.
 - `scopes` carries optional scope strings.
 - `claims` carries optional structured metadata (e.g., decoded JWT claims).
@@ -33,6 +34,9 @@ namespace AuthInfo
 @[inline] def session (token : String) (subject : Option String := none) : AuthInfo :=
   { scheme := "session", token := token, subject := subject }
 
+@[inline] def mtls (fingerprint : String) (subject : Option String := none) : AuthInfo :=
+  { scheme := "mtls", token := fingerprint, subject := subject }
+
 @[inline] def withScopes (info : AuthInfo) (scopes : Array String) : AuthInfo :=
   { info with scopes := scopes }
 
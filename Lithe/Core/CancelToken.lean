@@ -5,6 +5,8 @@ namespace Lithe
 structure CancelToken where
   flag : IO.Ref Bool
   isNever : Bool := false
+  /-- The token also counts as canceled once `IO.monoNanosNow` passes this. -/
+  deadline : Option Nat := none
 
 initialize cancelNeverRef : IO.Ref Bool ← IO.mkRef false
 
@@ -19,8 +21,24 @@ namespace CancelToken
 @[inline] def cancel (t : CancelToken) : IO Unit :=
   t.flag.set true
 
-@[inline] def isCanceled (t : CancelToken) : IO Bool :=
-  t.flag.get
+@[inline] def isCanceled (t : CancelToken) : IO Bool := do
+  if ← t.flag.get then
+    return true
+  match t.deadline with
+  | none => pure false
+  | some d => return (← IO.monoNanosNow) > d
+
+/--
+A token that is canceled with `t` or once `deadline` passes, whichever comes
+first. Canceling it cancels `t` too. The shared `never` token is replaced by a
+fresh one so it is never canceled.
+-/
+def withDeadline (t : CancelToken) (deadline : Nat) : IO CancelToken := do
+  let t ← if t.isNever then new else pure t
+  let deadline := match t.deadline with
+    | some d => min d deadline
+    | none => deadline
+  pure { t with deadline := some deadline }
 
 end CancelToken
 
@@ -31,6 +31,20 @@ namespace RequestCtx
 @[inline] def withDeadline (ctx : RequestCtx) (deadline : Option Nat) : RequestCtx :=
   { ctx with deadlineNanos := deadline }
 
+/--
+Applies the deadline the host sent in `req.metadata`: `cancel` trips and
+`deadlineNanos` expires when the host gives up on the response.
+-/
+def armHostDeadline (ctx : RequestCtx) : IO RequestCtx := do
+  match ctx.req.metadata.deadlineNanos with
+  | none => pure ctx
+  | some d =>
+      let cancel ← ctx.cancel.withDeadline d
+      let deadline := match ctx.deadlineNanos with
+        | some cur => min cur d
+        | none => d
+      pure { ctx with cancel := cancel, deadlineNanos := some deadline }
+
 end RequestCtx
 
 end Lithe
@@ -16,6 +16,9 @@ namespace HttpError
 @[inline] def badRequest (msg := "Bad Request") : HttpError :=
   { status := 400, code := "bad_request", message := msg }
 
+@[inline] def methodNotAllowed (msg := "Method Not Allowed") : HttpError :=
+  { status := 405, code := "method_not_allowed", message := msg }
+
 @[inline] def internal (msg := "Internal Server Error") : HttpError :=
   { status := 500, code := "internal_error", message := msg }
 
@@ -3,6 +3,7 @@ import Lithe.Runtime.AsyncRegistry
 import Lithe.Runtime.StreamRegistry
 import Lithe.Runtime.WSRegistry
 import Lithe.Runtime.Dispatch
+import Lithe.Runtime.Notify
 import Lithe.Codec.Wire
 import Lithe.Core.Context
 import Lithe.Core.Error
@@ -17,6 +18,50 @@ namespace Lithe
 def lithe_init : IO UInt32 :=
   pure 1
 
+/-- Library version reported in the handshake. -/
+def litheVersion : String := "0.1.0"
+
+initialize selectedWireVersion : IO.Ref UInt8 ← IO.mkRef wireVersion
+initialize selectedStreamWireVersion : IO.Ref UInt8 ← IO.mkRef streamWireVersion
+
+/--
+Report this library's version, supported wire versions and capabilities so
+the shim can pick a protocol before serving.
+-/
+@[export lithe_handshake]
+def lithe_handshake : IO ByteArray :=
+  pure (encodeHandshake
+    { libraryVersion := litheVersion
+    , capabilities := capBatchPoll ||| capNotify ||| capExtMethods ||| capRawHeaders
+    })
+
+/--
+Select the wire versions the shim negotiated. Responses and stream messages
+are encoded with them from now on.
+-/
+@[export lithe_select_protocol]
+def lithe_select_protocol (wire : UInt8) (stream : UInt8) : IO Unit := do
+  if wire < wireVersionV1 || wire > wireVersion then
+    throw (IO.userError s!"unsupported wire version {wire.toNat}")
+  if stream < streamWireVersionV1 || stream > streamWireVersion then
+    throw (IO.userError s!"unsupported stream wire version {stream.toNat}")
+  selectedWireVersion.set wire
+  selectedStreamWireVersion.set stream
+
+/--
+Lean's monotonic clock, so the shim can send deadlines on it.
+-/
+@[export lithe_mono_nanos]
+def lithe_mono_nanos : IO UInt64 := do
+  pure (UInt64.ofNat (← IO.monoNanosNow))
+
+/--
+Render an IO error raised by another export so the shim can report it.
+-/
+@[export lithe_io_error_message]
+def lithe_io_error_message (err : IO.Error) : ByteArray :=
+  stringToBytes (toString err)
+
 @[export lithe_new_app]
 def lithe_new_app (routerBytes : ByteArray) : IO UInt64 := do
   let _ := routerBytes
@@ -31,20 +76,20 @@ def lithe_handle (app : UInt64) (reqBytes : ByteArray) : IO ByteArray := do
       let ctx := RequestCtx.ofRequest req inst.state
       let resp ← dispatch inst.router ctx
       let wireResp := WireResponse.ofResponse resp
-      pure (encodeWireResponse wireResp)
+      pure (encodeWireResponse wireResp (← selectedWireVersion.get))
   | .error err =>
       let err := HttpError.badRequest err
       let resp := (Response.json (HttpError.toJson err)).withStatus err.status
-      pure (encodeWireResponse (WireResponse.ofResponse resp))
+      pure (encodeWireResponse (WireResponse.ofResponse resp) (← selectedWireVersion.get))
 
 @[export lithe_free_app]
 def lithe_free_app (app : UInt64) : IO Unit :=
   freeInstance app
 
-private def encodeError (msg : String) : ByteArray :=
+private def encodeError (msg : String) : IO ByteArray := do
   let err := HttpError.internal msg
   let resp := (Response.json (HttpError.toJson err)).withStatus err.status
-  encodeWireResponse (WireResponse.ofResponse resp)
+  pure (encodeWireResponse (WireResponse.ofResponse resp) (← selectedWireVersion.get))
 
 private def errorResponse (err : HttpError) : Response :=
   (Response.json (HttpError.toJson err)).withStatus err.status
@@ -55,13 +100,14 @@ private def handleRequest (inst : AppInstance) (cancel : CancelToken) (reqBytes
       let req := wireReq.toRequest
       let ctx := RequestCtx.ofRequest req inst.state
       let ctx := RequestCtx.withCancelToken ctx cancel
+      let ctx ← RequestCtx.armHostDeadline ctx
       let resp ← dispatch inst.router ctx
       let wireResp := WireResponse.ofResponse resp
-      pure (encodeWireResponse wireResp)
+      pure (encodeWireResponse wireResp (← selectedWireVersion.get))
   | .error err =>
       let err := HttpError.badRequest err
       let resp := (Response.json (HttpError.toJson err)).withStatus err.status
-      pure (encodeWireResponse (WireResponse.ofResponse resp))
+      pure (encodeWireResponse (WireResponse.ofResponse resp) (← selectedWireVersion.get))
 
 /--
 Start handling a request asynchronously. Returns a request ID that can be polled.
@@ -88,7 +134,7 @@ def lithe_poll_response (reqId : UInt64) : IO ByteArray := do
         removePending reqId
         match res with
         | .ok bytes => pure bytes
-        | .error e => pure (encodeError s!"io error: {e}")
+        | .error e => encodeError s!"io error: {e}"
       else
         pure ByteArray.empty
 
@@ -141,7 +187,7 @@ private partial def pumpResponseStream (stream : BodyStream) (q : StreamQueue) (
           StreamQueue.close q
   loop
 
-private def resolveStreamResponse (sess : StreamSession) : IO Unit := do
+private def resolveStreamResponse (reqId : UInt64) (sess : StreamSession) : IO Unit := do
   let ready? ← sess.respRef.get
   if ready?.isSome then
     pure ()
@@ -159,7 +205,7 @@ private def resolveStreamResponse (sess : StreamSession) : IO Unit := do
           sess.respRef.set (some resp)
       | some stream => do
           let stream ← BodyStream.prepend resp.body stream
-          let q ← StreamQueue.new streamQueueCapacity
+          let q ← StreamQueue.new streamQueueCapacity (onReadable := notifyHost notifyStreamResponse reqId)
           let _ ← IO.asTask (pumpResponseStream stream q sess.cancel)
           let headResp := { resp with body := ByteArray.empty, bodyStream := none }
           sess.respRef.set (some headResp)
@@ -170,8 +216,9 @@ private def resolveStreamResponse (sess : StreamSession) : IO Unit := do
 @[export lithe_stream_start]
 def lithe_stream_start (app : UInt64) (reqBytes : ByteArray) : IO UInt64 := do
   let inst ← getInstance app
+  let reqId ← allocStreamId
   let cancel ← CancelToken.new
-  let reqQueue ← StreamQueue.new streamQueueCapacity
+  let reqQueue ← StreamQueue.new streamQueueCapacity (onWritable := notifyHost notifyStreamBody reqId)
   let baseStream := BodyStream.fromQueue reqQueue
   let stream := BodyStream.withCancel baseStream cancel
   let task ← IO.asTask (do
@@ -180,7 +227,9 @@ def lithe_stream_start (app : UInt64) (reqBytes : ByteArray) : IO UInt64 := do
         let stream ← BodyStream.prepend wireReq.body stream
         let req := { wireReq.toRequest with body := ByteArray.empty, bodyStream := some stream }
         let ctx := RequestCtx.ofRequest req inst.state
-        let ctx := RequestCtx.withCancelToken ctx cancel
+        -- Only the handler is bound by the deadline; a streamed body keeps
+        -- the session's token and may outlive it.
+        let ctx ← RequestCtx.armHostDeadline (RequestCtx.withCancelToken ctx cancel)
         dispatch inst.router ctx
     | .error err =>
         pure (errorResponse (HttpError.badRequest err))
@@ -196,7 +245,10 @@ def lithe_stream_start (app : UInt64) (reqBytes : ByteArray) : IO UInt64 := do
     , respQueue := respQueue
     , headSent := headSent
     }
-  registerStream sess
+  insertStream reqId sess
+  -- The head only becomes pollable once the handler task finishes.
+  let _ ← IO.mapTask (fun _ => notifyHost notifyStreamResponse reqId) task
+  pure reqId
 
 /--
 Push a request body chunk. Returns:
@@ -231,7 +283,7 @@ def lithe_stream_poll_response (reqId : UInt64) : IO ByteArray := do
   match sess? with
   | none => pure ByteArray.empty
   | some sess => do
-      resolveStreamResponse sess
+      resolveStreamResponse reqId sess
       let resp? ← sess.respRef.get
       match resp? with
       | none => pure ByteArray.empty
@@ -244,7 +296,8 @@ def lithe_stream_poll_response (reqId : UInt64) : IO ByteArray := do
             sess.headSent.set true
             if !isStream then
               removeStream reqId
-            pure (encodeStreamHead resp.status.code resp.headers isStream body)
+            let version ← selectedStreamWireVersion.get
+            pure (encodeStreamHead resp.status.code resp.headers isStream body version resp.rawHeaders)
           else
             let respQ? ← sess.respQueue.get
             match respQ? with
@@ -254,15 +307,35 @@ def lithe_stream_poll_response (reqId : UInt64) : IO ByteArray := do
             | some q =>
                 match (← q.pop?) with
                 | some chunk =>
-                    pure (encodeStreamChunk chunk)
+                    pure (encodeStreamChunk chunk (← selectedStreamWireVersion.get))
                 | none =>
                     let closed ← q.isClosed
                     if closed then
                       removeStream reqId
-                      pure encodeStreamEnd
+                      pure (encodeStreamEnd (← selectedStreamWireVersion.get))
                     else
                       pure ByteArray.empty
 
+/--
+Drain messages from `poll` into `u32`-length-prefixed frames until it returns
+empty or the frames reach `budget` bytes. At least one message is taken.
+-/
+private partial def pollFrames (poll : IO ByteArray) (budget : Nat) (w : Writer) : IO ByteArray := do
+  if w.buf.size >= budget then
+    return w.buf
+  let msg ← poll
+  if msg.isEmpty then
+    return w.buf
+  pollFrames poll budget ((w.writeU32 (UInt32.ofNat msg.size)).writeRaw msg)
+
+/--
+Poll every ready stream response message (head, chunks, end) in one call.
+Returns empty when no message is ready.
+-/
+@[export lithe_stream_poll_batch]
+def lithe_stream_poll_batch (reqId : UInt64) (budget : UInt64) : IO ByteArray :=
+  pollFrames (lithe_stream_poll_response reqId) budget.toNat Writer.empty
+
 /--
 Cancel an in-flight stream request.
 -/
@@ -278,6 +351,13 @@ def lithe_ws_push (wsId : UInt64) (msg : ByteArray) : IO UInt64 :=
 def lithe_ws_poll (wsId : UInt64) : IO ByteArray :=
   wsPopOut wsId
 
+/--
+Poll every ready outbound WebSocket message, framed like `lithe_stream_poll_batch`.
+-/
+@[export lithe_ws_poll_batch]
+def lithe_ws_poll_batch (wsId : UInt64) (budget : UInt64) : IO ByteArray :=
+  pollFrames (wsPopOut wsId) budget.toNat Writer.empty
+
 @[export lithe_ws_close]
 def lithe_ws_close (wsId : UInt64) : IO Unit :=
   closeWS wsId
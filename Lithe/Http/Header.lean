@@ -5,4 +5,8 @@ namespace Lithe
 abbrev Header := String × String
 abbrev Headers := Array Header
 
+/-- A header whose value is kept as bytes, for values that are not UTF-8. -/
+abbrev RawHeader := String × ByteArray
+abbrev RawHeaders := Array RawHeader
+
 end Lithe
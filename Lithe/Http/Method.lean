@@ -4,10 +4,19 @@ namespace Lithe
 
 inductive Method
 | GET | POST | PUT | PATCH | DELETE | OPTIONS | HEAD
+/-- Any other method (`PROPFIND`, `QUERY`, ...), by its case-sensitive name. -/
+| ext (name : String)
   deriving BEq, DecidableEq, Repr
 
 namespace Method
 
+/-- Wire code for `ext`; the name follows as a string. -/
+def extCode : UInt8 := 255
+
+/-- Whether `s` is a valid method name (an RFC 9110 token). -/
+@[inline] def isToken (s : String) : Bool :=
+  !s.isEmpty && s.all (fun c => c.isAlphanum || "!#$%&'*+-.^_`|~".contains c)
+
 def toString : Method → String
   | GET => "GET"
   | POST => "POST"
@@ -16,9 +25,11 @@ def toString : Method → String
   | DELETE => "DELETE"
   | OPTIONS => "OPTIONS"
   | HEAD => "HEAD"
+  | ext name => name
 
 @[inline] def ofString? (s : String) : Option Method :=
-  match s.trimAscii.toString.toUpper with
+  let name := s.trimAscii.toString
+  match name.toUpper with
   | "GET" => some GET
   | "POST" => some POST
   | "PUT" => some PUT
@@ -26,7 +37,7 @@ def toString : Method → String
   | "DELETE" => some DELETE
   | "OPTIONS" => some OPTIONS
   | "HEAD" => some HEAD
-  | _ => none
+  | _ => if isToken name then some (ext name) else none
 
 @[inline] def toUInt8 : Method → UInt8
   | GET => 0
@@ -36,6 +47,7 @@ def toString : Method → String
   | DELETE => 4
   | OPTIONS => 5
   | HEAD => 6
+  | ext _ => extCode
 
 @[inline] def ofUInt8? (n : UInt8) : Option Method :=
   match n.toNat with
@@ -6,6 +6,54 @@ import Lithe.Core.Error
 
 namespace Lithe
 
+/-- A client certificate the host verified against its trusted CAs (mutual TLS). -/
+structure ClientCert where
+  /-- Subject distinguished name, e.g. `"CN=svc-a, O=Example"`. -/
+  subject     : String := ""
+  /-- Subject alternative names, tagged by kind: `"DNS:..."`, `"URI:..."`, `"email:..."`, `"IP:..."`. -/
+  sans        : Array String := #[]
+  /-- SHA-256 of the DER certificate, lowercase hex. -/
+  fingerprint : String := ""
+  deriving Inhabited, Repr
+
+/-- What a trusted load balancer reported through the PROXY protocol. -/
+structure ProxyInfo where
+  /-- Address of the balancer itself; `Request.remote` holds the client it reported. -/
+  peer      : String := ""
+  /-- Server name the client asked the balancer for (`PP2_TYPE_AUTHORITY`). -/
+  authority : Option String := none
+  /-- Connection id assigned by the balancer (`PP2_TYPE_UNIQUE_ID`); lowercase hex unless it is UTF-8. -/
+  uniqueId  : Option String := none
+  deriving Inhabited, Repr
+
+/-- Transport details the host reports for a request. -/
+structure RequestMeta where
+  /-- Host-assigned id of the connection the request arrived on. -/
+  connId      : Option UInt64 := none
+  /-- Address of the listener that accepted the connection; `unix:<path>` for a Unix socket. -/
+  localAddr   : Option String := none
+  /-- `"http"` or `"https"`. -/
+  scheme      : String := "http"
+  /-- For example `"HTTP/1.1"` or `"HTTP/2.0"`. -/
+  httpVersion : String := "HTTP/1.1"
+  /-- The request target exactly as received, before any decoding. -/
+  rawUri      : Option String := none
+  /-- Negotiated TLS version, e.g. `"TLSv1.3"`; `none` for plain connections. -/
+  tlsVersion  : Option String := none
+  /-- Negotiated TLS cipher suite, e.g. `"TLS13_AES_128_GCM_SHA256"`. -/
+  tlsCipher   : Option String := none
+  /-- Server name the client asked for (SNI). -/
+  tlsSni      : Option String := none
+  /-- Protocol chosen by ALPN, e.g. `"h2"`. -/
+  tlsAlpn     : Option String := none
+  /-- The verified client certificate, when the listener requires one. -/
+  clientCert  : Option ClientCert := none
+  /-- Set when the connection came through a trusted proxy speaking the PROXY protocol. -/
+  proxy       : Option ProxyInfo := none
+  /-- When the host gives up on the response, on `IO.monoNanosNow`'s clock. -/
+  deadlineNanos : Option Nat := none
+  deriving Inhabited, Repr
+
 structure Request where
   method  : Method
   path    : String
@@ -14,6 +62,14 @@ structure Request where
   body    : ByteArray
   bodyStream : Option BodyStream := none
   remote  : Option String := none
+  metadata : RequestMeta := {}
+  /--
+  Every header as received, with duplicates, as bytes. The shim keeps the
+  client's order, except that repeated names are grouped where the name first
+  appears. `headers` holds the same list minus values that are not UTF-8.
+  Empty when the request was not built by the host.
+  -/
+  rawHeaders : RawHeaders := #[]
 
 namespace Request
 
@@ -26,6 +82,17 @@ namespace Request
         if k.trimAscii.toString.toLower = target then some v else go rest
   go req.headers.toList
 
+/-- The first value of `name` as bytes, including values that are not UTF-8. -/
+@[inline] def rawHeader? (req : Request) (name : String) : Option ByteArray :=
+  if req.rawHeaders.isEmpty then
+    (req.header? name).map stringToBytes
+  else
+    let target := name.trimAscii.toString.toLower
+    req.rawHeaders.find? (fun (k, _) => k.trimAscii.toString.toLower = target) |>.map (·.snd)
+
+@[inline] def isSecure (req : Request) : Bool :=
+  req.metadata.scheme == "https"
+
 @[inline] def bodyString? (req : Request) : Option String :=
   bytesToString? req.body
 
@@ -12,12 +12,17 @@ structure Response where
   body    : ByteArray
   bodyStream : Option BodyStream := none
   background : BackgroundTasks := {}
+  /-- Headers with byte values (not UTF-8), sent after `headers` in order. -/
+  rawHeaders : RawHeaders := #[]
 
 namespace Response
 
 @[inline] def withHeader (r : Response) (name value : String) : Response :=
   { r with headers := r.headers.push (name, value) }
 
+@[inline] def withRawHeader (r : Response) (name : String) (value : ByteArray) : Response :=
+  { r with rawHeaders := r.rawHeaders.push (name, value) }
+
 private def normalizeHeaderName (s : String) : String :=
   s.trimAscii.toString.toLower
 
@@ -41,12 +46,14 @@ private def normalizeHeaderName (s : String) : String :=
     else
       (k, v) :: acc
   ) []
-  { r with headers := (headers ++ [(name, value)]).toArray }
+  let rawHeaders := r.rawHeaders.filter (fun (k, _) => normalizeHeaderName k != target)
+  { r with headers := (headers ++ [(name, value)]).toArray, rawHeaders := rawHeaders }
 
 @[inline] def removeHeader (r : Response) (name : String) : Response :=
   let target := normalizeHeaderName name
   let headers := r.headers.toList.filter (fun (k, _) => normalizeHeaderName k != target)
-  { r with headers := headers.toArray }
+  let rawHeaders := r.rawHeaders.filter (fun (k, _) => normalizeHeaderName k != target)
+  { r with headers := headers.toArray, rawHeaders := rawHeaders }
 
 @[inline] def withHeaders (r : Response) (hs : Array Header) : Response :=
   { r with headers := r.headers ++ hs }
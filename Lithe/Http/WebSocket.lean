@@ -6,6 +6,7 @@ import Lithe.Http.Request
 import Lithe.Http.Response
 import Lithe.Runtime.WSRegistry
 import Lithe.Runtime.StreamQueue
+import Lithe.Runtime.Notify
 import Lithe.Router.Builder
 import Lithe.App
 
@@ -156,12 +157,13 @@ def toHandler (h : WSHandler) (cfg : WSConfig := {}) : Handler :=
   fun ctx => do
     if !isWebSocketUpgrade ctx.req then
       throw { status := 426, code := "upgrade_required", message := "websocket upgrade required" }
-    let inQ ← StreamQueue.new cfg.inCapacity
-    let outQ ← StreamQueue.new cfg.outCapacity
+    let id ← allocWSId
+    let inQ ← StreamQueue.new cfg.inCapacity (onWritable := notifyHost notifyWsIn id)
+    let outQ ← StreamQueue.new cfg.outCapacity (onReadable := notifyHost notifyWsOut id)
     let cancel ← CancelToken.new
     let taskRef ← IO.mkRef (none : Option (Task (Except IO.Error Unit)))
     let sess : WSSession := { inQ := inQ, outQ := outQ, cancel := cancel, taskRef := taskRef }
-    let id ← registerWS sess
+    insertWS id sess
     let conn : WSConnection := { id := id, pollMs := cfg.pollMs }
     let task ← IO.asTask (do
       let _ ← (h conn ctx).run
@@ -145,4 +145,36 @@ def session
       throw cfg.onInvalid
   ) cfg
 
+/--
+Client certificate auth (mutual TLS) using a validator that returns AuthInfo.
+- The shim has already verified the certificate chain; `validate` decides
+  whether this identity may access the route.
+- Fails with `onMissing` when the listener does not require client certificates.
+-/
+def clientCertWith
+    (validate : ClientCert → ExceptT HttpError IO AuthInfo)
+    (cfg : AuthConfig := {}) : Middleware :=
+  auth (fun ctx =>
+    match ctx.req.metadata.clientCert with
+    | none => throw cfg.onMissing
+    | some cert => validate cert
+  ) cfg
+
+/--
+Client certificate auth using a boolean validator.
+- Returns AuthInfo.mtls with the fingerprint as token and the subject DN as
+  subject; the SANs are stored in `claims` under `"sans"`.
+-/
+def clientCert
+    (isAllowed : ClientCert → IO Bool)
+    (cfg : AuthConfig := {}) : Middleware :=
+  clientCertWith (fun cert => do
+    let ok ← isAllowed cert
+    if ok then
+      pure { AuthInfo.mtls cert.fingerprint (some cert.subject) with
+        claims := some (Lean.Json.mkObj [("sans", Lean.toJson cert.sans)]) }
+    else
+      throw cfg.onInvalid
+  ) cfg
+
 end Lithe
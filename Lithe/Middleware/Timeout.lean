@@ -11,13 +11,17 @@ private def nanosToMs (nanos : Nat) : Nat :=
 /--
 Best-effort timeout. If the handler exceeds the deadline, returns a timeout error.
 Note: the underlying handler is not cancelled and may continue running.
-This middleware also sets `ctx.deadlineNanos` for cooperative cancellation helpers.
+This middleware also sets `ctx.deadlineNanos` for cooperative cancellation helpers,
+keeping the host's deadline when that comes first.
 -/
 def timeout (ms : Nat) (onTimeout : HttpError := { status := 504, code := "timeout", message := "request timed out" }) : Middleware :=
   fun h ctx =>
     ExceptT.mk do
       let start ← IO.monoNanosNow
-      let deadline := some (start + ms * 1000000)
+      let deadline := start + ms * 1000000
+      let deadline := some (match ctx.deadlineNanos with
+        | some cur => min cur deadline
+        | none => deadline)
       let ctx := RequestCtx.withDeadline ctx deadline
       let res ← (h ctx).run
       let stop ← IO.monoNanosNow
@@ -26,6 +26,10 @@ namespace Router
 @[inline] def head (path : String) (h : Handler) (r : Router) : Router :=
   add Method.HEAD path h r
 
+/-- Route any method by name, including extension methods like `PROPFIND`. -/
+@[inline] def method (name : String) (path : String) (h : Handler) (r : Router) : Router :=
+  add ((Method.ofString? name).getD (Method.ext name)) path h r
+
 @[inline] def nest (pref : String) (child : Router) (r : Router) : Router :=
   let parsed := RoutePattern.parse pref
   let adjusted := child.routes.map (fun route =>
@@ -36,9 +40,13 @@ namespace Router
 @[inline] def withFallback (h : Handler) (r : Router) : Router :=
   { r with fallback := h }
 
+@[inline] def withMethodNotAllowed (h : List Method → Handler) (r : Router) : Router :=
+  { r with methodNotAllowed := h }
+
 @[inline] def withMiddleware (m : Middleware) (r : Router) : Router :=
   { routes := r.routes.map (fun route => { route with handler := m route.handler })
   , fallback := m r.fallback
+  , methodNotAllowed := fun allowed => m (r.methodNotAllowed allowed)
   }
 
 end Router
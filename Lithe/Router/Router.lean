@@ -9,9 +9,18 @@ structure Route where
   pattern : RoutePattern
   handler : Handler
 
+/-- 405 listing the methods the path does have routes for in `Allow`. -/
+@[inline] def Router.defaultMethodNotAllowed (allowed : List Method) : Handler :=
+  fun _ =>
+    let err : HttpError := HttpError.methodNotAllowed
+    let resp := (Response.json (HttpError.toJson err)).withStatus err.status
+    pure (resp.withHeader "allow" (", ".intercalate (allowed.map Method.toString)))
+
 structure Router where
   routes   : Array Route
   fallback : Handler
+  /-- Answers a path that has routes, but none for the request's method. -/
+  methodNotAllowed : List Method → Handler := Router.defaultMethodNotAllowed
 
 namespace Router
 
@@ -16,12 +16,31 @@ private def findMatch (routes : List Route) (req : Request) : Option (Handler ×
       else
         findMatch rest req
 
-/-- Run a request through the router and middleware chain. -/
+/-- A route for `req`; HEAD falls back to the GET route for the same path. -/
+private def findRoute (routes : List Route) (req : Request) : Option (Handler × Std.HashMap String String) :=
+  match findMatch routes req with
+  | none => if req.method == .HEAD then findMatch routes { req with method := .GET } else none
+  | found => found
+
+/-- Methods with a route matching `path`, in route order. -/
+private def allowedMethods (routes : List Route) (path : String) : List Method :=
+  (routes.filter (fun route => (matchRoute route.pattern path).isSome)).map (·.method) |>.eraseDups
+
+/--
+Run a request through the router and middleware chain. A path with routes for
+other methods only gets `methodNotAllowed`; one without any, the fallback.
+HEAD is served by a GET route, and an unrouted OPTIONS (such as a CORS
+preflight) still goes to the fallback.
+-/
 def dispatch (r : Router) (ctx : RequestCtx) : IO Response :=
-  match findMatch r.routes.toList ctx.req with
+  match findRoute r.routes.toList ctx.req with
   | some (h, params) =>
       Handler.run h (RequestCtx.withParams ctx params)
   | none =>
-      Handler.run r.fallback ctx
+      match allowedMethods r.routes.toList ctx.req.path with
+      | [] => Handler.run r.fallback ctx
+      | allowed =>
+          if ctx.req.method == .OPTIONS then Handler.run r.fallback ctx
+          else Handler.run (r.methodNotAllowed allowed) ctx
 
 end Lithe
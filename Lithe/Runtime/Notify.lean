@@ -0,0 +1,21 @@
+import Lithe.Prelude
+
+namespace Lithe
+
+/-- A stream session has a response head, chunk, or end ready to poll. -/
+def notifyStreamResponse : UInt8 := 1
+/-- A stream session's request-body queue has room for another chunk. -/
+def notifyStreamBody : UInt8 := 2
+/-- A websocket session has an outbound message ready to poll. -/
+def notifyWsOut : UInt8 := 3
+/-- A websocket session's inbound queue has room for another message. -/
+def notifyWsIn : UInt8 := 4
+
+/--
+Wake the host waiting on `(kind, id)`. Implemented in `c/lithe_notify.c`;
+a no-op until the host installs a callback.
+-/
+@[extern "lithe_notify"]
+opaque notifyHost (kind : UInt8) (id : UInt64) : IO Unit
+
+end Lithe
@@ -31,20 +31,36 @@ structure StreamQueue where
   buf      : IO.Ref ByteQueue
   bytes    : IO.Ref Nat
   closed   : IO.Ref Bool
+  /-- Runs after a chunk is pushed or the queue is closed. -/
+  onReadable : IO Unit := pure ()
+  /-- Runs after a chunk is popped or the queue is closed. -/
+  onWritable : IO Unit := pure ()
 
 namespace StreamQueue
 
-@[inline] def new (capacity : Nat) : IO StreamQueue := do
+@[inline] def new
+    (capacity : Nat)
+    (onReadable : IO Unit := pure ())
+    (onWritable : IO Unit := pure ()) : IO StreamQueue := do
   let buf ← IO.mkRef ByteQueue.empty
   let bytes ← IO.mkRef 0
   let closed ← IO.mkRef false
-  pure { capacity := capacity, buf := buf, bytes := bytes, closed := closed }
+  pure
+    { capacity := capacity
+    , buf := buf
+    , bytes := bytes
+    , closed := closed
+    , onReadable := onReadable
+    , onWritable := onWritable
+    }
 
 @[inline] def isClosed (q : StreamQueue) : IO Bool :=
   q.closed.get
 
-@[inline] def close (q : StreamQueue) : IO Unit :=
+@[inline] def close (q : StreamQueue) : IO Unit := do
   q.closed.set true
+  q.onReadable
+  q.onWritable
 
 @[inline] def push (q : StreamQueue) (chunk : ByteArray) : IO Bool := do
   let isClosed ← q.closed.get
@@ -58,6 +74,7 @@ namespace StreamQueue
     else
       q.buf.modify (fun bq => ByteQueue.push bq chunk)
       q.bytes.set next
+      q.onReadable
       pure true
 
 @[inline] def pop? (q : StreamQueue) : IO (Option ByteArray) := do
@@ -69,6 +86,7 @@ namespace StreamQueue
       let sz ← q.bytes.get
       let next := if sz >= chunk.size then sz - chunk.size else 0
       q.bytes.set next
+      q.onWritable
       pure (some chunk)
 
 @[inline] def sizeBytes (q : StreamQueue) : IO Nat :=
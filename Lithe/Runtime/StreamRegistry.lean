@@ -18,10 +18,18 @@ initialize streamRef : IO.Ref (Std.HashMap UInt64 StreamSession) ←
 
 initialize nextStreamIdRef : IO.Ref UInt64 ← IO.mkRef 1
 
-@[inline] def registerStream (sess : StreamSession) : IO UInt64 := do
+/-- Reserve a stream id so queues can be wired to it before the session exists. -/
+@[inline] def allocStreamId : IO UInt64 := do
   let id ← nextStreamIdRef.get
   nextStreamIdRef.set (id + 1)
+  pure id
+
+@[inline] def insertStream (id : UInt64) (sess : StreamSession) : IO Unit :=
   streamRef.modify (fun m => m.insert id sess)
+
+@[inline] def registerStream (sess : StreamSession) : IO UInt64 := do
+  let id ← allocStreamId
+  insertStream id sess
   pure id
 
 @[inline] def getStream? (id : UInt64) : IO (Option StreamSession) := do
@@ -19,10 +19,18 @@ initialize wsRef : IO.Ref (Std.HashMap UInt64 WSSession) ←
 
 initialize nextWsIdRef : IO.Ref UInt64 ← IO.mkRef 1
 
-@[inline] def registerWS (sess : WSSession) : IO UInt64 := do
+/-- Reserve a websocket id so queues can be wired to it before the session exists. -/
+@[inline] def allocWSId : IO UInt64 := do
   let id ← nextWsIdRef.get
   nextWsIdRef.set (id + 1)
+  pure id
+
+@[inline] def insertWS (id : UInt64) (sess : WSSession) : IO Unit :=
   wsRef.modify (fun m => m.insert id sess)
+
+@[inline] def registerWS (sess : WSSession) : IO UInt64 := do
+  let id ← allocWSId
+  insertWS id sess
   pure id
 
 @[inline] def getWS? (id : UInt64) : IO (Option WSSession) := do
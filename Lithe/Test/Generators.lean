@@ -174,7 +174,7 @@ def bodyBytes (minLen maxLen : Nat) : Generator ByteArray :=
   map byteArrayOfNatArray (arrayOf (chooseNat 0 255) minLen maxLen)
 
 def method : Generator Method :=
-  oneOf #[Method.GET, Method.POST, Method.PUT, Method.PATCH, Method.DELETE, Method.OPTIONS, Method.HEAD] Method.GET
+  oneOf #[Method.GET, Method.POST, Method.PUT, Method.PATCH, Method.DELETE, Method.OPTIONS, Method.HEAD, Method.ext "PROPFIND"] Method.GET
 
 def statusCode : Generator UInt16 :=
   oneOf #[200, 201, 204, 400, 404, 500] 200
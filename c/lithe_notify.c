@@ -0,0 +1,21 @@
+#include <lean/lean.h>
+#include <stdatomic.h>
+
+typedef void (*lithe_notify_fn)(uint8_t kind, uint64_t id);
+
+static _Atomic(lithe_notify_fn) lithe_notify_cb = NULL;
+
+/* Installed by the host (the Rust shim) before any app is created. */
+LEAN_EXPORT void lithe_set_notify_callback(lithe_notify_fn cb) {
+    atomic_store(&lithe_notify_cb, cb);
+}
+
+/* Called from Lean whenever a stream or websocket queue changes state. */
+LEAN_EXPORT lean_obj_res lithe_notify(uint8_t kind, uint64_t id, lean_obj_arg w) {
+    (void)w;
+    lithe_notify_fn cb = atomic_load(&lithe_notify_cb);
+    if (cb) {
+        cb(kind, id);
+    }
+    return lean_io_result_mk_ok(lean_box(0));
+}
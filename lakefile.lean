@@ -7,6 +7,17 @@ package lithe where
 require std from git "https://github.com/leanprover/std4" @ "v4.27.0"
 require SQLite from git "https://github.com/leanprover/leansqlite" @ "main"
 
+target lithe_notify.o pkg : System.FilePath := do
+  let oFile := pkg.buildDir / "c" / "lithe_notify.o"
+  let srcJob ← inputTextFile <| pkg.dir / "c" / "lithe_notify.c"
+  let weakArgs := #["-I", (← getLeanIncludeDir).toString]
+  buildO oFile srcJob weakArgs #["-fPIC"] "cc" getLeanTrace
+
+extern_lib liblithe_notify pkg := do
+  let notifyO ← lithe_notify.o.fetch
+  let name := nameToStaticLib "lithe_notify"
+  buildStaticLib (pkg.staticLibDir / name) #[notifyO]
+
 @[default_target]
 lean_lib Lithe
 
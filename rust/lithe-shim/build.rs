@@ -103,6 +103,7 @@ fn main() {
         build.file(file);
     }
     build.file(manifest_dir.join("lean_shim.c"));
+    build.file(repo_root.join("c/lithe_notify.c"));
 
     build.compile("lithe_example");
 
@@ -110,6 +111,7 @@ fn main() {
         println!("cargo:rerun-if-changed={}", file.display());
     }
     println!("cargo:rerun-if-changed={}", manifest_dir.join("lean_shim.c").display());
+    println!("cargo:rerun-if-changed={}", repo_root.join("c/lithe_notify.c").display());
     for entry in walkdir::WalkDir::new(&example_dir) {
         let entry = entry.unwrap();
         let path = entry.path();
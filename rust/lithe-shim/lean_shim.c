@@ -13,6 +13,10 @@ LEAN_EXPORT size_t lithe_lean_sarray_size(lean_object* o) {
     return lean_sarray_size(o);
 }
 
+LEAN_EXPORT void lithe_lean_sarray_set_size(lean_object* o, size_t size) {
+    lean_to_sarray(o)->m_size = size;
+}
+
 LEAN_EXPORT bool lithe_lean_io_result_is_ok(lean_object* r) {
     return lean_io_result_is_ok(r);
 }
@@ -29,10 +33,18 @@ LEAN_EXPORT uint64_t lithe_lean_unbox_uint64(lean_object* o) {
     return lean_unbox_uint64(o);
 }
 
+LEAN_EXPORT void lithe_lean_inc(lean_object* o) {
+    lean_inc(o);
+}
+
 LEAN_EXPORT void lithe_lean_dec(lean_object* o) {
     lean_dec(o);
 }
 
+LEAN_EXPORT void lithe_lean_mark_mt(lean_object* o) {
+    lean_mark_mt(o);
+}
+
 LEAN_EXPORT size_t lithe_byte_array_size(lean_object* o) {
     if (lean_is_sarray(o)) {
         return lean_sarray_size(o);
@@ -0,0 +1,49 @@
+use axum::{routing::get, Json, Router};
+
+use crate::{hardening, metrics};
+
+/// Routes for `admin` listeners: a liveness probe and the shim's counters.
+/// The app itself is never reachable here.
+pub(crate) fn router() -> Router {
+    Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route(
+            "/metrics",
+            get(|| async {
+                let m = metrics::snapshot();
+                Json(serde_json::json!({
+                    "lean_errors": m.lean_errors,
+                    "invalid_headers": m.invalid_headers,
+                    "shed_requests": m.shed_requests,
+                    "body_idle_timeouts": m.body_idle_timeouts,
+                    "stream_idle_timeouts": m.stream_idle_timeouts,
+                    "stream_lifetime_timeouts": m.stream_lifetime_timeouts,
+                    "slow_client_writes": m.slow_client_writes,
+                }))
+            }),
+        )
+        .layer(axum::middleware::from_fn(hardening::middleware))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_health_and_metrics_only() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap().serve(router().into_make_service());
+        tokio::spawn(server);
+
+        let client = hyper::Client::new();
+        let get = |path: &str| client.get(format!("http://{addr}{path}").parse().unwrap());
+        assert_eq!(get("/healthz").await.unwrap().status(), 200);
+        let res = get("/metrics").await.unwrap();
+        assert_eq!(res.status(), 200);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json["lean_errors"].is_u64());
+        assert_eq!(get("/hello").await.unwrap().status(), 404);
+    }
+}
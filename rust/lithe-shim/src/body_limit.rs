@@ -0,0 +1,159 @@
+use axum::http::{header, HeaderMap, StatusCode, Version};
+use axum::response::Response;
+use std::sync::OnceLock;
+
+use crate::{error, hardening};
+
+static CONFIG: OnceLock<Result<BodyLimits, String>> = OnceLock::new();
+
+/// Request body size limits, read once from `LITHE_MAX_BODY_BYTES` and
+/// `LITHE_MAX_BODY_BYTES_BY_PATH`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BodyLimits {
+    default: Option<u64>,
+    // Longest prefix first; `None` lifts the limit under that prefix.
+    prefixes: Vec<(String, Option<u64>)>,
+}
+
+impl BodyLimits {
+    /// Parses a byte count, where empty or `0` means no limit.
+    fn parse_bytes(bytes: &str) -> Result<Option<u64>, String> {
+        let bytes = bytes.trim();
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        let bytes: u64 = bytes.parse().map_err(|_| format!("invalid byte count {bytes:?}"))?;
+        Ok(Some(bytes).filter(|b| *b > 0))
+    }
+
+    /// Parses `prefix=bytes` pairs, comma-separated. `0` bytes means no limit.
+    pub(crate) fn parse(default: Option<u64>, list: &str) -> Result<Self, String> {
+        let mut prefixes = Vec::new();
+        for item in list.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (prefix, bytes) = item
+                .split_once('=')
+                .filter(|(prefix, _)| prefix.starts_with('/'))
+                .ok_or_else(|| format!("expected /prefix=bytes, got {item:?}"))?;
+            let limit = Self::parse_bytes(bytes)
+                .map_err(|_| format!("invalid byte count in {item:?}"))?;
+            prefixes.push((prefix.trim_end_matches('/').to_string(), limit));
+        }
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(BodyLimits { default, prefixes })
+    }
+
+    fn from_env() -> Result<Self, String> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let default = Self::parse_bytes(&var("LITHE_MAX_BODY_BYTES").unwrap_or_default())
+            .map_err(|err| format!("invalid LITHE_MAX_BODY_BYTES: {err}"))?;
+        let list = var("LITHE_MAX_BODY_BYTES_BY_PATH").unwrap_or_default();
+        Self::parse(default, &list)
+            .map_err(|err| format!("invalid LITHE_MAX_BODY_BYTES_BY_PATH: {err}"))
+    }
+
+    /// The limit for `path`: the longest matching prefix, on segment
+    /// boundaries, else the default.
+    pub(crate) fn limit_for(&self, path: &str) -> Option<u64> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+}
+
+/// Serving fails on invalid limits first (see [`check`]); until then every
+/// request body is refused.
+pub(crate) fn config() -> &'static BodyLimits {
+    static REFUSE_ALL: BodyLimits = BodyLimits {
+        default: Some(0),
+        prefixes: Vec::new(),
+    };
+    CONFIG.get_or_init(BodyLimits::from_env).as_ref().unwrap_or(&REFUSE_ALL)
+}
+
+/// Why the body size limits cannot be used, if they cannot.
+pub(crate) fn check() -> Result<(), String> {
+    let cfg = CONFIG.get_or_init(BodyLimits::from_env);
+    cfg.as_ref().map(drop).map_err(Clone::clone)
+}
+
+/// Whether the request declares a body larger than `limit` up front.
+pub(crate) fn declared_too_large(headers: &HeaderMap, limit: u64) -> bool {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.trim().parse::<u64>().ok())
+        .is_some_and(|len| len > limit)
+}
+
+/// 413, closing HTTP/1 connections so the rest of the body is never read.
+pub(crate) fn too_large(version: Version) -> Response {
+    let resp = error::error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        "request body is too large",
+    );
+    hardening::close(version, resp.map(axum::body::boxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn longest_prefix_wins_on_segment_boundaries() {
+        let list = "/upload=1048576, /upload/avatars/=4096,/hooks=0";
+        let limits = BodyLimits::parse(Some(1024), list).unwrap();
+        assert_eq!(limits.limit_for("/"), Some(1024));
+        assert_eq!(limits.limit_for("/upload"), Some(1_048_576));
+        assert_eq!(limits.limit_for("/upload/avatars/me"), Some(4096));
+        assert_eq!(limits.limit_for("/uploads"), Some(1024));
+        assert_eq!(limits.limit_for("/hooks/github"), None);
+        assert!(BodyLimits::parse(None, "upload=10").is_err());
+        assert!(BodyLimits::parse(None, "/upload=ten").is_err());
+    }
+
+    #[test]
+    fn byte_counts_are_parsed_strictly() {
+        assert_eq!(BodyLimits::parse_bytes(" 1024 "), Ok(Some(1024)));
+        assert_eq!(BodyLimits::parse_bytes("0"), Ok(None));
+        assert_eq!(BodyLimits::parse_bytes(""), Ok(None));
+        assert!(BodyLimits::parse_bytes("10MB").is_err());
+        assert!(BodyLimits::parse_bytes("-1").is_err());
+    }
+
+    #[test]
+    fn limits_are_read_from_the_environment() {
+        let vars = |max: &'static str, by_path: &'static str| {
+            move |key: &str| match key {
+                "LITHE_MAX_BODY_BYTES" => Some(max.to_string()),
+                "LITHE_MAX_BODY_BYTES_BY_PATH" => Some(by_path.to_string()),
+                _ => None,
+            }
+        };
+        assert_eq!(BodyLimits::from_vars(|_| None), Ok(BodyLimits::default()));
+        let limits = BodyLimits::from_vars(vars("1024", "/upload=4096")).unwrap();
+        assert_eq!(limits.limit_for("/"), Some(1024));
+        assert_eq!(limits.limit_for("/upload/x"), Some(4096));
+        let err = BodyLimits::from_vars(vars("10MB", "")).unwrap_err();
+        assert!(err.starts_with("invalid LITHE_MAX_BODY_BYTES: "), "{err}");
+        let err = BodyLimits::from_vars(vars("1024", "upload=1")).unwrap_err();
+        assert!(err.starts_with("invalid LITHE_MAX_BODY_BYTES_BY_PATH: "), "{err}");
+    }
+
+    #[test]
+    fn content_length_is_checked_before_reading() {
+        let mut headers = HeaderMap::new();
+        assert!(!declared_too_large(&headers, 10));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("10"));
+        assert!(!declared_too_large(&headers, 10));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("11"));
+        assert!(declared_too_large(&headers, 10));
+    }
+}
@@ -0,0 +1,140 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::conn::AddrStream;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::hardening::Activity;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Per-connection details captured at accept time and passed to Lean with
+/// every request on that connection.
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    /// Process-unique connection id.
+    pub id: u64,
+    /// Client address: the peer, or the client a trusted proxy reported.
+    /// `None` on Unix sockets.
+    pub remote: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    /// Path of the Unix socket the connection arrived on.
+    pub unix_path: Option<Arc<Path>>,
+    /// `"http"` or `"https"`.
+    pub scheme: &'static str,
+    /// Session details when the connection is TLS.
+    pub tls: Option<Arc<TlsInfo>>,
+    /// Set when the connection came through a trusted PROXY protocol peer.
+    pub proxy: Option<Arc<ProxyInfo>>,
+    /// Requests in flight on the connection, for its timeouts and request cap.
+    pub(crate) activity: Option<Arc<Activity>>,
+}
+
+/// What a trusted load balancer reported in its PROXY protocol header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyInfo {
+    /// The balancer's own address.
+    pub peer: SocketAddr,
+    /// Server name the client asked the balancer for (`PP2_TYPE_AUTHORITY`).
+    pub authority: Option<String>,
+    /// The balancer's connection id (`PP2_TYPE_UNIQUE_ID`); lowercase hex
+    /// unless it is UTF-8.
+    pub unique_id: Option<String>,
+}
+
+/// TLS session details, captured once the handshake completes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Negotiated protocol version, e.g. `"TLSv1.3"`.
+    pub version: Option<&'static str>,
+    /// Negotiated cipher suite, e.g. `"TLS13_AES_128_GCM_SHA256"`.
+    pub cipher: Option<String>,
+    /// Server name the client asked for (SNI).
+    pub sni: Option<String>,
+    /// Protocol chosen by ALPN, e.g. `"h2"`.
+    pub alpn: Option<String>,
+    /// The client certificate, when the listener requires one.
+    pub client: Option<ClientCert>,
+}
+
+/// A client certificate that passed verification against the configured CAs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Subject distinguished name, e.g. `"CN=svc-a, O=Example"`.
+    pub subject: String,
+    /// Subject alternative names tagged by kind: `"DNS:..."`, `"URI:..."`,
+    /// `"email:..."` or `"IP:..."`.
+    pub sans: Vec<String>,
+    /// SHA-256 of the DER certificate, lowercase hex.
+    pub fingerprint: String,
+}
+
+impl ConnInfo {
+    pub fn new(remote: Option<SocketAddr>, local: Option<SocketAddr>, scheme: &'static str) -> Self {
+        Self {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            remote,
+            local,
+            scheme,
+            unix_path: None,
+            tls: None,
+            proxy: None,
+            activity: None,
+        }
+    }
+
+    /// Marks the connection as accepted on the Unix socket at `path`.
+    pub fn with_unix_path(mut self, path: PathBuf) -> Self {
+        self.unix_path = Some(path.into());
+        self
+    }
+
+    /// Records a PROXY header; `client` replaces `remote` when the header
+    /// named one.
+    pub fn with_proxy(mut self, client: Option<SocketAddr>, proxy: ProxyInfo) -> Self {
+        if client.is_some() {
+            self.remote = client;
+        }
+        self.proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    pub(crate) fn with_activity(mut self, activity: Arc<Activity>) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Stops the HTTP/1 head and idle timeouts once the connection carries
+    /// another protocol.
+    pub(crate) fn upgraded(&self) {
+        if let Some(activity) = &self.activity {
+            activity.exempt();
+        }
+    }
+
+    /// Marks the connection as TLS.
+    pub fn with_tls(mut self, tls: TlsInfo) -> Self {
+        self.scheme = "https";
+        self.tls = Some(Arc::new(tls));
+        self
+    }
+}
+
+impl Connected<&AddrStream> for ConnInfo {
+    fn connect_info(target: &AddrStream) -> Self {
+        Self::new(Some(target.remote_addr()), Some(target.local_addr()), "http")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conn_ids_are_unique() {
+        let a = ConnInfo::new(None, None, "http");
+        let b = ConnInfo::new(None, None, "http");
+        assert_ne!(a.id, b.id);
+    }
+}
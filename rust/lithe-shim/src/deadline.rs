@@ -0,0 +1,139 @@
+use axum::http::HeaderMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::ffi;
+
+static CONFIG: OnceLock<DeadlineConfig> = OnceLock::new();
+// A shim instant and Lean's monotonic clock read at the same moment.
+static LEAN_CLOCK: OnceLock<Option<(Instant, u64)>> = OnceLock::new();
+
+/// Header a client may send to ask for a shorter (or, within bounds, longer)
+/// timeout, in milliseconds.
+const REQUEST_TIMEOUT: &str = "request-timeout";
+
+/// How long a request may take, read once from the environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DeadlineConfig {
+    /// `LITHE_RUST_TIMEOUT_MS`; caps every request.
+    server: Option<Duration>,
+    /// Bounds on a client's `Request-Timeout`; the header is ignored unless
+    /// `LITHE_CLIENT_TIMEOUT_MAX_MS` is set.
+    client: Option<(Duration, Duration)>,
+}
+
+impl DeadlineConfig {
+    fn from_env() -> Self {
+        let ms = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis)
+        };
+        let client = ms("LITHE_CLIENT_TIMEOUT_MAX_MS").map(|max| {
+            let min = ms("LITHE_CLIENT_TIMEOUT_MIN_MS").unwrap_or(Duration::from_millis(100));
+            (min.min(max), max)
+        });
+        DeadlineConfig {
+            server: ms("LITHE_RUST_TIMEOUT_MS"),
+            client,
+        }
+    }
+
+    /// The time `headers`' request may take: the server timeout, or the
+    /// client's clamped `Request-Timeout` when that is shorter.
+    pub(crate) fn budget(&self, headers: &HeaderMap) -> Option<Duration> {
+        let requested = self.client.and_then(|(min, max)| {
+            let ms = headers.get(REQUEST_TIMEOUT)?.to_str().ok()?.trim().parse::<u64>().ok()?;
+            Some(Duration::from_millis(ms).clamp(min, max))
+        });
+        match (self.server, requested) {
+            (Some(server), Some(requested)) => Some(server.min(requested)),
+            (server, requested) => server.or(requested),
+        }
+    }
+}
+
+pub(crate) fn config() -> &'static DeadlineConfig {
+    CONFIG.get_or_init(DeadlineConfig::from_env)
+}
+
+/// Reads Lean's clock once so deadlines can be sent on it. Call on a Lean
+/// thread during runtime initialization.
+pub(crate) unsafe fn calibrate() {
+    LEAN_CLOCK.get_or_init(|| {
+        let before = Instant::now();
+        let lean = ffi::io_result(ffi::lithe_mono_nanos(), |v| ffi::lithe_lean_unbox_uint64(v));
+        let after = Instant::now();
+        match lean {
+            Ok(nanos) => Some((before + (after - before) / 2, nanos)),
+            Err(err) => {
+                warn!(error = %err.message, "failed to read Lean's clock; deadlines stay in the shim");
+                None
+            }
+        }
+    });
+}
+
+/// `at` on Lean's `IO.monoNanosNow` clock, if it has been calibrated.
+pub(crate) fn lean_nanos(at: Instant) -> Option<u64> {
+    let (anchor, nanos) = (*LEAN_CLOCK.get()?)?;
+    Some(to_lean(anchor, nanos, at))
+}
+
+fn to_lean(anchor: Instant, nanos: u64, at: Instant) -> u64 {
+    match at.checked_duration_since(anchor) {
+        Some(ahead) => nanos.saturating_add(ahead.as_nanos() as u64),
+        None => nanos.saturating_sub((anchor - at).as_nanos() as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(timeout: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_TIMEOUT, HeaderValue::from_static(timeout));
+        headers
+    }
+
+    #[test]
+    fn client_timeout_is_clamped_and_capped_by_the_server() {
+        let ms = Duration::from_millis;
+        let cfg = DeadlineConfig {
+            server: Some(ms(5000)),
+            client: None,
+        };
+        assert_eq!(cfg.budget(&headers("100")), Some(ms(5000)));
+
+        let cfg = DeadlineConfig {
+            server: Some(ms(5000)),
+            client: Some((ms(100), ms(10_000))),
+        };
+        assert_eq!(cfg.budget(&HeaderMap::new()), Some(ms(5000)));
+        assert_eq!(cfg.budget(&headers("250")), Some(ms(250)));
+        assert_eq!(cfg.budget(&headers("1")), Some(ms(100)));
+        assert_eq!(cfg.budget(&headers("60000")), Some(ms(5000)));
+        assert_eq!(cfg.budget(&headers("soon")), Some(ms(5000)));
+
+        let cfg = DeadlineConfig {
+            server: None,
+            client: Some((ms(100), ms(10_000))),
+        };
+        assert_eq!(cfg.budget(&HeaderMap::new()), None);
+        assert_eq!(cfg.budget(&headers("60000")), Some(ms(10_000)));
+    }
+
+    #[test]
+    fn instants_map_onto_lean_clock() {
+        let anchor = Instant::now();
+        let later = anchor + Duration::from_millis(1500);
+        assert_eq!(to_lean(anchor, 1_000, later), 1_500_001_000);
+        assert_eq!(to_lean(later, 2_000_000_000, anchor), 500_000_000);
+        assert_eq!(to_lean(later, 1, anchor), 0);
+    }
+}
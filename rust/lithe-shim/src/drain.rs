@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{lean_pool, stream_cancel_now, websocket};
+
+static DRAIN_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static APPS: OnceLock<Mutex<HashMap<u64, Arc<AppSessions>>>> = OnceLock::new();
+
+/// How long open streams and WebSockets get to finish once shutdown starts
+/// (`LITHE_DRAIN_TIMEOUT_SECS`, default 30).
+pub fn drain_timeout() -> Duration {
+    *DRAIN_TIMEOUT.get_or_init(|| {
+        std::env::var("LITHE_DRAIN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30))
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Phase {
+    Serving,
+    // SSE streams end and WebSockets are sent a 1001 close.
+    Draining,
+    // The deadline passed; everything still open is cancelled.
+    Forced,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Session {
+    Stream(u64),
+    WebSocket(u64),
+}
+
+// Rust-side mirror of an app's Lean stream and WebSocket registries.
+struct AppSessions {
+    phase: watch::Sender<Phase>,
+    open: Mutex<HashSet<Session>>,
+    idle: Notify,
+}
+
+impl AppSessions {
+    fn snapshot(&self) -> Vec<Session> {
+        self.open
+            .lock()
+            .map(|open| open.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    async fn idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.snapshot().is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+fn sessions(app_id: u64) -> Arc<AppSessions> {
+    let apps = APPS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut apps = apps.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    apps.entry(app_id)
+        .or_insert_with(|| {
+            Arc::new(AppSessions {
+                phase: watch::channel(Phase::Serving).0,
+                open: Mutex::new(HashSet::new()),
+                idle: Notify::new(),
+            })
+        })
+        .clone()
+}
+
+/// An open stream or WebSocket, counted until dropped.
+pub(crate) struct Registered {
+    app: Arc<AppSessions>,
+    session: Session,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let empty = match self.app.open.lock() {
+            Ok(mut open) => {
+                open.remove(&self.session);
+                open.is_empty()
+            }
+            Err(_) => false,
+        };
+        if empty {
+            self.app.idle.notify_waiters();
+        }
+    }
+}
+
+pub(crate) fn register(app_id: u64, session: Session) -> Registered {
+    let app = sessions(app_id);
+    if let Ok(mut open) = app.open.lock() {
+        open.insert(session);
+    }
+    Registered { app, session }
+}
+
+/// Follows the app's shutdown phase.
+pub(crate) fn phase(app_id: u64) -> watch::Receiver<Phase> {
+    sessions(app_id).phase.subscribe()
+}
+
+/// Starts draining `app_id`: SSE streams end cleanly, WebSockets are sent a
+/// 1001 close, and other streams may run until [`finish`].
+pub(crate) fn begin(app_id: u64) {
+    let app = sessions(app_id);
+    app.phase.send_if_modified(|phase| {
+        let begin = *phase == Phase::Serving;
+        if begin {
+            *phase = Phase::Draining;
+        }
+        begin
+    });
+}
+
+/// Waits until `app_id` has no open sessions. At `deadline` the stragglers
+/// are cancelled in Lean and their tasks told to stop.
+pub(crate) async fn finish(app_id: u64, deadline: Instant) {
+    let app = sessions(app_id);
+    if tokio::time::timeout_at(deadline, app.idle()).await.is_ok() {
+        return;
+    }
+    let stragglers = app.snapshot();
+    warn!(app_id, open = stragglers.len(), "drain deadline passed; cancelling");
+    app.phase.send_replace(Phase::Forced);
+    for session in stragglers {
+        let cancelled = lean_pool::run(move || {
+            match session {
+                Session::Stream(req_id) => stream_cancel_now(req_id),
+                Session::WebSocket(ws_id) => websocket::ws_close_now(ws_id),
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(err) = cancelled {
+            warn!(app_id, error = %err.message, "failed to cancel a straggler");
+        }
+    }
+    app.idle().await;
+    info!(app_id, "drain complete");
+}
+
+/// Drops the drain state of a freed app.
+pub(crate) fn forget(app_id: u64) {
+    if let Some(apps) = APPS.get() {
+        if let Ok(mut apps) = apps.lock() {
+            apps.remove(&app_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finish_waits_for_open_sessions() {
+        let app_id = u64::MAX - 1;
+        let first = register(app_id, Session::Stream(1));
+        let second = register(app_id, Session::WebSocket(1));
+        let mut phase = phase(app_id);
+        begin(app_id);
+        assert_eq!(*phase.borrow_and_update(), Phase::Draining);
+        assert_eq!(sessions(app_id).snapshot().len(), 2);
+
+        drop(first);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(second);
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        tokio::time::timeout(Duration::from_secs(1), finish(app_id, deadline))
+            .await
+            .expect("drain finished before the deadline");
+        assert_eq!(*phase.borrow(), Phase::Draining);
+        assert!(sessions(app_id).snapshot().is_empty());
+        forget(app_id);
+    }
+}
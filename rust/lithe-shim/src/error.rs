@@ -0,0 +1,43 @@
+use axum::body::Body;
+use axum::http::{header, Response, StatusCode};
+use serde_json::json;
+
+// Same shape as Lean's `HttpError.toJson`, so clients see one error format
+// whether the failure happened in Lean or in the shim.
+pub(crate) fn error_response(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    let body = json!({
+        "status": status.as_u16().to_string(),
+        "code": code,
+        "message": message,
+    });
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub(crate) fn internal_error(message: &str) -> Response<Body> {
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn error_response_matches_http_error_json() {
+        let resp = internal_error("boom");
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({"status": "500", "code": "internal_error", "message": "boom"})
+        );
+    }
+}
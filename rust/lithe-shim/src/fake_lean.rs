@@ -0,0 +1,416 @@
+// Stands in for the Lean library in unit tests, under the same names as the
+// exports in `ffi`. Objects are reference counted like Lean's and are never
+// freed, so a test can still check one after its last release. Streams are
+// scripted from the test through the helpers at the bottom.
+#![allow(clippy::missing_safety_doc)]
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::ffi::lean_object;
+use crate::{notify, wire};
+
+enum Value {
+    Bytes(Vec<u8>),
+    Scalar(u64),
+    Ok(*mut lean_object),
+    Err(*mut lean_object),
+    Error(String),
+    Released,
+}
+
+struct Obj {
+    refs: AtomicUsize,
+    mt: AtomicBool,
+    value: Mutex<Value>,
+}
+
+fn new_obj(value: Value) -> *mut lean_object {
+    let obj = Box::new(Obj {
+        refs: AtomicUsize::new(1),
+        mt: AtomicBool::new(false),
+        value: Mutex::new(value),
+    });
+    Box::into_raw(obj).cast()
+}
+
+unsafe fn obj<'a>(o: *mut lean_object) -> &'a Obj {
+    &*o.cast::<Obj>()
+}
+
+unsafe fn with_bytes<T>(o: *mut lean_object, f: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+    match &mut *obj(o).value.lock().unwrap() {
+        Value::Bytes(bytes) => f(bytes),
+        Value::Released => panic!("byte array used after its last release"),
+        _ => panic!("not a byte array"),
+    }
+}
+
+fn ok(value: Value) -> *mut lean_object {
+    new_obj(Value::Ok(new_obj(value)))
+}
+
+fn err(message: &str) -> *mut lean_object {
+    new_obj(Value::Err(new_obj(Value::Error(message.to_string()))))
+}
+
+fn unit() -> *mut lean_object {
+    ok(Value::Scalar(0))
+}
+
+pub unsafe fn lean_initialize_runtime_module() {}
+pub unsafe fn lean_initialize() {}
+pub unsafe fn lean_initialize_thread() {}
+pub unsafe fn lean_init_task_manager() {}
+pub unsafe fn lean_init_task_manager_using(_num_workers: u32) {}
+pub unsafe fn lean_finalize_task_manager() {}
+
+pub unsafe fn lithe_lean_io_result_is_ok(r: *mut lean_object) -> bool {
+    matches!(*obj(r).value.lock().unwrap(), Value::Ok(_))
+}
+
+pub unsafe fn lithe_lean_io_result_get_value(r: *mut lean_object) -> *mut lean_object {
+    match *obj(r).value.lock().unwrap() {
+        Value::Ok(v) => v,
+        _ => panic!("not an ok result"),
+    }
+}
+
+pub unsafe fn lithe_lean_io_result_get_error(r: *mut lean_object) -> *mut lean_object {
+    match *obj(r).value.lock().unwrap() {
+        Value::Err(e) => e,
+        _ => panic!("not an error result"),
+    }
+}
+
+pub unsafe fn lithe_lean_unbox_uint64(o: *mut lean_object) -> u64 {
+    match *obj(o).value.lock().unwrap() {
+        Value::Scalar(n) => n,
+        _ => panic!("not a scalar"),
+    }
+}
+
+pub unsafe fn lithe_lean_alloc_sarray(
+    elem_size: u32,
+    size: usize,
+    capacity: usize,
+) -> *mut lean_object {
+    assert_eq!(elem_size, 1);
+    let mut bytes = Vec::with_capacity(capacity);
+    bytes.resize(size, 0);
+    new_obj(Value::Bytes(bytes))
+}
+
+pub unsafe fn lithe_lean_sarray_cptr(o: *mut lean_object) -> *mut u8 {
+    with_bytes(o, |bytes| bytes.as_mut_ptr())
+}
+
+pub unsafe fn lithe_lean_sarray_size(o: *mut lean_object) -> usize {
+    with_bytes(o, |bytes| bytes.len())
+}
+
+pub unsafe fn lithe_lean_sarray_set_size(o: *mut lean_object, size: usize) {
+    with_bytes(o, |bytes| {
+        assert!(size <= bytes.capacity());
+        bytes.set_len(size);
+    })
+}
+
+pub unsafe fn lithe_lean_inc(o: *mut lean_object) {
+    let prev = obj(o).refs.fetch_add(1, Ordering::SeqCst);
+    assert!(prev > 0, "object revived after its last release");
+}
+
+pub unsafe fn lithe_lean_dec(o: *mut lean_object) {
+    let prev = obj(o).refs.fetch_sub(1, Ordering::SeqCst);
+    assert!(prev > 0, "object released twice");
+    if prev == 1 {
+        let value = std::mem::replace(&mut *obj(o).value.lock().unwrap(), Value::Released);
+        if let Value::Ok(inner) | Value::Err(inner) = value {
+            lithe_lean_dec(inner);
+        }
+    }
+}
+
+pub unsafe fn lithe_lean_mark_mt(o: *mut lean_object) {
+    obj(o).mt.store(true, Ordering::SeqCst);
+}
+
+pub unsafe fn lithe_byte_array_size(o: *mut lean_object) -> usize {
+    lithe_lean_sarray_size(o)
+}
+
+pub unsafe fn lithe_byte_array_copy(o: *mut lean_object, dst: *mut u8) {
+    with_bytes(o, |bytes| std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len()))
+}
+
+#[cfg(lithe_example = "hello")]
+#[allow(non_snake_case)]
+pub unsafe fn initialize_hello_Hello(_builtin: u8) -> *mut lean_object {
+    unit()
+}
+
+#[cfg(lithe_example = "crafter")]
+#[allow(non_snake_case)]
+pub unsafe fn initialize_crafter_Crafter(_builtin: u8) -> *mut lean_object {
+    unit()
+}
+
+pub unsafe fn lithe_io_error_message(e: *mut lean_object) -> *mut lean_object {
+    let message = match &*obj(e).value.lock().unwrap() {
+        Value::Error(message) => message.clone(),
+        _ => panic!("not an error"),
+    };
+    lithe_lean_dec(e);
+    new_obj(Value::Bytes(message.into_bytes()))
+}
+
+pub unsafe fn lithe_handshake() -> *mut lean_object {
+    let name = b"fake";
+    let mut offer = vec![wire::HANDSHAKE_VERSION];
+    offer.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    offer.extend_from_slice(name);
+    offer.extend_from_slice(&[
+        wire::WIRE_VERSION_V1,
+        wire::WIRE_VERSION,
+        wire::STREAM_WIRE_VERSION_V1,
+        wire::STREAM_WIRE_VERSION,
+    ]);
+    let caps =
+        wire::CAP_BATCH_POLL | wire::CAP_NOTIFY | wire::CAP_EXT_METHODS | wire::CAP_RAW_HEADERS;
+    offer.extend_from_slice(&caps.to_be_bytes());
+    ok(Value::Bytes(offer))
+}
+
+pub unsafe fn lithe_select_protocol(_wire: u8, _stream: u8) -> *mut lean_object {
+    unit()
+}
+
+pub unsafe fn lithe_mono_nanos() -> *mut lean_object {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    ok(Value::Scalar(EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64))
+}
+
+pub unsafe fn lithe_new_app_named(_name: *mut lean_object) -> *mut lean_object {
+    ok(Value::Scalar(runtime(|rt| rt.next_id())))
+}
+
+pub unsafe fn lithe_handle(_app: u64, _req: *mut lean_object) -> *mut lean_object {
+    err("fake lean only streams")
+}
+
+pub unsafe fn lithe_free_app(_app: u64) -> *mut lean_object {
+    unit()
+}
+
+pub unsafe fn lithe_handle_async(_app: u64, _req: *mut lean_object) -> *mut lean_object {
+    err("fake lean only streams")
+}
+
+pub unsafe fn lithe_poll_response(_req_id: u64) -> *mut lean_object {
+    err("fake lean only streams")
+}
+
+pub unsafe fn lithe_cancel_request(_req_id: u64) -> *mut lean_object {
+    err("fake lean only streams")
+}
+
+pub unsafe fn lithe_stream_start(app: u64, _req: *mut lean_object) -> *mut lean_object {
+    let id = runtime(|rt| {
+        let id = rt.next_id();
+        rt.streams.insert(id, Stream::default());
+        rt.started.push_back((app, id));
+        id
+    });
+    ok(Value::Scalar(id))
+}
+
+pub unsafe fn lithe_stream_push_body(
+    req_id: u64,
+    chunk: *mut lean_object,
+    is_last: u64,
+) -> *mut lean_object {
+    let chunk = with_bytes(chunk, |bytes| bytes.clone());
+    let pushed = runtime(|rt| match rt.streams.get_mut(&req_id) {
+        Some(s) if !s.cancelled && !s.body_done => {
+            s.body.extend_from_slice(&chunk);
+            s.body_done = is_last != 0;
+            1
+        }
+        _ => 0,
+    });
+    ok(Value::Scalar(pushed))
+}
+
+pub unsafe fn lithe_stream_poll_response(req_id: u64) -> *mut lean_object {
+    let msg = runtime(|rt| rt.stream(req_id).out.pop_front()).unwrap_or_default();
+    ok(Value::Bytes(msg))
+}
+
+pub unsafe fn lithe_stream_poll_batch(req_id: u64, budget: u64) -> *mut lean_object {
+    let batch = runtime(|rt| {
+        let out = &mut rt.stream(req_id).out;
+        let mut batch = Vec::new();
+        while let Some(msg) = out.front() {
+            if !batch.is_empty() && (batch.len() + msg.len()) as u64 > budget {
+                break;
+            }
+            batch.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+            batch.extend_from_slice(msg);
+            out.pop_front();
+        }
+        batch
+    });
+    ok(Value::Bytes(batch))
+}
+
+pub unsafe fn lithe_stream_cancel(req_id: u64) -> *mut lean_object {
+    runtime(|rt| rt.stream(req_id).cancelled = true);
+    unit()
+}
+
+pub unsafe fn lithe_ws_push(_ws_id: u64, _msg: *mut lean_object) -> *mut lean_object {
+    err("fake lean has no websockets")
+}
+
+pub unsafe fn lithe_ws_poll(_ws_id: u64) -> *mut lean_object {
+    err("fake lean has no websockets")
+}
+
+pub unsafe fn lithe_ws_poll_batch(_ws_id: u64, _budget: u64) -> *mut lean_object {
+    err("fake lean has no websockets")
+}
+
+pub unsafe fn lithe_ws_close(_ws_id: u64) -> *mut lean_object {
+    unit()
+}
+
+pub unsafe fn lithe_set_notify_callback(cb: extern "C" fn(kind: u8, id: u64)) {
+    runtime(|rt| rt.notify = Some(cb));
+}
+
+pub unsafe fn hello_new_app() -> *mut lean_object {
+    err("fake lean has no hello app")
+}
+
+pub unsafe fn hello_handle(_app: u64, _req: *mut lean_object) -> *mut lean_object {
+    err("fake lean has no hello app")
+}
+
+pub unsafe fn hello_free_app(_app: u64) -> *mut lean_object {
+    unit()
+}
+
+/// A scripted response stream and what the shim sent it.
+#[derive(Default)]
+pub(crate) struct Stream {
+    pub(crate) body: Vec<u8>,
+    pub(crate) body_done: bool,
+    pub(crate) cancelled: bool,
+    out: VecDeque<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Runtime {
+    last_id: u64,
+    streams: HashMap<u64, Stream>,
+    started: VecDeque<(u64, u64)>,
+    notify: Option<extern "C" fn(u8, u64)>,
+}
+
+impl Runtime {
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn stream(&mut self, id: u64) -> &mut Stream {
+        self.streams.get_mut(&id).expect("unknown stream")
+    }
+}
+
+fn runtime<T>(f: impl FnOnce(&mut Runtime) -> T) -> T {
+    static RUNTIME: OnceLock<Mutex<Runtime>> = OnceLock::new();
+    let rt = RUNTIME.get_or_init(Default::default);
+    f(&mut rt.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// A new Lean `ByteArray` holding `data`, with one reference.
+pub(crate) fn byte_array(data: &[u8]) -> *mut lean_object {
+    new_obj(Value::Bytes(data.to_vec()))
+}
+
+/// References held on `o`; zero once it has been released.
+pub(crate) fn refs(o: *mut lean_object) -> usize {
+    unsafe { obj(o).refs.load(Ordering::SeqCst) }
+}
+
+pub(crate) fn is_mt(o: *mut lean_object) -> bool {
+    unsafe { obj(o).mt.load(Ordering::SeqCst) }
+}
+
+/// Polls `cond` until it holds, failing the test after a few seconds.
+pub(crate) async fn eventually(what: &str, mut cond: impl FnMut() -> bool) {
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while !cond() {
+        assert!(Instant::now() < give_up, "timed out waiting for {what}");
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+}
+
+/// The next stream the shim starts for `app`.
+pub(crate) async fn started(app: u64) -> u64 {
+    let mut id = None;
+    eventually("a stream to start", || {
+        id = runtime(|rt| {
+            let at = rt.started.iter().position(|(a, _)| *a == app)?;
+            rt.started.remove(at).map(|(_, id)| id)
+        });
+        id.is_some()
+    })
+    .await;
+    id.unwrap()
+}
+
+/// Runs `f` on stream `id`.
+pub(crate) fn stream<T>(id: u64, f: impl FnOnce(&mut Stream) -> T) -> T {
+    runtime(|rt| f(rt.stream(id)))
+}
+
+fn queue(id: u64, msg: Vec<u8>) {
+    let notify = runtime(|rt| {
+        rt.stream(id).out.push_back(msg);
+        rt.notify
+    });
+    if let Some(notify) = notify {
+        notify(notify::STREAM_RESPONSE, id);
+    }
+}
+
+fn put_bytes(msg: &mut Vec<u8>, bytes: &[u8]) {
+    msg.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    msg.extend_from_slice(bytes);
+}
+
+/// Queues the response head, in the v1 layout.
+pub(crate) fn respond(id: u64, status: u16, headers: &[(&str, &str)], is_stream: bool, body: &[u8]) {
+    let mut msg = vec![wire::STREAM_WIRE_VERSION_V1, wire::STREAM_MSG_HEAD];
+    msg.extend_from_slice(&status.to_be_bytes());
+    msg.push(is_stream as u8);
+    msg.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+    for (name, value) in headers {
+        put_bytes(&mut msg, name.as_bytes());
+        put_bytes(&mut msg, value.as_bytes());
+    }
+    put_bytes(&mut msg, body);
+    queue(id, msg);
+}
+
+/// Queues a chunk of a streamed response.
+pub(crate) fn send(id: u64, chunk: &[u8]) {
+    let mut msg = vec![wire::STREAM_WIRE_VERSION_V1, wire::STREAM_MSG_CHUNK];
+    put_bytes(&mut msg, chunk);
+    queue(id, msg);
+}
@@ -1,27 +1,45 @@
+use std::fmt;
+
 #[repr(C)]
 pub struct lean_object {
     _private: [u8; 0],
 }
 
+/// An exception that escaped a Lean export.
+#[derive(Clone, Debug)]
+pub struct LeanError {
+    pub message: String,
+}
+
+impl fmt::Display for LeanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lean error: {}", self.message)
+    }
+}
+
+impl std::error::Error for LeanError {}
+
+#[cfg(not(test))]
 extern "C" {
     pub fn lean_initialize_runtime_module();
     pub fn lean_initialize();
     pub fn lean_initialize_thread();
     pub fn lean_init_task_manager();
+    pub fn lean_init_task_manager_using(num_workers: u32);
     pub fn lean_finalize_task_manager();
 
-    pub fn lean_io_result_show_error(r: *mut lean_object);
-
     pub fn lithe_lean_io_result_is_ok(r: *mut lean_object) -> bool;
     pub fn lithe_lean_io_result_get_value(r: *mut lean_object) -> *mut lean_object;
-    #[allow(dead_code)]
     pub fn lithe_lean_io_result_get_error(r: *mut lean_object) -> *mut lean_object;
 
     pub fn lithe_lean_unbox_uint64(o: *mut lean_object) -> u64;
     pub fn lithe_lean_alloc_sarray(elem_size: u32, size: usize, capacity: usize) -> *mut lean_object;
     pub fn lithe_lean_sarray_cptr(o: *mut lean_object) -> *mut u8;
     pub fn lithe_lean_sarray_size(o: *mut lean_object) -> usize;
+    pub fn lithe_lean_sarray_set_size(o: *mut lean_object, size: usize);
+    pub fn lithe_lean_inc(o: *mut lean_object);
     pub fn lithe_lean_dec(o: *mut lean_object);
+    pub fn lithe_lean_mark_mt(o: *mut lean_object);
     pub fn lithe_byte_array_size(o: *mut lean_object) -> usize;
     pub fn lithe_byte_array_copy(o: *mut lean_object, dst: *mut u8);
 
@@ -30,6 +48,10 @@ extern "C" {
     #[cfg(lithe_example = "crafter")]
     pub fn initialize_crafter_Crafter(builtin: u8) -> *mut lean_object;
 
+    pub fn lithe_io_error_message(err: *mut lean_object) -> *mut lean_object;
+    pub fn lithe_handshake() -> *mut lean_object;
+    pub fn lithe_select_protocol(wire: u8, stream: u8) -> *mut lean_object;
+    pub fn lithe_mono_nanos() -> *mut lean_object;
     pub fn lithe_new_app_named(name: *mut lean_object) -> *mut lean_object;
     pub fn lithe_handle(app: u64, req: *mut lean_object) -> *mut lean_object;
     pub fn lithe_free_app(app: u64) -> *mut lean_object;
@@ -43,12 +65,16 @@ extern "C" {
         is_last: u64,
     ) -> *mut lean_object;
     pub fn lithe_stream_poll_response(req_id: u64) -> *mut lean_object;
+    pub fn lithe_stream_poll_batch(req_id: u64, budget: u64) -> *mut lean_object;
     pub fn lithe_stream_cancel(req_id: u64) -> *mut lean_object;
 
     pub fn lithe_ws_push(ws_id: u64, msg: *mut lean_object) -> *mut lean_object;
     pub fn lithe_ws_poll(ws_id: u64) -> *mut lean_object;
+    pub fn lithe_ws_poll_batch(ws_id: u64, budget: u64) -> *mut lean_object;
     pub fn lithe_ws_close(ws_id: u64) -> *mut lean_object;
 
+    pub fn lithe_set_notify_callback(cb: extern "C" fn(kind: u8, id: u64));
+
     #[allow(dead_code)]
     pub fn hello_new_app() -> *mut lean_object;
     #[allow(dead_code)]
@@ -57,6 +83,12 @@ extern "C" {
     pub fn hello_free_app(app: u64) -> *mut lean_object;
 }
 
+// Unit tests run against a scripted stand-in rather than linking Lean.
+#[cfg(test)]
+pub use crate::fake_lean::*;
+
+/// # Safety
+/// The Lean runtime must be initialized on the calling thread.
 pub unsafe fn mk_byte_array(data: &[u8]) -> *mut lean_object {
     let size = data.len();
     let arr = lithe_lean_alloc_sarray(1, size, size);
@@ -65,6 +97,8 @@ pub unsafe fn mk_byte_array(data: &[u8]) -> *mut lean_object {
     arr
 }
 
+/// # Safety
+/// `arr` must point to a live Lean `ByteArray`.
 pub unsafe fn byte_array_to_vec(arr: *mut lean_object) -> Vec<u8> {
     let size = lithe_byte_array_size(arr);
     if size == 0 {
@@ -75,14 +109,25 @@ pub unsafe fn byte_array_to_vec(arr: *mut lean_object) -> Vec<u8> {
     out
 }
 
-pub unsafe fn unwrap_io_result<T>(res: *mut lean_object, f: impl FnOnce(*mut lean_object) -> T) -> T {
+/// # Safety
+/// `res` must be an owned Lean `IO` result; it is consumed. `f` only borrows
+/// the value.
+pub unsafe fn io_result<T>(
+    res: *mut lean_object,
+    f: impl FnOnce(*mut lean_object) -> T,
+) -> Result<T, LeanError> {
     if lithe_lean_io_result_is_ok(res) {
         let val = lithe_lean_io_result_get_value(res);
         let out = f(val);
         lithe_lean_dec(res);
-        out
+        Ok(out)
     } else {
-        lean_io_result_show_error(res);
-        panic!("lean IO error");
+        let err = lithe_lean_io_result_get_error(res);
+        lithe_lean_inc(err);
+        let msg = lithe_io_error_message(err);
+        let message = String::from_utf8_lossy(&byte_array_to_vec(msg)).into_owned();
+        lithe_lean_dec(msg);
+        lithe_lean_dec(res);
+        Err(LeanError { message })
     }
 }
@@ -0,0 +1,280 @@
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+use crate::conn::ConnInfo;
+use crate::proxy::Cidr;
+
+static CONFIG: OnceLock<Result<ForwardedConfig, String>> = OnceLock::new();
+
+// Removed from requests whose peer is not a trusted proxy.
+const FORWARDING_HEADERS: &[&str] = &[
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-forwarded-port",
+    "x-real-ip",
+];
+
+/// Reverse proxies whose forwarding headers are believed, read once from
+/// `LITHE_TRUSTED_PROXIES`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ForwardedConfig {
+    trusted: Vec<Cidr>,
+    /// Trust peers on Unix sockets, which have no address to match.
+    unix: bool,
+}
+
+impl ForwardedConfig {
+    /// Parses a comma-separated list of CIDRs, plus `unix` for Unix socket peers.
+    fn parse(list: &str) -> Result<Self, String> {
+        let mut cfg = ForwardedConfig::default();
+        for item in list.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            if item == "unix" {
+                cfg.unix = true;
+            } else {
+                cfg.trusted.push(item.parse()?);
+            }
+        }
+        Ok(cfg)
+    }
+
+    fn from_env() -> Result<Self, String> {
+        let Ok(list) = std::env::var("LITHE_TRUSTED_PROXIES") else {
+            return Ok(Self::default());
+        };
+        Self::parse(&list).map_err(|err| format!("invalid LITHE_TRUSTED_PROXIES: {err}"))
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn trusts_peer(&self, conn: &ConnInfo) -> bool {
+        match conn.remote {
+            Some(addr) => self.trusts(addr.ip()),
+            None => self.unix && conn.unix_path.is_some(),
+        }
+    }
+}
+
+/// Serving fails on an invalid list first (see [`check`]); until then it
+/// trusts no one.
+pub(crate) fn config() -> &'static ForwardedConfig {
+    static UNTRUSTED: ForwardedConfig = ForwardedConfig {
+        trusted: Vec::new(),
+        unix: false,
+    };
+    CONFIG.get_or_init(ForwardedConfig::from_env).as_ref().unwrap_or(&UNTRUSTED)
+}
+
+/// Why `LITHE_TRUSTED_PROXIES` cannot be used, if it cannot.
+pub(crate) fn check() -> Result<(), String> {
+    let cfg = CONFIG.get_or_init(ForwardedConfig::from_env);
+    cfg.as_ref().map(drop).map_err(Clone::clone)
+}
+
+/// The client as Lean should see it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Client {
+    pub(crate) remote: Option<String>,
+    pub(crate) scheme: &'static str,
+}
+
+// One proxy hop: who the request came from, and how it was addressed.
+#[derive(Debug, Default)]
+struct Hop {
+    node: String,
+    ip: Option<IpAddr>,
+    proto: Option<&'static str>,
+    host: Option<String>,
+}
+
+/// Resolves the client behind trusted proxies. The rightmost hop that is
+/// not itself a trusted proxy is the client, and its `proto`/`host` replace
+/// the scheme and `Host` header. Forwarding headers from untrusted peers are
+/// removed so Lean never sees spoofed values.
+pub(crate) fn resolve(cfg: &ForwardedConfig, conn: &ConnInfo, headers: &mut HeaderMap) -> Client {
+    let direct = Client {
+        remote: conn.remote.map(|addr| addr.to_string()),
+        scheme: conn.scheme,
+    };
+    if !cfg.trusts_peer(conn) {
+        for name in FORWARDING_HEADERS {
+            headers.remove(*name);
+        }
+        return direct;
+    }
+    let hops = if headers.contains_key(header::FORWARDED) {
+        forwarded_hops(headers)
+    } else {
+        x_forwarded_hops(headers)
+    };
+    let hop = hops
+        .iter()
+        .rev()
+        .find(|hop| !hop.ip.is_some_and(|ip| cfg.trusts(ip)))
+        .or(hops.first());
+    let Some(hop) = hop else {
+        return direct;
+    };
+    if let Some(host) = hop.host.as_deref().and_then(|h| HeaderValue::from_str(h).ok()) {
+        headers.insert(header::HOST, host);
+    }
+    Client {
+        remote: Some(hop.node.clone()),
+        scheme: hop.proto.unwrap_or(conn.scheme),
+    }
+}
+
+fn list<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(HeaderName::from_bytes(name.as_bytes()).expect("static header name"))
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn proto(value: &str) -> Option<&'static str> {
+    match value.to_ascii_lowercase().as_str() {
+        "http" => Some("http"),
+        "https" => Some("https"),
+        _ => None,
+    }
+}
+
+// `for` values: `192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]:80`, a bare IPv6
+// address in X-Forwarded-For, or an obfuscated token such as `unknown`.
+fn node(value: &str) -> (String, Option<IpAddr>) {
+    let value = value.trim_matches('"');
+    let ip = value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            let inner = value.strip_prefix('[')?.split(']').next()?;
+            inner.parse().ok()
+        });
+    (value.to_string(), ip)
+}
+
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    list(headers, "forwarded")
+        .into_iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim().trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => (hop.node, hop.ip) = node(value),
+                    "proto" => hop.proto = proto(value),
+                    "host" => hop.host = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .filter(|hop| !hop.node.is_empty())
+        .collect()
+}
+
+// X-Forwarded-Proto and -Host line up with X-Forwarded-For when they have
+// one entry per hop; otherwise their last entry applies to every hop.
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let fors = list(headers, "x-forwarded-for");
+    let protos = list(headers, "x-forwarded-proto");
+    let hosts = list(headers, "x-forwarded-host");
+    fors.iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let (node, ip) = node(value);
+            Hop {
+                node,
+                ip,
+                proto: aligned(&protos, fors.len(), i).and_then(proto),
+                host: aligned(&hosts, fors.len(), i).map(str::to_string),
+            }
+        })
+        .collect()
+}
+
+fn aligned<'a>(values: &[&'a str], hops: usize, i: usize) -> Option<&'a str> {
+    if values.len() == hops {
+        values.get(i).copied()
+    } else {
+        values.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(remote: &str) -> ConnInfo {
+        ConnInfo::new(Some(remote.parse().unwrap()), None, "http")
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    #[test]
+    fn untrusted_peers_lose_forwarding_headers() {
+        let cfg = ForwardedConfig::parse("10.0.0.0/8").unwrap();
+        let mut h = headers(&[("x-forwarded-for", "1.2.3.4"), ("forwarded", "for=1.2.3.4"), ("x-other", "1")]);
+        let client = resolve(&cfg, &conn("203.0.113.5:4000"), &mut h);
+        assert_eq!(client.remote.as_deref(), Some("203.0.113.5:4000"));
+        assert!(h.get("x-forwarded-for").is_none() && h.get("forwarded").is_none());
+        assert!(h.get("x-other").is_some());
+    }
+
+    #[test]
+    fn x_forwarded_for_skips_trusted_hops() {
+        let cfg = ForwardedConfig::parse("10.0.0.0/8").unwrap();
+        let mut h = headers(&[
+            ("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.0.0.3"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "shop.example"),
+        ]);
+        let client = resolve(&cfg, &conn("10.0.0.2:5000"), &mut h);
+        // 6.6.6.6 came from the client itself and is not believed.
+        assert_eq!(client.remote.as_deref(), Some("198.51.100.7"));
+        assert_eq!(client.scheme, "https");
+        assert_eq!(h.get("host").unwrap(), "shop.example");
+        assert!(h.get("x-forwarded-for").is_some());
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_and_reads_its_own_hop() {
+        let cfg = ForwardedConfig::parse("10.0.0.0/8,unix").unwrap();
+        let mut h = headers(&[
+            ("forwarded", r#"for="[2001:db8::7]:4711";proto=https;host=a.example, for=10.0.0.9;proto=http"#),
+            ("x-forwarded-for", "9.9.9.9"),
+        ]);
+        let unix = ConnInfo::new(None, None, "http").with_unix_path("/run/lithe.sock".into());
+        let client = resolve(&cfg, &unix, &mut h);
+        assert_eq!(client.remote.as_deref(), Some("[2001:db8::7]:4711"));
+        assert_eq!(client.scheme, "https");
+        assert_eq!(h.get("host").unwrap(), "a.example");
+    }
+
+    #[test]
+    fn all_trusted_hops_resolve_to_the_first() {
+        let cfg = ForwardedConfig::parse("10.0.0.0/8").unwrap();
+        let mut h = headers(&[("x-forwarded-for", "10.1.1.1, 10.2.2.2")]);
+        let client = resolve(&cfg, &conn("10.0.0.2:5000"), &mut h);
+        assert_eq!(client.remote.as_deref(), Some("10.1.1.1"));
+        assert_eq!(client.scheme, "http");
+        assert!(ForwardedConfig::parse("10.0.0.0/8,nope").is_err());
+    }
+}
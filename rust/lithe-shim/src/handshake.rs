@@ -0,0 +1,199 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::{ffi, wire};
+
+/// Optional features the Lean library advertised in its handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `lithe_stream_poll_batch` / `lithe_ws_poll_batch` are available.
+    pub batch_poll: bool,
+    /// Lean calls the notify callback when a stream or socket becomes ready.
+    pub notify: bool,
+    /// Requests may use methods outside `Lithe.Method`'s fixed variants.
+    pub ext_methods: bool,
+    /// Request header values may be any bytes rather than UTF-8 only.
+    pub raw_headers: bool,
+}
+
+impl Capabilities {
+    fn from_bits(bits: u32) -> Self {
+        Self {
+            batch_poll: bits & wire::CAP_BATCH_POLL != 0,
+            notify: bits & wire::CAP_NOTIFY != 0,
+            ext_methods: bits & wire::CAP_EXT_METHODS != 0,
+            raw_headers: bits & wire::CAP_RAW_HEADERS != 0,
+        }
+    }
+}
+
+/// The protocol agreed with the Lean library at startup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub lean_version: String,
+    pub wire_version: u8,
+    pub stream_wire_version: u8,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Clone, Debug)]
+pub enum HandshakeError {
+    /// A handshake export raised.
+    Lean(String),
+    /// The handshake payload could not be read.
+    Decode(String),
+    /// The shim and the Lean library share no version of a wire format.
+    NoCommonVersion {
+        what: &'static str,
+        lean_version: String,
+        lean: (u8, u8),
+        shim: (u8, u8),
+    },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Lean(msg) => write!(f, "lean handshake failed: {msg}"),
+            HandshakeError::Decode(msg) => write!(f, "invalid lean handshake: {msg}"),
+            HandshakeError::NoCommonVersion {
+                what,
+                lean_version,
+                lean,
+                shim,
+            } => write!(
+                f,
+                "no common {what} version: lean library {lean_version} supports {}..={}, \
+                 lithe-shim supports {}..={}; rebuild both from the same lithe checkout",
+                lean.0, lean.1, shim.0, shim.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+static PROTOCOL: OnceLock<Result<Protocol, HandshakeError>> = OnceLock::new();
+
+fn pick(
+    what: &'static str,
+    offer: &wire::HandshakeOffer,
+    lean: (u8, u8),
+    shim: (u8, u8),
+) -> Result<u8, HandshakeError> {
+    let lo = lean.0.max(shim.0);
+    let hi = lean.1.min(shim.1);
+    if lo <= hi {
+        Ok(hi)
+    } else {
+        Err(HandshakeError::NoCommonVersion {
+            what,
+            lean_version: offer.library_version.clone(),
+            lean,
+            shim,
+        })
+    }
+}
+
+/// Picks the highest wire and stream wire versions both sides support.
+fn negotiate(offer: &wire::HandshakeOffer) -> Result<Protocol, HandshakeError> {
+    let wire_version = pick(
+        "wire",
+        offer,
+        (offer.wire_min, offer.wire_max),
+        (wire::WIRE_VERSION_V1, wire::WIRE_VERSION),
+    )?;
+    let stream_wire_version = pick(
+        "stream wire",
+        offer,
+        (offer.stream_wire_min, offer.stream_wire_max),
+        (wire::STREAM_WIRE_VERSION_V1, wire::STREAM_WIRE_VERSION),
+    )?;
+    Ok(Protocol {
+        lean_version: offer.library_version.clone(),
+        wire_version,
+        stream_wire_version,
+        capabilities: Capabilities::from_bits(offer.capabilities),
+    })
+}
+
+unsafe fn handshake() -> Result<Protocol, HandshakeError> {
+    let res = ffi::lithe_handshake();
+    let bytes = ffi::io_result(res, |val| ffi::byte_array_to_vec(val))
+        .map_err(|err| HandshakeError::Lean(err.message))?;
+    let offer = wire::decode_handshake(&bytes).map_err(HandshakeError::Decode)?;
+    let protocol = negotiate(&offer)?;
+    let res = ffi::lithe_select_protocol(protocol.wire_version, protocol.stream_wire_version);
+    ffi::io_result(res, |_| ()).map_err(|err| HandshakeError::Lean(err.message))?;
+    Ok(protocol)
+}
+
+/// Runs the handshake once, on a Lean thread during runtime initialization.
+pub(crate) unsafe fn run() {
+    PROTOCOL.get_or_init(|| handshake());
+}
+
+/// The handshake outcome. Only call once the runtime is initialized.
+pub(crate) fn get() -> Result<&'static Protocol, &'static HandshakeError> {
+    PROTOCOL
+        .get()
+        .expect("lean runtime not initialized")
+        .as_ref()
+}
+
+/// Capabilities in use; none until a handshake succeeds.
+pub(crate) fn capabilities() -> Capabilities {
+    match PROTOCOL.get() {
+        Some(Ok(p)) => p.capabilities,
+        _ => Capabilities::default(),
+    }
+}
+
+/// Wire version for encoding requests; v1 until a handshake succeeds.
+pub(crate) fn wire_version() -> u8 {
+    match PROTOCOL.get() {
+        Some(Ok(p)) => p.wire_version,
+        _ => wire::WIRE_VERSION_V1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(wire: (u8, u8), stream: (u8, u8), capabilities: u32) -> wire::HandshakeOffer {
+        wire::HandshakeOffer {
+            library_version: "0.1.0".to_string(),
+            wire_min: wire.0,
+            wire_max: wire.1,
+            stream_wire_min: stream.0,
+            stream_wire_max: stream.1,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version() {
+        let p = negotiate(&offer((1, 9), (1, 1), wire::CAP_NOTIFY)).unwrap();
+        assert_eq!(p.wire_version, wire::WIRE_VERSION);
+        assert_eq!(p.stream_wire_version, 1);
+        assert_eq!(
+            p.capabilities,
+            Capabilities {
+                batch_poll: false,
+                notify: true,
+                ext_methods: false,
+                raw_headers: false,
+            }
+        );
+    }
+
+    #[test]
+    fn negotiate_rejects_disjoint_versions() {
+        let err = negotiate(&offer((5, 7), (1, 2), 0)).unwrap_err();
+        assert!(matches!(err, HandshakeError::NoCommonVersion { what: "wire", .. }));
+        let msg = err.to_string();
+        assert!(msg.contains("5..=7"), "{msg}");
+        assert!(msg.contains("same lithe checkout"), "{msg}");
+    }
+}
@@ -0,0 +1,423 @@
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::http::{header, HeaderValue, Request, StatusCode, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::server::accept::Accept;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::conn::ConnInfo;
+use crate::{error, limit};
+
+static CONFIG: OnceLock<HardeningConfig> = OnceLock::new();
+
+// Sent, then the connection closed, when a request head is not complete in time.
+const REQUEST_TIMEOUT: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+// Room in hyper's read buffer for the request line and line endings.
+const REQUEST_LINE_SLACK: usize = 1024;
+
+/// Limits on what a client may send before the request reaches Lean, read
+/// once from the environment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HardeningConfig {
+    /// Request header names and values, in bytes.
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_headers: usize,
+    /// Path and query, in bytes.
+    pub(crate) max_uri_bytes: usize,
+    /// From the first byte of a request (or the connection opening) to the
+    /// end of its head.
+    pub(crate) header_timeout: Option<Duration>,
+    /// Between the end of one response and the next request.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Requests served on one connection before it is closed.
+    pub(crate) max_requests: Option<u64>,
+}
+
+impl HardeningConfig {
+    fn from_env() -> Self {
+        let num = |key: &str| std::env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+        let timeout = |key: &str, default: u64, unit: fn(u64) -> Duration| {
+            Some(num(key).unwrap_or(default)).filter(|n| *n > 0).map(unit)
+        };
+        Self {
+            max_header_bytes: num("LITHE_MAX_HEADER_BYTES").map_or(32 * 1024, |n| n as usize),
+            max_headers: num("LITHE_MAX_HEADERS").map_or(100, |n| n as usize),
+            max_uri_bytes: num("LITHE_MAX_URI_BYTES").map_or(8 * 1024, |n| n as usize),
+            header_timeout: timeout("LITHE_HEADER_TIMEOUT_MS", 10_000, Duration::from_millis),
+            idle_timeout: timeout("LITHE_KEEPALIVE_TIMEOUT_SECS", 60, Duration::from_secs),
+            max_requests: num("LITHE_MAX_CONN_REQUESTS").filter(|n| *n > 0),
+        }
+    }
+}
+
+pub(crate) fn config() -> &'static HardeningConfig {
+    CONFIG.get_or_init(HardeningConfig::from_env)
+}
+
+/// Caps hyper's buffers so an oversized head fails in hyper with 431 or 414
+/// before [`middleware`] would see it.
+pub(crate) fn configure<I>(builder: hyper::server::Builder<I>) -> hyper::server::Builder<I> {
+    let cfg = config();
+    let head = cfg.max_header_bytes + cfg.max_uri_bytes + REQUEST_LINE_SLACK;
+    // HTTP/2 charges 32 bytes of overhead per header (RFC 7540 §6.5.2).
+    let list = cfg.max_header_bytes + 32 * cfg.max_headers;
+    builder
+        .http1_max_buf_size(head.max(8192))
+        .http2_max_header_list_size(list.try_into().unwrap_or(u32::MAX))
+}
+
+/// Requests in flight on one connection, shared between its [`TimedConn`]
+/// and [`middleware`].
+#[derive(Debug, Default)]
+pub(crate) struct Activity {
+    active: AtomicUsize,
+    served: AtomicU64,
+    // HTTP/2 and upgraded connections carry frames between requests, so the
+    // HTTP/1 head and idle timeouts do not apply.
+    exempt: AtomicBool,
+}
+
+impl Activity {
+    pub(crate) fn exempt(&self) {
+        self.exempt.store(true, Ordering::Relaxed);
+    }
+
+    fn begin(self: &Arc<Self>) -> (u64, Active) {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        let served = self.served.fetch_add(1, Ordering::AcqRel) + 1;
+        (served, Active(self.clone()))
+    }
+}
+
+struct Active(Arc<Activity>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Wraps every connection `inner` accepts in a [`TimedConn`].
+pub(crate) fn incoming<A>(inner: A) -> Incoming<A> {
+    let cfg = config();
+    Incoming {
+        inner: Box::pin(inner),
+        header_timeout: cfg.header_timeout,
+        idle_timeout: cfg.idle_timeout,
+    }
+}
+
+pub struct Incoming<A> {
+    inner: Pin<Box<A>>,
+    header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<A: Accept> Accept for Incoming<A> {
+    type Conn = TimedConn<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (header_timeout, idle_timeout) = (self.header_timeout, self.idle_timeout);
+        self.inner.as_mut().poll_accept(cx).map(|conn| {
+            conn.map(|conn| conn.map(|conn| TimedConn::new(conn, header_timeout, idle_timeout)))
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    // Waiting for the first byte of a request.
+    Idle,
+    // Part of a request head has arrived.
+    Head,
+}
+
+/// A connection that is closed when its client is too slow to send a request
+/// head, or stays idle too long between requests. A late head is answered
+/// with 408 first.
+pub struct TimedConn<C> {
+    inner: C,
+    activity: Arc<Activity>,
+    header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    phase: Phase,
+    // Requests begun as of the last read; a change means a head completed.
+    seen: u64,
+    // The first request's head is timed from accept, not from its first byte.
+    first: bool,
+    timer: Option<Pin<Box<Sleep>>>,
+    // Bytes of the 408 response written so far, once timed out.
+    closing: Option<usize>,
+}
+
+impl<C> TimedConn<C> {
+    fn new(inner: C, header_timeout: Option<Duration>, idle_timeout: Option<Duration>) -> Self {
+        let mut conn = TimedConn {
+            inner,
+            activity: Arc::new(Activity::default()),
+            header_timeout,
+            idle_timeout,
+            phase: Phase::Idle,
+            seen: 0,
+            first: true,
+            timer: None,
+            closing: None,
+        };
+        conn.arm(header_timeout);
+        conn
+    }
+
+    fn arm(&mut self, timeout: Option<Duration>) {
+        self.timer = timeout.map(|t| Box::pin(tokio::time::sleep_until(Instant::now() + t)));
+    }
+
+    fn expired(&mut self, cx: &mut Context<'_>) -> bool {
+        self.timer.as_mut().is_some_and(|timer| timer.as_mut().poll(cx).is_ready())
+    }
+}
+
+impl<'a, C> Connected<&'a TimedConn<C>> for ConnInfo
+where
+    ConnInfo: Connected<&'a C>,
+{
+    fn connect_info(target: &'a TimedConn<C>) -> Self {
+        let info = <ConnInfo as Connected<&'a C>>::connect_info(&target.inner);
+        info.with_activity(target.activity.clone())
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> TimedConn<C> {
+    fn poll_request_timeout(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let written = self.closing.get_or_insert(0);
+        while *written < REQUEST_TIMEOUT.len() {
+            match Pin::new(&mut self.inner).poll_write(cx, &REQUEST_TIMEOUT[*written..]) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(n)) => *written += n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for TimedConn<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.closing.is_some() {
+            // Reads see end of file once the 408 is out.
+            return this.poll_request_timeout(cx);
+        }
+        if this.activity.exempt.load(Ordering::Relaxed)
+            || this.activity.active.load(Ordering::Acquire) > 0
+        {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let served = this.activity.served.load(Ordering::Acquire);
+        if served != this.seen {
+            this.seen = served;
+            this.phase = Phase::Idle;
+            this.arm(this.idle_timeout);
+        }
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[before..];
+                if this.first && read.starts_with(b"PRI ") {
+                    // The HTTP/2 preface.
+                    this.activity.exempt();
+                }
+                if !read.is_empty() && this.phase == Phase::Idle {
+                    this.phase = Phase::Head;
+                    if !std::mem::take(&mut this.first) {
+                        this.arm(this.header_timeout);
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending if this.expired(cx) => match this.phase {
+                Phase::Head => this.poll_request_timeout(cx),
+                _ => Poll::Ready(Ok(())),
+            },
+            other => other,
+        }
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for TimedConn<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn is_http1(version: Version) -> bool {
+    matches!(version, Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11)
+}
+
+// HTTP/2 forbids `Connection`; there the stream just ends.
+pub(crate) fn close(version: Version, mut resp: Response) -> Response {
+    if is_http1(version) {
+        resp.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    resp
+}
+
+fn reject(version: Version, status: StatusCode, code: &str, message: &str) -> Response {
+    let resp = error::error_response(status, code, message).map(axum::body::boxed);
+    close(version, resp)
+}
+
+/// Middleware that refuses requests whose heads are over the configured
+/// limits, and closes connections that have served their quota of requests.
+/// Rejections never reach Lean and close the connection.
+pub(crate) async fn middleware<B>(req: Request<B>, next: Next<B>) -> Response {
+    let cfg = config();
+    let version = req.version();
+    let uri_bytes = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+    if uri_bytes > cfg.max_uri_bytes {
+        return reject(version, StatusCode::URI_TOO_LONG, "uri_too_long", "request URI is too long");
+    }
+    if req.headers().len() > cfg.max_headers {
+        let status = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        return reject(version, status, "too_many_headers", "too many request headers");
+    }
+    let header_bytes: usize = req
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if header_bytes > cfg.max_header_bytes {
+        let status = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        return reject(version, status, "headers_too_large", "request headers are too large");
+    }
+
+    let activity = req
+        .extensions()
+        .get::<ConnectInfo<ConnInfo>>()
+        .and_then(|ConnectInfo(conn)| conn.activity.clone());
+    let Some(activity) = activity else {
+        return next.run(req).await;
+    };
+    let (served, active) = activity.begin();
+    match cfg.max_requests {
+        // Only HTTP/2 can get here: HTTP/1 closed after the last allowed
+        // request. Clients retry a 421 on a new connection.
+        Some(max) if served > max => reject(
+            version,
+            StatusCode::MISDIRECTED_REQUEST,
+            "connection_exhausted",
+            "connection has served its request limit",
+        ),
+        Some(max) if served == max => limit::hold(close(version, next.run(req).await), active),
+        _ => limit::hold(next.run(req).await, active),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    async fn serve() -> std::net::SocketAddr {
+        let router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(middleware));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Incoming {
+            inner: Box::pin(hyper::server::conn::AddrIncoming::from_listener(listener).unwrap()),
+            header_timeout: Some(TIMEOUT),
+            idle_timeout: Some(TIMEOUT),
+        };
+        let server = configure(axum::Server::builder(incoming))
+            .serve(router.into_make_service_with_connect_info::<ConnInfo>());
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn exchange(addr: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused_and_closed() {
+        let addr = serve().await;
+        let uri = "a".repeat(config().max_uri_bytes);
+        let request = format!("GET /{uri} HTTP/1.1\r\nhost: x\r\n\r\n");
+        let response = exchange(addr, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 414"), "{response}");
+        assert!(response.contains("uri_too_long"));
+
+        let big = "a".repeat(config().max_header_bytes);
+        let request = format!("GET / HTTP/1.1\r\nhost: x\r\nx-big: {big}\r\n\r\n");
+        let response = exchange(addr, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+        assert!(response.contains("connection: close"));
+    }
+
+    #[tokio::test]
+    async fn slow_heads_get_408_and_idle_connections_close() {
+        let addr = serve().await;
+        let started = Instant::now();
+        let response = exchange(addr, b"GET / HTTP/1.1\r\nhost: x\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+        assert!(started.elapsed() >= TIMEOUT);
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: x\r\n\r\n").await.unwrap();
+        // Idle after a response: closed without a 408.
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("ok"), "{response}");
+    }
+}
@@ -0,0 +1,553 @@
+use axum::{
+    body::Body,
+    extract::{connect_info::Connected, ConnectInfo},
+    http::{header, request::Parts, Request, Response, StatusCode, Version},
+    response::IntoResponse,
+    routing::future::RouteFuture,
+    Router,
+};
+use bytes::{Buf, Bytes};
+use futures_util::future::Either;
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::upgrade::OnUpgrade;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::io;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+use crate::conn::ConnInfo;
+
+static CONFIG: OnceLock<Http2Config> = OnceLock::new();
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+// SETTINGS_MAX_FRAME_SIZE's initial value; hyper does not raise it.
+const MAX_FRAME_SIZE: usize = 16_384;
+// Time an upgraded client gets to send its connection preface.
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP/2 settings, read once from the environment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Http2Config {
+    /// Serve HTTP/2: ALPN `h2` over TLS, and h2c with prior knowledge or
+    /// `Upgrade: h2c` on cleartext connections.
+    pub(crate) enabled: bool,
+    /// Streams a client may have open at once on one connection.
+    pub(crate) max_concurrent_streams: Option<u32>,
+    /// Initial flow-control window per stream; hyper's default when `None`.
+    pub(crate) stream_window: Option<u32>,
+    /// Initial flow-control window per connection; hyper's default when `None`.
+    pub(crate) connection_window: Option<u32>,
+}
+
+impl Http2Config {
+    fn from_env() -> Self {
+        let num = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .filter(|n| *n > 0)
+        };
+        Self {
+            enabled: !matches!(
+                std::env::var("LITHE_HTTP2").as_deref(),
+                Ok("0" | "false" | "off")
+            ),
+            max_concurrent_streams: num("LITHE_HTTP2_MAX_STREAMS").or(Some(256)),
+            stream_window: num("LITHE_HTTP2_STREAM_WINDOW"),
+            connection_window: num("LITHE_HTTP2_CONN_WINDOW"),
+        }
+    }
+}
+
+pub(crate) fn config() -> &'static Http2Config {
+    CONFIG.get_or_init(Http2Config::from_env)
+}
+
+/// Applies [`config`] to a server; with HTTP/2 disabled it only speaks HTTP/1.
+pub(crate) fn configure<I>(builder: hyper::server::Builder<I>) -> hyper::server::Builder<I> {
+    let cfg = config();
+    if !cfg.enabled {
+        return builder.http1_only(true);
+    }
+    builder
+        .http2_max_concurrent_streams(cfg.max_concurrent_streams)
+        .http2_initial_stream_window_size(cfg.stream_window)
+        .http2_initial_connection_window_size(cfg.connection_window)
+}
+
+fn h2_connection() -> Http {
+    let cfg = config();
+    let mut http = Http::new();
+    http.http2_only(true)
+        .http2_max_concurrent_streams(cfg.max_concurrent_streams)
+        .http2_initial_stream_window_size(cfg.stream_window)
+        .http2_initial_connection_window_size(cfg.connection_window);
+    http
+}
+
+/// Makes each connection's service: the listener's router, with the
+/// connection's [`ConnInfo`] as connect info.
+#[derive(Clone)]
+pub(crate) struct MakeService(pub(crate) Router);
+
+impl<'a, I> Service<&'a I> for MakeService
+where
+    ConnInfo: Connected<&'a I>,
+{
+    type Response = ConnService;
+    type Error = Infallible;
+    type Future = Ready<Result<ConnService, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, target: &'a I) -> Self::Future {
+        ready(Ok(ConnService {
+            router: self.0.clone(),
+            conn: <ConnInfo as Connected<&'a I>>::connect_info(target),
+        }))
+    }
+}
+
+/// One connection's service. An `Upgrade: h2c` request switches the
+/// connection to HTTP/2, still served by this service.
+#[derive(Clone)]
+pub(crate) struct ConnService {
+    router: Router,
+    conn: ConnInfo,
+}
+
+impl Service<Request<Body>> for ConnService {
+    type Response = axum::response::Response;
+    type Error = Infallible;
+    type Future = Either<Ready<Result<Self::Response, Infallible>>, RouteFuture<Body, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Service::<Request<Body>>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+        if let Some(settings) = h2c_upgrade(&parts, &self.conn) {
+            self.conn.upgraded();
+            return Either::Left(ready(Ok(upgrade(parts, settings, self.clone()))));
+        }
+        let mut req = Request::from_parts(parts, body);
+        req.extensions_mut().insert(ConnectInfo(self.conn.clone()));
+        Either::Right(self.router.call(req))
+    }
+}
+
+fn has_token(parts: &Parts, name: header::HeaderName, token: &str) -> bool {
+    parts.headers.get_all(name).iter().any(|v| {
+        v.to_str()
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    })
+}
+
+/// The decoded `HTTP2-Settings` of a request asking to switch a cleartext
+/// HTTP/1.1 connection to HTTP/2 (RFC 7540 §3.2), or `None` if it does not
+/// or cannot. Requests with a body stay on HTTP/1.1, as the spec allows.
+fn h2c_upgrade(parts: &Parts, conn: &ConnInfo) -> Option<Vec<u8>> {
+    let upgrade = config().enabled
+        && conn.tls.is_none()
+        && parts.version == Version::HTTP_11
+        && parts.extensions.get::<OnUpgrade>().is_some()
+        && has_token(parts, header::UPGRADE, "h2c")
+        && has_token(parts, header::CONNECTION, "upgrade")
+        && !parts.headers.contains_key(header::TRANSFER_ENCODING)
+        && parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .is_none_or(|v| v.as_bytes() == b"0");
+    if !upgrade {
+        return None;
+    }
+    let mut values = parts.headers.get_all("http2-settings").iter();
+    let (Some(value), None) = (values.next(), values.next()) else {
+        return None;
+    };
+    base64url(value.as_bytes()).filter(|settings| settings.len() % 6 == 0)
+}
+
+// Decodes unpadded base64url, as `HTTP2-Settings` is sent.
+fn base64url(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &c in input {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6 | v as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // A lone trailing character cannot encode a byte.
+    (bits < 6).then_some(out)
+}
+
+// Answers an `Upgrade: h2c` request with 101 and serves the rest of the
+// connection as HTTP/2 with `service`. The upgrade request itself becomes
+// stream 1, so its response goes out over HTTP/2.
+fn upgrade(mut parts: Parts, settings: Vec<u8>, service: ConnService) -> axum::response::Response {
+    let Some(on_upgrade) = parts.extensions.remove::<OnUpgrade>() else {
+        return (StatusCode::BAD_REQUEST, "connection cannot be upgraded").into_response();
+    };
+    let stream1 = request_frames(&parts);
+    tokio::spawn(async move {
+        let io = match on_upgrade.await {
+            Ok(io) => io,
+            Err(err) => {
+                debug!(error = %err, "h2c upgrade failed");
+                return;
+            }
+        };
+        if let Err(err) = serve_upgraded(io, settings, stream1, service).await {
+            debug!(error = %err, "h2c connection failed");
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "h2c")
+        .body(axum::body::boxed(Body::empty()))
+        .unwrap()
+}
+
+// Waits for the client preface and its SETTINGS frame, then hands hyper the
+// connection as if the client had prior knowledge of HTTP/2. The settings
+// from the upgrade request go first in that SETTINGS frame, so the client's
+// own override them and hyper ACKs the one frame the client expects. The
+// upgrade request follows as stream 1.
+async fn serve_upgraded<I, S, B>(
+    mut io: I,
+    settings: Vec<u8>,
+    stream1: Vec<u8>,
+    service: S,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: hyper::body::HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut prefix = tokio::time::timeout(PREFACE_TIMEOUT, read_preface(&mut io))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no client preface"))??;
+    let rest = prefix.split_off(preface_len(&prefix).unwrap_or(prefix.len()));
+    let client = &prefix[PREFACE.len() + FRAME_HEADER_LEN..];
+    let mut replay = PREFACE.to_vec();
+    let payload = [&settings[..], client].concat();
+    write_frame(&mut replay, FRAME_SETTINGS, 0, 0, &payload);
+    replay.extend_from_slice(&stream1);
+    replay.extend_from_slice(&rest);
+    let io = Rewind {
+        prefix: Bytes::from(replay),
+        inner: io,
+    };
+    h2_connection().serve_connection(io, service).await?;
+    Ok(())
+}
+
+// Length of the preface plus the first SETTINGS frame, once all of it is in `buf`.
+fn preface_len(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(PREFACE.len()..PREFACE.len() + FRAME_HEADER_LEN)?;
+    let len = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+    let total = PREFACE.len() + FRAME_HEADER_LEN + len;
+    (buf.len() >= total).then_some(total)
+}
+
+async fn read_preface<I: AsyncRead + Unpin>(io: &mut I) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(128);
+    loop {
+        let seen = buf.len().min(PREFACE.len());
+        if buf[..seen] != PREFACE[..seen] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP/2 preface"));
+        }
+        if buf.len() > PREFACE.len() + 3 && buf[PREFACE.len() + 3] != FRAME_SETTINGS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "preface not followed by SETTINGS"));
+        }
+        if preface_len(&buf).is_some() {
+            return Ok(buf);
+        }
+        if io.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+fn hpack_int(buf: &mut Vec<u8>, value: usize, prefix_bits: u32) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        buf.push(value as u8);
+        return;
+    }
+    buf.push(max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        buf.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    buf.push(rest as u8);
+}
+
+// "Literal Header Field without Indexing — New Name", no Huffman coding.
+fn hpack_literal(buf: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    buf.push(0);
+    hpack_int(buf, name.len(), 7);
+    buf.extend_from_slice(name);
+    hpack_int(buf, value.len(), 7);
+    buf.extend_from_slice(value);
+}
+
+fn write_frame(buf: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    let len = payload.len() as u32;
+    buf.extend_from_slice(&len.to_be_bytes()[1..]);
+    buf.push(kind);
+    buf.push(flags);
+    buf.extend_from_slice(&stream.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+// Encodes a bodyless HTTP/1.1 request as the HEADERS (+ CONTINUATION) frames
+// of stream 1.
+fn request_frames(parts: &Parts) -> Vec<u8> {
+    let mut block = Vec::new();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .filter(|p| !p.is_empty())
+        .unwrap_or("/");
+    hpack_literal(&mut block, b":method", parts.method.as_str().as_bytes());
+    hpack_literal(&mut block, b":scheme", b"http");
+    if let Some(host) = parts.headers.get(header::HOST) {
+        hpack_literal(&mut block, b":authority", host.as_bytes());
+    }
+    hpack_literal(&mut block, b":path", path.as_bytes());
+    for (name, value) in &parts.headers {
+        // Connection-specific headers are not allowed in HTTP/2.
+        let skip = matches!(
+            name.as_str(),
+            "host"
+                | "connection"
+                | "upgrade"
+                | "http2-settings"
+                | "keep-alive"
+                | "proxy-connection"
+                | "transfer-encoding"
+        ) || (name == header::TE && value.as_bytes() != b"trailers");
+        if !skip {
+            hpack_literal(&mut block, name.as_str().as_bytes(), value.as_bytes());
+        }
+    }
+
+    let mut frames = Vec::with_capacity(block.len() + FRAME_HEADER_LEN);
+    let mut chunks = block.chunks(MAX_FRAME_SIZE).peekable();
+    let mut kind = FRAME_HEADERS;
+    let mut flags = FLAG_END_STREAM;
+    while let Some(chunk) = chunks.next() {
+        let end = if chunks.peek().is_none() { FLAG_END_HEADERS } else { 0 };
+        write_frame(&mut frames, kind, flags | end, 1, chunk);
+        kind = FRAME_CONTINUATION;
+        flags = 0;
+    }
+    frames
+}
+
+// Replays bytes already read from the connection before reading more.
+struct Rewind<I> {
+    prefix: Bytes,
+    inner: I,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Rewind<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Rewind<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tokio::io::AsyncWriteExt;
+
+    fn upgrade_parts(uri: &str) -> Parts {
+        let (parts, _) = Request::builder()
+            .uri(uri)
+            .header(header::HOST, "a.example")
+            .header(header::CONNECTION, "Upgrade, HTTP2-Settings")
+            .header(header::UPGRADE, "h2c")
+            .header("http2-settings", "AAMAAABkAAQAAP__")
+            .header("x-test", "1")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    #[test]
+    fn hpack_int_uses_continuation_bytes() {
+        let mut buf = Vec::new();
+        hpack_int(&mut buf, 10, 5);
+        hpack_int(&mut buf, 1337, 5);
+        assert_eq!(buf, [10, 31, 154, 10]);
+    }
+
+    #[test]
+    fn large_header_blocks_use_continuation_frames() {
+        let mut parts = upgrade_parts("/");
+        parts
+            .headers
+            .insert("x-big", "v".repeat(MAX_FRAME_SIZE).parse().unwrap());
+        let frames = request_frames(&parts);
+        assert_eq!(frames[3], FRAME_HEADERS);
+        assert_eq!(frames[4], FLAG_END_STREAM);
+        let next = FRAME_HEADER_LEN + MAX_FRAME_SIZE;
+        assert_eq!(frames[next + 3], FRAME_CONTINUATION);
+        assert_eq!(frames[next + 4], FLAG_END_HEADERS);
+    }
+
+    #[test]
+    fn http2_settings_must_decode_to_whole_parameters() {
+        let conn = ConnInfo::new(None, None, "http");
+        let mut parts = upgrade_parts("/");
+        parts.extensions.insert(hyper::upgrade::on(Request::new(Body::empty())));
+        let settings = h2c_upgrade(&parts, &conn).unwrap();
+        assert_eq!(settings, [0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 255, 255]);
+
+        for bad in ["AAMAAABk!", "AAMAA", "AAMAAABkA"] {
+            parts.headers.insert("http2-settings", bad.parse().unwrap());
+            assert_eq!(h2c_upgrade(&parts, &conn), None, "{bad}");
+        }
+        parts.headers.insert("http2-settings", "AAMAAABk".parse().unwrap());
+        parts.headers.append("http2-settings", "AAMAAABk".parse().unwrap());
+        assert_eq!(h2c_upgrade(&parts, &conn), None);
+    }
+
+    // Serves `parts` as an upgraded stream 1 over a duplex pipe and returns
+    // the client's side, with the preface and an empty SETTINGS frame sent.
+    async fn upgraded(parts: &Parts, settings: Vec<u8>) -> tokio::io::DuplexStream {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let service = hyper::service::service_fn(|req: Request<Body>| async move {
+            let test = req.headers().get("x-test").cloned();
+            let body = format!("{} {} {:?} {:?}", req.method(), req.uri(), req.version(), test);
+            Ok::<_, Infallible>(Response::new(Body::from(body)))
+        });
+        tokio::spawn(serve_upgraded(server, settings, request_frames(parts), service));
+        client.write_all(PREFACE).await.unwrap();
+        let mut frame = Vec::new();
+        write_frame(&mut frame, FRAME_SETTINGS, 0, 0, &[]);
+        client.write_all(&frame).await.unwrap();
+        client
+    }
+
+    // DATA received on stream 1 until it ends or nothing arrives for a while.
+    async fn stream_one_data(client: &mut tokio::io::DuplexStream) -> String {
+        let mut body = Vec::new();
+        let wait = Duration::from_millis(300);
+        loop {
+            let mut head = [0u8; FRAME_HEADER_LEN];
+            match tokio::time::timeout(wait, client.read_exact(&mut head)).await {
+                Ok(res) => res.unwrap(),
+                Err(_) => break,
+            };
+            let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+            let mut payload = vec![0u8; len];
+            client.read_exact(&mut payload).await.unwrap();
+            let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+            // DATA on stream 1; the response HEADERS come first.
+            if head[3] == 0x0 && stream == 1 {
+                body.extend_from_slice(&payload);
+                if head[4] & FLAG_END_STREAM != 0 {
+                    break;
+                }
+            }
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn upgraded_request_is_served_as_stream_one() {
+        let mut client = upgraded(&upgrade_parts("/items?x=1"), Vec::new()).await;
+        assert_eq!(
+            stream_one_data(&mut client).await,
+            "GET http://a.example/items?x=1 HTTP/2.0 Some(\"1\")"
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrade_request_settings_apply_to_the_connection() {
+        // SETTINGS_INITIAL_WINDOW_SIZE = 4: stream 1 gets 4 bytes until the
+        // client opens the window.
+        let settings = vec![0, 4, 0, 0, 0, 4];
+        let mut client = upgraded(&upgrade_parts("/"), settings).await;
+        assert_eq!(stream_one_data(&mut client).await, "GET ");
+    }
+}
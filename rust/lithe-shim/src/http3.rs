@@ -0,0 +1,445 @@
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderValue, Method, Request, Version},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use bytes::{Buf, Bytes};
+use hyper::body::HttpBody as _;
+use hyper::service::Service;
+use rustls23::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::conn::{ConnInfo, TlsInfo};
+use crate::drain;
+use crate::tls::{self, TlsConfig};
+
+type H3Stream<S> = h3::server::RequestStream<S, Bytes>;
+
+// `H3_NO_ERROR`, sent when the listener shuts down.
+const H3_NO_ERROR: u32 = 0x100;
+
+static ALT_SVC: OnceLock<HeaderValue> = OnceLock::new();
+
+/// Adds `Alt-Svc` to HTTP/1.1 and HTTP/2 responses once a QUIC listener is up.
+pub(crate) async fn alt_svc<B>(req: Request<B>, next: Next<B>) -> Response {
+    let advertise = req.version() != Version::HTTP_3;
+    let mut resp = next.run(req).await;
+    if let Some(value) = ALT_SVC.get().filter(|_| advertise) {
+        resp.headers_mut().insert("alt-svc", value.clone());
+    }
+    resp
+}
+
+fn invalid(msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// QUIC needs its own rustls config (TLS 1.3 only, ALPN `h3`). It serves the
+// first configured certificate and does not reload.
+fn quic_crypto(cfg: &TlsConfig) -> io::Result<rustls23::ServerConfig> {
+    let provider = Arc::new(rustls23::crypto::ring::default_provider());
+    let paths = cfg
+        .certs
+        .first()
+        .ok_or_else(|| invalid("no TLS certificates configured"))?;
+    let chain: Vec<CertificateDer<'static>> = tls::load_certs(&paths.cert)?
+        .into_iter()
+        .map(|cert| CertificateDer::from(cert.0))
+        .collect();
+    let key = PrivateKeyDer::try_from(tls::load_key(&paths.key)?.0).map_err(invalid)?;
+    let builder = rustls23::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls23::version::TLS13])
+        .map_err(invalid)?;
+    let builder = match &cfg.client_ca {
+        Some(path) => {
+            let mut roots = rustls23::RootCertStore::empty();
+            for cert in tls::load_certs(path)? {
+                roots.add(CertificateDer::from(cert.0)).map_err(invalid)?;
+            }
+            let verifier =
+                rustls23::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(chain, key).map_err(invalid)?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(config)
+}
+
+/// Binds a QUIC endpoint on `addr` and advertises it via `Alt-Svc`.
+pub(crate) fn bind(addr: SocketAddr, cfg: &TlsConfig) -> io::Result<quinn::Endpoint> {
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(quic_crypto(cfg)?)
+        .map_err(invalid)?;
+    let endpoint =
+        quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?;
+    let port = endpoint.local_addr()?.port();
+    if let Ok(value) = HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400")) {
+        let _ = ALT_SVC.set(value);
+    }
+    Ok(endpoint)
+}
+
+/// Accepts QUIC connections until `shutdown` resolves. Open connections are
+/// then sent a GOAWAY and get until the drain deadline to finish the
+/// requests they have in flight before the endpoint closes. Each request
+/// becomes the same `Request<Body>` the TCP listeners produce and goes
+/// through `router`, so Lean sees the usual wire encoding and stream session
+/// whatever the transport.
+pub(crate) async fn serve<F>(endpoint: quinn::Endpoint, router: Router, shutdown: F)
+where
+    F: std::future::Future<Output = ()>,
+{
+    tokio::pin!(shutdown);
+    let local = endpoint.local_addr().ok();
+    info!(addr = ?local, "http/3 listening");
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut shutdown => break,
+        };
+        let router = router.clone();
+        let stop_rx = stop_rx.clone();
+        connections.spawn(async move {
+            let conn = match incoming.await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    debug!(error = %err, "QUIC handshake failed");
+                    return;
+                }
+            };
+            let remote = conn.remote_address();
+            if let Err(err) = serve_connection(conn, local, router, stop_rx).await {
+                debug!(%remote, error = %err, "http/3 connection closed");
+            }
+        });
+    }
+    let _ = stop_tx.send(true);
+    let deadline = tokio::time::Instant::now() + drain::drain_timeout();
+    let drained = tokio::time::timeout_at(deadline, async {
+        while connections.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        warn!("http/3 drain deadline passed with connections open; closing them");
+    }
+    endpoint.close(H3_NO_ERROR.into(), b"shutdown");
+    endpoint.wait_idle().await;
+}
+
+fn conn_info(conn: &quinn::Connection, local: Option<SocketAddr>) -> ConnInfo {
+    let handshake = conn
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok());
+    let client = conn
+        .peer_identity()
+        .and_then(|id| id.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|chain| {
+            let leaf = chain.first()?;
+            tls::client_cert(&rustls::Certificate(leaf.to_vec()))
+        });
+    let info = TlsInfo {
+        version: Some("TLSv1.3"),
+        cipher: None,
+        sni: handshake.as_ref().and_then(|h| h.server_name.clone()),
+        alpn: handshake
+            .as_ref()
+            .and_then(|h| h.protocol.as_ref())
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        client,
+    };
+    ConnInfo::new(Some(conn.remote_address()), local, "https").with_tls(info)
+}
+
+async fn serve_connection(
+    conn: quinn::Connection,
+    local: Option<SocketAddr>,
+    router: Router,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), h3::error::ConnectionError> {
+    let info = conn_info(&conn, local);
+    let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+    let mut requests = JoinSet::new();
+    loop {
+        let resolver = tokio::select! {
+            accepted = h3_conn.accept() => match accepted? {
+                Some(resolver) => resolver,
+                None => break,
+            },
+            Some(_) = requests.join_next(), if !requests.is_empty() => continue,
+            _ = async { let _ = stop.wait_for(|stop| *stop).await; } => {
+                // Tells the client to open no more requests on this connection.
+                h3_conn.shutdown(0).await?;
+                break;
+            }
+        };
+        let router = router.clone();
+        let info = info.clone();
+        requests.spawn(async move {
+            match resolver.resolve_request().await {
+                Ok((req, stream)) => {
+                    if let Err(err) = serve_request(req, stream, router, info).await {
+                        debug!(error = %err, "http/3 request failed");
+                    }
+                }
+                Err(err) => debug!(error = %err, "failed to read http/3 request"),
+            }
+        });
+    }
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+// http 1.x (h3) and http 0.2 (hyper/axum) types share their wire forms.
+fn to_request(req: http1::Request<()>, body: Body) -> Result<Request<Body>, String> {
+    let (parts, ()) = req.into_parts();
+    let method = Method::from_bytes(parts.method.as_str().as_bytes()).map_err(|e| e.to_string())?;
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(parts.uri.to_string())
+        .version(Version::HTTP_3);
+    for (name, value) in &parts.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder.body(body).map_err(|e| e.to_string())
+}
+
+fn to_h3_response(resp: &Response) -> Result<http1::Response<()>, String> {
+    let mut builder = http1::Response::builder().status(resp.status().as_u16());
+    for (name, value) in resp.headers() {
+        // Connection-specific headers are not allowed in HTTP/3.
+        let skip = matches!(
+            name.as_str(),
+            "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+        );
+        if !skip {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+    }
+    builder.body(()).map_err(|e| e.to_string())
+}
+
+async fn serve_request<S>(
+    req: http1::Request<()>,
+    stream: H3Stream<S>,
+    mut router: Router,
+    conn: ConnInfo,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: h3::quic::BidiStream<Bytes> + Send + 'static,
+    S::RecvStream: Send + 'static,
+{
+    let (mut send, mut recv) = stream.split();
+    let (mut body_tx, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let bytes = chunk.copy_to_bytes(chunk.remaining());
+                    if body_tx.send_data(bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    warn!(error = %err, "failed to read http/3 request body");
+                    body_tx.abort();
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut req = to_request(req, body)?;
+    req.extensions_mut().insert(ConnectInfo(conn));
+    let resp = router.call(req).await?;
+    send.send_response(to_h3_response(&resp)?).await?;
+    let mut body = resp.into_body();
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    if let Some(trailers) = body.trailers().await? {
+        let mut map = http1::HeaderMap::new();
+        for (name, value) in &trailers {
+            map.append(
+                http1::HeaderName::from_bytes(name.as_str().as_bytes())?,
+                http1::HeaderValue::from_bytes(value.as_bytes())?,
+            );
+        }
+        send.send_trailers(map).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::any;
+
+    fn write_cert(dir: &std::path::Path) -> tls::CertPaths {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let paths = tls::CertPaths {
+            cert: dir.join("cert.pem"),
+            key: dir.join("cert.key"),
+        };
+        std::fs::write(&paths.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&paths.key, cert.serialize_private_key_pem()).unwrap();
+        paths
+    }
+
+    async fn echo(ConnectInfo(conn): ConnectInfo<ConnInfo>, req: Request<Body>) -> String {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let alpn = conn.tls.as_ref().and_then(|t| t.alpn.clone());
+        format!(
+            "{} {} {:?} {:?} {}",
+            parts.method,
+            parts.uri.path(),
+            parts.version,
+            alpn,
+            String::from_utf8_lossy(&body)
+        )
+    }
+
+    type Sender = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    // An HTTP/3 client connection to `addr`, trusting the certificate at `paths`.
+    async fn connect(paths: &tls::CertPaths, addr: SocketAddr) -> (quinn::Endpoint, Sender) {
+        let mut roots = rustls23::RootCertStore::empty();
+        for cert in tls::load_certs(&paths.cert).unwrap() {
+            roots.add(CertificateDer::from(cert.0)).unwrap();
+        }
+        let mut crypto = rustls23::ClientConfig::builder_with_provider(Arc::new(
+            rustls23::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls23::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+        let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+        let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(conn))
+            .await
+            .unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+        (client, sender)
+    }
+
+    #[tokio::test]
+    async fn serves_requests_over_quic() {
+        let dir = std::env::temp_dir().join(format!("lithe-h3-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = write_cert(&dir);
+        let server = bind("127.0.0.1:0".parse().unwrap(), &TlsConfig::new(vec![paths.clone()]))
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let router = Router::new().route("/echo", any(echo));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let served = tokio::spawn(serve(server, router, async {
+            let _ = stop_rx.await;
+        }));
+
+        let (client, mut sender) = connect(&paths, addr).await;
+        let req = http1::Request::post("https://localhost/echo").body(()).unwrap();
+        let mut stream = sender.send_request(req).await.unwrap();
+        stream.send_data(Bytes::from_static(b"hello")).await.unwrap();
+        stream.finish().await.unwrap();
+        let resp = stream.recv_response().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "POST /echo HTTP/3.0 Some(\"h3\") hello"
+        );
+
+        let _ = stop_tx.send(());
+        client.close(0u32.into(), b"done");
+        served.await.unwrap();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_in_flight_requests_finish() {
+        let dir = std::env::temp_dir().join(format!("lithe-h3-drain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = write_cert(&dir);
+        let server = bind("127.0.0.1:0".parse().unwrap(), &TlsConfig::new(vec![paths.clone()]))
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let router = Router::new().route(
+            "/slow",
+            any({
+                let release = release.clone();
+                || async move {
+                    release.notified().await;
+                    "done"
+                }
+            }),
+        );
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let served = tokio::spawn(serve(server, router, async {
+            let _ = stop_rx.await;
+        }));
+
+        let (client, mut sender) = connect(&paths, addr).await;
+        let req = http1::Request::get("https://localhost/slow").body(()).unwrap();
+        let mut stream = sender.send_request(req).await.unwrap();
+        stream.finish().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let _ = stop_tx.send(());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!served.is_finished());
+
+        release.notify_one();
+        let resp = stream.recv_response().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let mut chunk = stream.recv_data().await.unwrap().unwrap();
+        assert_eq!(chunk.copy_to_bytes(chunk.remaining()), "done");
+        served.await.unwrap();
+        client.close(0u32.into(), b"done");
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn advertises_alt_svc_except_over_http3() {
+        let _ = ALT_SVC.set(HeaderValue::from_static("h3=\":4433\"; ma=86400"));
+        let mut router = Router::new()
+            .route("/", any(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(alt_svc));
+        let value = ALT_SVC.get().unwrap().clone();
+
+        let resp = router.call(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(resp.headers().get("alt-svc"), Some(&value));
+
+        let req = Request::builder()
+            .version(Version::HTTP_3)
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.headers().get("alt-svc").is_none());
+    }
+}
@@ -0,0 +1,179 @@
+use bytes::Bytes;
+
+use crate::ffi::{self, lean_object};
+use crate::lean_pool;
+
+// A Lean object pointer that may cross threads. Only used for objects that
+// were marked multi-threaded, so their reference counts are atomic.
+struct LeanObj(*mut lean_object);
+
+unsafe impl Send for LeanObj {}
+unsafe impl Sync for LeanObj {}
+
+impl LeanObj {
+    // The allocator is per-thread, so the reference is dropped on a Lean thread.
+    fn release(self) {
+        if lean_pool::on_lean_thread() {
+            unsafe { ffi::lithe_lean_dec(self.0) }
+        } else {
+            lean_pool::spawn(move || self.release());
+        }
+    }
+
+    fn take(&mut self) -> LeanObj {
+        LeanObj(std::mem::replace(&mut self.0, std::ptr::null_mut()))
+    }
+}
+
+/// A Lean `ByteArray` viewed in place. Dropping it releases the Lean
+/// reference on a Lean thread, since the allocator is per-thread.
+pub(crate) struct LeanBytes {
+    obj: LeanObj,
+    ptr: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for LeanBytes {}
+unsafe impl Sync for LeanBytes {}
+
+impl LeanBytes {
+    /// Wraps `arr` as [`Bytes`] without copying. Empty arrays are released
+    /// right away.
+    ///
+    /// # Safety
+    /// Must run on a Lean thread; `arr` must be an owned Lean `ByteArray`.
+    pub(crate) unsafe fn into_bytes(arr: *mut lean_object) -> Bytes {
+        let len = ffi::lithe_lean_sarray_size(arr);
+        if len == 0 {
+            ffi::lithe_lean_dec(arr);
+            return Bytes::new();
+        }
+        ffi::lithe_lean_mark_mt(arr);
+        let ptr = ffi::lithe_lean_sarray_cptr(arr) as *const u8;
+        Bytes::from_owner(LeanBytes {
+            obj: LeanObj(arr),
+            ptr,
+            len,
+        })
+    }
+
+    /// Like [`LeanBytes::into_bytes`] for a borrowed array, such as the value
+    /// inside an `IO` result.
+    ///
+    /// # Safety
+    /// Must run on a Lean thread; `arr` must be a live Lean `ByteArray`.
+    pub(crate) unsafe fn from_borrowed(arr: *mut lean_object) -> Bytes {
+        ffi::lithe_lean_inc(arr);
+        Self::into_bytes(arr)
+    }
+}
+
+impl AsRef<[u8]> for LeanBytes {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LeanBytes {
+    fn drop(&mut self) {
+        self.obj.take().release();
+    }
+}
+
+/// An empty Lean `ByteArray` that is filled in place. It is allocated and
+/// handed to Lean on Lean threads but written from anywhere, so a request
+/// body is copied once, straight into memory Lean then owns. It is never
+/// marked multi-threaded: until Lean has it, this is its only reference.
+pub(crate) struct LeanBuf {
+    obj: LeanObj,
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+unsafe impl Send for LeanBuf {}
+
+impl LeanBuf {
+    /// An array with room for `cap` bytes.
+    ///
+    /// # Safety
+    /// Must run on a Lean thread.
+    pub(crate) unsafe fn alloc(cap: usize) -> Self {
+        let arr = ffi::lithe_lean_alloc_sarray(1, 0, cap);
+        LeanBuf {
+            obj: LeanObj(arr),
+            ptr: ffi::lithe_lean_sarray_cptr(arr),
+            len: 0,
+            cap,
+        }
+    }
+
+    /// Copies as much of `data` as fits and returns how many bytes that was.
+    pub(crate) fn fill(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.cap - self.len);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), n) };
+        self.len += n;
+        n
+    }
+
+    /// The array, sized to what was written. It stays owned by `self`.
+    ///
+    /// # Safety
+    /// Must run on a Lean thread.
+    pub(crate) unsafe fn as_array(&mut self) -> *mut lean_object {
+        ffi::lithe_lean_sarray_set_size(self.obj.0, self.len);
+        self.obj.0
+    }
+}
+
+impl Drop for LeanBuf {
+    fn drop(&mut self) {
+        self.obj.take().release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_lean::{self, byte_array, eventually, refs};
+
+    #[tokio::test]
+    async fn bytes_hold_one_lean_reference_until_the_last_clone_drops() {
+        let arr = byte_array(b"hello");
+        let bytes = unsafe { LeanBytes::from_borrowed(arr) };
+        assert_eq!(refs(arr), 2);
+        assert!(fake_lean::is_mt(arr));
+
+        let tail = bytes.slice(1..);
+        drop(bytes);
+        assert_eq!(&tail[..], b"ello");
+        assert_eq!(refs(arr), 2);
+        // Released on a Lean thread, since this one is not.
+        drop(tail);
+        eventually("the array to be released", || refs(arr) == 1).await;
+        unsafe { ffi::lithe_lean_dec(arr) };
+        assert_eq!(refs(arr), 0);
+    }
+
+    #[test]
+    fn empty_arrays_are_released_at_once() {
+        let arr = byte_array(b"");
+        let bytes = unsafe { LeanBytes::into_bytes(arr) };
+        assert!(bytes.is_empty());
+        assert_eq!(refs(arr), 0);
+    }
+
+    #[tokio::test]
+    async fn buffers_fill_in_place_up_to_their_capacity() {
+        let mut buf = unsafe { LeanBuf::alloc(4) };
+        assert_eq!(buf.fill(b"abc"), 3);
+        assert_eq!(buf.fill(b"def"), 1);
+        assert_eq!(buf.fill(b"g"), 0);
+        let arr = unsafe { buf.as_array() };
+        assert_eq!(unsafe { ffi::byte_array_to_vec(arr) }, b"abcd");
+        assert_eq!(refs(arr), 1);
+        assert!(!fake_lean::is_mt(arr));
+        drop(buf);
+        eventually("the buffer to be released", || refs(arr) == 0).await;
+    }
+}
@@ -0,0 +1,212 @@
+use std::cell::Cell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::oneshot;
+use tracing::error;
+
+use crate::ffi::LeanError;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Clone, Debug)]
+pub struct LeanConfig {
+    /// OS threads dedicated to calling into Lean.
+    pub threads: usize,
+    /// Workers for Lean's own task manager (`IO.asTask`); `None` uses Lean's default.
+    pub task_workers: Option<usize>,
+}
+
+impl Default for LeanConfig {
+    fn default() -> Self {
+        Self {
+            threads: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            task_workers: None,
+        }
+    }
+}
+
+impl LeanConfig {
+    /// Reads `LITHE_LEAN_THREADS` and `LITHE_LEAN_TASK_WORKERS`, falling back to defaults.
+    pub fn from_env() -> Self {
+        let mut cfg = Self::default();
+        if let Some(n) = env_usize("LITHE_LEAN_THREADS") {
+            cfg.threads = n;
+        }
+        cfg.task_workers = env_usize("LITHE_LEAN_TASK_WORKERS");
+        cfg
+    }
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+}
+
+struct Pool {
+    tx: mpsc::Sender<Job>,
+}
+
+impl Pool {
+    // `init` runs on each thread before it takes a job.
+    fn new(threads: usize, init: fn()) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("lithe-lean-{i}"))
+                .spawn(move || worker(rx, init))
+                .expect("failed to spawn lean thread");
+        }
+        Pool { tx }
+    }
+
+    fn submit(&self, job: Job) {
+        if self.tx.send(job).is_err() {
+            error!("lean pool is gone");
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, LeanError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, LeanError> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.submit(Box::new(move || {
+            let _ = tx.send(f());
+        }));
+        rx.await.unwrap_or_else(|_| Err(lost()))
+    }
+
+    fn run_blocking<T, F>(&self, f: F) -> Result<T, LeanError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, LeanError> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.submit(Box::new(move || {
+            let _ = tx.send(f());
+        }));
+        rx.recv().unwrap_or_else(|_| Err(lost()))
+    }
+}
+
+// The job panicked, or the pool has no threads left to run it.
+fn lost() -> LeanError {
+    LeanError {
+        message: "lean call did not complete".to_string(),
+    }
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+static CONFIG: OnceLock<LeanConfig> = OnceLock::new();
+thread_local! {
+    static ON_POOL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the pool configuration. Only effective before the first Lean call.
+pub(crate) fn configure(cfg: LeanConfig) -> bool {
+    CONFIG.set(cfg).is_ok() && POOL.get().is_none()
+}
+
+pub(crate) fn config() -> &'static LeanConfig {
+    CONFIG.get_or_init(LeanConfig::from_env)
+}
+
+fn pool() -> &'static Pool {
+    POOL.get_or_init(|| Pool::new(config().threads, crate::init_lean_runtime))
+}
+
+fn worker(rx: Arc<Mutex<mpsc::Receiver<Job>>>, init: fn()) {
+    init();
+    ON_POOL.with(|cell| cell.set(true));
+    loop {
+        let job = {
+            let Ok(rx) = rx.lock() else { return };
+            match rx.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            }
+        };
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("lean call panicked");
+        }
+    }
+}
+
+/// Runs `f` on a Lean thread and awaits its result without blocking the
+/// caller. A panic in `f` comes back as a [`LeanError`].
+pub(crate) async fn run<T, F>(f: F) -> Result<T, LeanError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, LeanError> + Send + 'static,
+{
+    pool().run(f).await
+}
+
+/// Runs `f` on a Lean thread, blocking the current thread until it finishes.
+pub(crate) fn run_blocking<T, F>(f: F) -> Result<T, LeanError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, LeanError> + Send + 'static,
+{
+    pool().run_blocking(f)
+}
+
+/// Whether the current thread is a Lean pool thread.
+pub(crate) fn on_lean_thread() -> bool {
+    ON_POOL.with(|cell| cell.get())
+}
+
+/// Queues `f` on a Lean thread without waiting for it (cancellation, cleanup).
+pub(crate) fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    pool().submit(Box::new(f));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pool() -> &'static Pool {
+        Box::leak(Box::new(Pool::new(1, || {})))
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn busy_pool_leaves_the_runtime_free() {
+        let pool = test_pool();
+        let (release, blocked) = mpsc::channel::<()>();
+        let slow = tokio::spawn(pool.run(move || {
+            blocked.recv().ok();
+            Ok(1)
+        }));
+        // The only Lean thread is stuck until released, yet other tasks on
+        // this single-threaded runtime still run to completion.
+        let other = tokio::spawn(async {
+            tokio::task::yield_now().await;
+            2
+        });
+        assert_eq!(other.await.unwrap(), 2);
+        assert!(!slow.is_finished());
+        release.send(()).unwrap();
+        assert_eq!(slow.await.unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn panicking_call_is_an_error() {
+        let pool = test_pool();
+        let err = pool.run(|| -> Result<(), LeanError> { panic!("boom") }).await.unwrap_err();
+        assert_eq!(err.message, "lean call did not complete");
+        // The thread survives the panic.
+        assert_eq!(pool.run(|| Ok(3)).await.unwrap(), 3);
+        assert!(pool.run_blocking(|| -> Result<(), LeanError> { panic!("boom") }).is_err());
+    }
+}
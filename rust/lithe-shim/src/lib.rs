@@ -1,37 +1,98 @@
 pub mod ffi;
+pub mod metrics;
 pub mod wire;
 pub mod websocket;
+mod admin;
+mod body_limit;
+mod conn;
+mod deadline;
+mod drain;
+mod error;
+#[cfg(test)]
+mod fake_lean;
+mod forwarded;
+mod handshake;
+mod hardening;
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+mod lean_bytes;
+mod lean_pool;
+mod limit;
+mod listener;
+mod notify;
+mod proxy;
+mod timeouts;
+mod tls;
+#[cfg(unix)]
+mod upgrade;
+
+pub use conn::{ClientCert, ConnInfo, ProxyInfo, TlsInfo};
+pub use drain::drain_timeout;
+pub use ffi::LeanError;
+pub use handshake::{Capabilities, HandshakeError, Protocol};
+pub use lean_pool::LeanConfig;
+pub use listener::{inherited, unix_mode_from_env, Bind, Listener, Role};
+pub use tls::{CertPaths, TlsConfig};
+#[cfg(unix)]
+pub use upgrade::{upgrade_ready, Handoff, UpgradeReady};
 
 use axum::{
     body::Body,
     extract::{ConnectInfo, FromRequestParts, State},
-    http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode},
+    http::{request::Parts, HeaderMap, HeaderName, Method, Request, Response, StatusCode},
     routing::any,
     Router,
 };
 use axum::extract::ws::WebSocketUpgrade;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response as AxumResponse};
+use body_limit::BodyLimits;
 use bytes::Bytes;
 use hyper::body::HttpBody as _;
+use hyper::server::conn::AddrIncoming;
+use lean_bytes::{LeanBuf, LeanBytes};
 use std::cell::Cell;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::{Once, OnceLock};
-use std::time::{Duration, Instant};
-use tracing::warn;
+use std::pin::Pin;
+use std::sync::{Arc, Once, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use timeouts::TimeoutConfig;
+use tokio::sync::{oneshot, watch};
+use tracing::{debug, error, warn};
 
 #[derive(Clone)]
 pub struct AppState {
     pub app_id: u64,
+    body_limits: Arc<BodyLimits>,
+    timeouts: TimeoutConfig,
+}
+
+impl AppState {
+    /// State for serving `app_id` with the limits and timeouts read from the
+    /// environment.
+    pub fn new(app_id: u64) -> Self {
+        AppState {
+            app_id,
+            body_limits: Arc::new(body_limit::config().clone()),
+            timeouts: *timeouts::config(),
+        }
+    }
 }
 
 static START: Once = Once::new();
-static RUST_TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
-const POLL_INTERVAL_MS: u64 = 5;
+static EXAMPLE_INIT: OnceLock<Result<(), LeanError>> = OnceLock::new();
+static HEADER_POLICY: OnceLock<HeaderPolicy> = OnceLock::new();
 const PUSH_CLOSED: u64 = 0;
 const PUSH_OK: u64 = 1;
 const PUSH_FULL: u64 = 2;
+// Upper bound on the bytes drained per batched poll; one message may exceed it.
+pub(crate) const POLL_BATCH_BYTES: u64 = 256 * 1024;
+// Largest Lean array a request body is copied into at once.
+const BODY_BUF_BYTES: usize = 64 * 1024;
 thread_local! {
-    static LEAN_THREAD_INIT: Cell<bool> = Cell::new(false);
+    static LEAN_THREAD_INIT: Cell<bool> = const { Cell::new(false) };
 }
 
 fn init_lean_thread() {
@@ -45,70 +106,140 @@ fn init_lean_thread() {
     });
 }
 
-pub fn init_lean() {
+// Runs on each Lean pool thread before it accepts work.
+fn init_lean_runtime() {
     START.call_once(|| unsafe {
         ffi::lean_initialize_runtime_module();
         ffi::lean_initialize();
-        ffi::lean_init_task_manager();
+        match lean_pool::config().task_workers {
+            Some(n) => ffi::lean_init_task_manager_using(n as u32),
+            None => ffi::lean_init_task_manager(),
+        }
         init_lean_thread();
-        init_example();
+        notify::install();
+        EXAMPLE_INIT.get_or_init(|| init_example());
+        handshake::run();
+        deadline::calibrate();
     });
     init_lean_thread();
 }
 
+/// Starts the Lean thread pool (configured from the environment) and waits
+/// until the Lean runtime is initialized. Fails if the app's Lean module
+/// could not be initialized; serving it would then reach uninitialized state.
+pub fn init_lean() -> Result<(), LeanError> {
+    lean_pool::run_blocking(|| Ok(()))?;
+    EXAMPLE_INIT.get().cloned().unwrap_or(Ok(()))
+}
+
+/// Like [`init_lean`], with an explicit pool configuration. Returns
+/// `Ok(false)` if the pool was already configured or started.
+pub fn init_lean_with(cfg: LeanConfig) -> Result<bool, LeanError> {
+    let applied = lean_pool::configure(cfg);
+    init_lean()?;
+    Ok(applied)
+}
+
+/// Reads the settings the shim must not serve without, failing on invalid
+/// values rather than falling back to a default that could trust the wrong
+/// peers or let the wrong requests through. Serving calls it first.
+pub fn check_env() -> Result<(), String> {
+    proxy::check()?;
+    forwarded::check()?;
+    body_limit::check()?;
+    Ok(())
+}
+
+/// Initializes Lean and returns the protocol negotiated with the Lean library,
+/// or why none could be agreed on.
+pub fn protocol() -> Result<&'static Protocol, &'static HandshakeError> {
+    // An init failure is reported by `init_lean`; the handshake still ran.
+    let _ = init_lean();
+    handshake::get()
+}
+
 #[cfg(lithe_example = "hello")]
-unsafe fn init_example() {
+unsafe fn init_example() -> Result<(), LeanError> {
     let init_res = ffi::initialize_hello_Hello(0);
-    ffi::unwrap_io_result(init_res, |_| ());
+    ffi::io_result(init_res, |_| ()).inspect_err(|err| {
+        error!(error = %err.message, "failed to initialize Hello module");
+    })
 }
 
 #[cfg(lithe_example = "crafter")]
-unsafe fn init_example() {
+unsafe fn init_example() -> Result<(), LeanError> {
     let init_res = ffi::initialize_crafter_Crafter(0);
-    ffi::unwrap_io_result(init_res, |_| ());
+    ffi::io_result(init_res, |_| ()).inspect_err(|err| {
+        error!(error = %err.message, "failed to initialize Crafter module");
+    })
 }
 
 #[cfg(not(any(lithe_example = "hello", lithe_example = "crafter")))]
-unsafe fn init_example() {}
-
-fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
-    headers
-        .iter()
-        .filter_map(|(name, value)| {
-            let value = value.to_str().ok()?;
-            Some((name.as_str().to_string(), value.to_string()))
-        })
-        .collect()
+unsafe fn init_example() -> Result<(), LeanError> {
+    Ok(())
+}
+
+/// What to do with a response header from Lean that is not valid HTTP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HeaderPolicy {
+    Drop,
+    Log,
+    Reject,
 }
 
-fn rust_timeout() -> Option<Duration> {
-    *RUST_TIMEOUT.get_or_init(|| {
-        std::env::var("LITHE_RUST_TIMEOUT_MS")
-            .ok()
-            .and_then(|v| v.parse::<u64>().ok())
-            .filter(|ms| *ms > 0)
-            .map(Duration::from_millis)
+fn header_policy() -> HeaderPolicy {
+    *HEADER_POLICY.get_or_init(|| match std::env::var("LITHE_STRICT_HEADERS").as_deref() {
+        Ok("log") => HeaderPolicy::Log,
+        Ok("reject") => HeaderPolicy::Reject,
+        _ => HeaderPolicy::Drop,
     })
 }
 
+// Appends headers in order, keeping duplicates. A `HeaderMap` keeps each
+// name's values together, so they go out where the name first appears and
+// values interleaved across names are regrouped. Invalid headers are always
+// counted; `policy` decides whether they are also logged or fail the response.
 fn apply_headers(
-    mut builder: axum::http::response::Builder,
-    headers: Vec<(String, String)>,
-) -> axum::http::response::Builder {
-    for (k, v) in headers {
-        if let (Ok(name), Ok(value)) = (
-            HeaderName::from_bytes(k.as_bytes()),
-            HeaderValue::from_str(&v),
-        ) {
-            builder = builder.header(name, value);
+    target: &mut HeaderMap,
+    headers: &wire::WireHeaders,
+    keep: fn(&HeaderName) -> bool,
+    policy: HeaderPolicy,
+) -> Result<(), String> {
+    target.reserve(headers.len());
+    for header in headers.iter() {
+        match header {
+            Ok((name, value)) => {
+                if keep(&name) {
+                    target.append(name, value);
+                }
+            }
+            Err(err) => {
+                metrics::inc_invalid_headers();
+                match policy {
+                    HeaderPolicy::Drop => {}
+                    HeaderPolicy::Log => {
+                        warn!(error = %err, "dropping invalid response header from lean")
+                    }
+                    HeaderPolicy::Reject => return Err(err),
+                }
+            }
         }
     }
-    builder
+    Ok(())
+}
+
+fn invalid_headers_response(err: &str) -> Response<Body> {
+    error!(error = %err, "rejecting response with invalid header from lean");
+    error::internal_error("invalid response header")
 }
 
-fn head_to_response(status: u16, headers: Vec<(String, String)>, body: Body) -> Response<Body> {
-    let builder = Response::builder().status(status);
-    let builder = apply_headers(builder, headers);
+fn head_to_response(status: u16, headers: wire::WireHeaders, body: Body) -> Response<Body> {
+    let mut builder = Response::builder().status(status);
+    if let Some(target) = builder.headers_mut() {
+        if let Err(err) = apply_headers(target, &headers, |_| true, header_policy()) {
+            return invalid_headers_response(&err);
+        }
+    }
     builder.body(body).unwrap_or_else(|_| {
         Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -117,28 +248,19 @@ fn head_to_response(status: u16, headers: Vec<(String, String)>, body: Body) ->
     })
 }
 
-fn handle_sync(app_id: u64, payload: &[u8]) -> Vec<u8> {
-    unsafe {
-        init_lean();
-        let req_arr = ffi::mk_byte_array(payload);
+async fn handle_sync(app_id: u64, payload: Vec<u8>) -> Result<Bytes, LeanError> {
+    lean_pool::run(move || unsafe {
+        let req_arr = ffi::mk_byte_array(&payload);
         let res = ffi::lithe_handle(app_id, req_arr);
         ffi::lithe_lean_dec(req_arr);
-        ffi::unwrap_io_result(res, |val| ffi::byte_array_to_vec(val))
-    }
-}
-
-fn header_value(headers: &[(String, String)], name: &str) -> Option<String> {
-    let target = name.trim().to_lowercase();
-    headers
-        .iter()
-        .find(|(k, _)| k.trim().to_lowercase() == target)
-        .map(|(_, v)| v.clone())
+        ffi::io_result(res, |val| LeanBytes::from_borrowed(val))
+    })
+    .await
 }
 
-fn ws_header_allowed(name: &str) -> bool {
-    let key = name.trim().to_lowercase();
+fn ws_header_allowed(name: &HeaderName) -> bool {
     !matches!(
-        key.as_str(),
+        name.as_str(),
         "x-lithe-ws-id"
             | "connection"
             | "upgrade"
@@ -154,13 +276,15 @@ fn ws_header_allowed(name: &str) -> bool {
 struct StreamGuard {
     req_id: u64,
     active: bool,
+    _session: drain::Registered,
 }
 
 impl StreamGuard {
-    fn new(req_id: u64) -> Self {
+    fn new(app_id: u64, req_id: u64) -> Self {
         Self {
             req_id,
             active: true,
+            _session: drain::register(app_id, drain::Session::Stream(req_id)),
         }
     }
 
@@ -177,59 +301,228 @@ impl Drop for StreamGuard {
     }
 }
 
-fn stream_start(app_id: u64, payload: &[u8]) -> u64 {
-    unsafe {
-        init_lean();
-        let req_arr = ffi::mk_byte_array(payload);
+// Body of a streamed response. Hyper drops it when the client disconnects or,
+// on HTTP/2, resets the stream; that wakes the task feeding it so the Lean
+// stream is cancelled even while Lean has nothing to send.
+struct StreamBody {
+    inner: Body,
+    _dropped: oneshot::Sender<()>,
+}
+
+impl futures_util::Stream for StreamBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+}
+
+// Also allocates a buffer of `first` bytes for the request body, if non-zero.
+async fn stream_start(
+    app_id: u64,
+    payload: Vec<u8>,
+    first: usize,
+) -> Result<(u64, Option<LeanBuf>), LeanError> {
+    lean_pool::run(move || unsafe {
+        let req_arr = ffi::mk_byte_array(&payload);
         let res = ffi::lithe_stream_start(app_id, req_arr);
         ffi::lithe_lean_dec(req_arr);
-        ffi::unwrap_io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
-    }
+        let req_id = ffi::io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))?;
+        Ok((req_id, (first > 0).then(|| LeanBuf::alloc(first))))
+    })
+    .await
 }
 
-fn stream_push_body(req_id: u64, chunk: &[u8], is_last: bool) -> u64 {
-    unsafe {
-        init_lean();
-        let chunk_arr = ffi::mk_byte_array(chunk);
-        let res = ffi::lithe_stream_push_body(req_id, chunk_arr, if is_last { 1 } else { 0 });
-        ffi::lithe_lean_dec(chunk_arr);
-        ffi::unwrap_io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
+// Hands `buf` to Lean, or an empty array without one. When Lean's queue is
+// full the buffer comes back to be pushed again; otherwise a fresh one of
+// `next` bytes does, unless `next` is zero.
+async fn stream_push_body(
+    req_id: u64,
+    buf: Option<LeanBuf>,
+    is_last: bool,
+    next: usize,
+) -> Result<(u64, Option<LeanBuf>), LeanError> {
+    lean_pool::run(move || unsafe {
+        let mut buf = buf.unwrap_or_else(|| LeanBuf::alloc(0));
+        let res = ffi::lithe_stream_push_body(req_id, buf.as_array(), if is_last { 1 } else { 0 });
+        let pushed = ffi::io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))?;
+        if pushed == PUSH_FULL {
+            return Ok((pushed, Some(buf)));
+        }
+        drop(buf);
+        Ok((pushed, (next > 0).then(|| LeanBuf::alloc(next))))
+    })
+    .await
+}
+
+// Room for the next request body chunk: what is left of a declared length,
+// else as much as `last`, at most `BODY_BUF_BYTES`.
+fn body_buf_len(body: &Body, last: usize) -> usize {
+    if body.is_end_stream() {
+        return 0;
+    }
+    match body.size_hint().upper() {
+        Some(left) => left.min(BODY_BUF_BYTES as u64) as usize,
+        None => last.min(BODY_BUF_BYTES),
     }
 }
 
-fn stream_poll_response(req_id: u64) -> Option<Vec<u8>> {
-    unsafe {
-        init_lean();
-        let res = ffi::lithe_stream_poll_response(req_id);
-        ffi::unwrap_io_result(res, |val| {
-            let bytes = ffi::byte_array_to_vec(val);
-            if bytes.is_empty() {
-                None
-            } else {
-                Some(bytes)
-            }
-        })
+async fn stream_poll_batch(req_id: u64) -> Result<Bytes, LeanError> {
+    let batch_poll = handshake::capabilities().batch_poll;
+    lean_pool::run(move || unsafe {
+        if batch_poll {
+            let res = ffi::lithe_stream_poll_batch(req_id, POLL_BATCH_BYTES);
+            ffi::io_result(res, |val| LeanBytes::from_borrowed(val))
+        } else {
+            let res = ffi::lithe_stream_poll_response(req_id);
+            ffi::io_result(res, |val| ffi::byte_array_to_vec(val)).and_then(|msg| {
+                wire::single_frame(&msg).map_err(|message| LeanError { message })
+            })
+        }
+    })
+    .await
+}
+
+enum PollError {
+    Lean(LeanError),
+    Decode(String),
+}
+
+// Buffers one batched poll, since a head and the chunks queued behind it can
+// arrive in the same call.
+struct StreamPoller {
+    req_id: u64,
+    pending: VecDeque<wire::StreamMsg>,
+}
+
+impl StreamPoller {
+    fn new(req_id: u64) -> Self {
+        Self {
+            req_id,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next ready message, or `None` when Lean has nothing queued.
+    async fn next(&mut self) -> Result<Option<wire::StreamMsg>, PollError> {
+        if self.pending.is_empty() {
+            let bytes = stream_poll_batch(self.req_id).await.map_err(PollError::Lean)?;
+            let msgs = wire::decode_stream_msgs(&bytes).map_err(PollError::Decode)?;
+            self.pending.extend(msgs);
+        }
+        Ok(self.pending.pop_front())
     }
 }
 
+// Fire-and-forget so it can run from `Drop` and never waits on Lean.
 fn stream_cancel(req_id: u64) {
+    lean_pool::spawn(move || stream_cancel_now(req_id));
+}
+
+// Must run on a Lean thread.
+fn stream_cancel_now(req_id: u64) {
     unsafe {
-        init_lean();
         let res = ffi::lithe_stream_cancel(req_id);
-        ffi::unwrap_io_result(res, |_| ());
+        if let Err(err) = ffi::io_result(res, |_| ()) {
+            metrics::inc_lean_errors();
+            error!(req_id, error = %err.message, "lean stream cancel failed");
+        }
+    }
+}
+
+// False once the client has gone, or reads slower than the minimum write rate.
+async fn send_chunk(
+    req_id: u64,
+    sender: &mut hyper::body::Sender,
+    chunk: Bytes,
+    timeouts: &TimeoutConfig,
+) -> bool {
+    let limit = timeouts.write_timeout(chunk.len());
+    match timeouts::within(limit, sender.send_data(chunk)).await {
+        Some(sent) => sent.is_ok(),
+        None => {
+            debug!(req_id, "client reading the response too slowly");
+            metrics::inc_slow_client_writes();
+            false
+        }
     }
 }
 
-async fn push_request_body(req_id: u64, mut body: Body) {
-    while let Some(next) = body.data().await {
+fn lean_error_response(err: &LeanError, method: &Method, path: &str) -> Response<Body> {
+    metrics::inc_lean_errors();
+    error!(%method, path, error = %err.message, "lean handler failed");
+    // The exception text can name app internals, so it only goes to the log.
+    error::internal_error("Internal Server Error")
+}
+
+/// Why the shim stopped reading a request body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BodyAbort {
+    TooLarge,
+    Idle,
+}
+
+// Past `limit` bytes, or when the client goes quiet for longer than `idle`,
+// the Lean stream is cancelled and `aborted` set, so the handler
+// can answer 413 or 408 if the head is not out yet.
+async fn push_request_body(
+    req_id: u64,
+    mut body: Body,
+    mut buf: Option<LeanBuf>,
+    limit: Option<u64>,
+    idle: Option<Duration>,
+    aborted: watch::Sender<Option<BodyAbort>>,
+) {
+    let waiter = notify::Waiter::new(notify::STREAM_BODY, req_id);
+    let mut received = 0u64;
+    loop {
+        let Some(next) = timeouts::within(idle, body.data()).await else {
+            debug!(req_id, received, "request body idle");
+            metrics::inc_body_idle_timeouts();
+            stream_cancel(req_id);
+            aborted.send_replace(Some(BodyAbort::Idle));
+            return;
+        };
+        let Some(next) = next else {
+            break;
+        };
         match next {
             Ok(chunk) => {
-                let bytes = chunk.to_vec();
-                loop {
-                    match stream_push_body(req_id, &bytes, false) {
-                        PUSH_OK => break,
-                        PUSH_FULL => {
-                            tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+                received += chunk.len() as u64;
+                if limit.is_some_and(|limit| received > limit) {
+                    debug!(req_id, received, "request body over limit");
+                    stream_cancel(req_id);
+                    aborted.send_replace(Some(BodyAbort::TooLarge));
+                    return;
+                }
+                // Chunks larger than the buffer go over in several pushes.
+                let mut rest = &chunk[..];
+                while !rest.is_empty() {
+                    let mut next = match buf.take() {
+                        Some(next) => next,
+                        None => {
+                            let len = rest.len().min(BODY_BUF_BYTES);
+                            match lean_pool::run(move || Ok(unsafe { LeanBuf::alloc(len) })).await {
+                                Ok(next) => next,
+                                Err(err) => {
+                                    metrics::inc_lean_errors();
+                                    error!(req_id, error = %err.message, "lean body allocation failed");
+                                    stream_cancel(req_id);
+                                    return;
+                                }
+                            }
+                        }
+                    };
+                    rest = &rest[next.fill(rest)..];
+                    let len = match rest.len() {
+                        0 => body_buf_len(&body, chunk.len()),
+                        left => left.min(BODY_BUF_BYTES),
+                    };
+                    match push_body_chunk(req_id, &waiter, Some(next), false, len).await {
+                        Some((PUSH_OK, next)) => buf = next,
+                        Some((PUSH_CLOSED, _)) => {
+                            debug!(req_id, "lean stopped reading the request body");
+                            return;
                         }
                         _ => return,
                     }
@@ -243,209 +536,422 @@ async fn push_request_body(req_id: u64, mut body: Body) {
         }
     }
 
+    push_body_chunk(req_id, &waiter, buf, true, 0).await;
+}
+
+// Pushes `buf` once Lean has room for it. Returns Lean's answer and the buffer
+// for the next chunk, or `None` after cancelling the stream if Lean raised.
+async fn push_body_chunk(
+    req_id: u64,
+    waiter: &notify::Waiter,
+    mut buf: Option<LeanBuf>,
+    is_last: bool,
+    next: usize,
+) -> Option<(u64, Option<LeanBuf>)> {
     loop {
-        match stream_push_body(req_id, &[], true) {
-            PUSH_OK | PUSH_CLOSED => break,
-            PUSH_FULL => {
-                tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+        match stream_push_body(req_id, buf, is_last, next).await {
+            Ok((PUSH_FULL, back)) => {
+                buf = back;
+                waiter.wait().await;
+            }
+            Ok(pushed) => return Some(pushed),
+            Err(err) => {
+                metrics::inc_lean_errors();
+                error!(req_id, error = %err.message, "lean body push failed");
+                stream_cancel(req_id);
+                return None;
             }
-            _ => break,
         }
     }
 }
 
+fn encode_wire_request(
+    parts: &Parts,
+    conn: &ConnInfo,
+    client: &forwarded::Client,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<Vec<u8>, String> {
+    // Older Lean libraries read header values as strings and would reject the
+    // whole request, so only send them the values that are visible ASCII.
+    let ascii_headers: HeaderMap;
+    let headers = if handshake::capabilities().raw_headers {
+        &parts.headers
+    } else {
+        ascii_headers = parts
+            .headers
+            .iter()
+            .filter(|(_, v)| v.to_str().is_ok())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        &ascii_headers
+    };
+    let meta = wire::RequestMeta {
+        conn_id: Some(conn.id),
+        local: conn.local,
+        local_path: conn.unix_path.as_deref(),
+        scheme: Some(client.scheme),
+        version: Some(parts.version),
+        raw_uri: Some(&parts.uri),
+        tls: conn.tls.as_deref(),
+        proxy: conn.proxy.as_deref(),
+        deadline_nanos: deadline.and_then(|at| deadline::lean_nanos(at.into_std())),
+    };
+    wire::encode_request(
+        handshake::wire_version(),
+        &parts.method,
+        parts.uri.path(),
+        parts.uri.query().unwrap_or(""),
+        headers,
+        &[],
+        client.remote.as_deref(),
+        &meta,
+    )
+}
+
 async fn handle(
     State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    conn: Option<ConnectInfo<ConnInfo>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
     req: Request<Body>,
-) -> Response<Body> {
-    init_lean();
-
+) -> AxumResponse {
+    let conn = match (conn, addr) {
+        (Some(ConnectInfo(conn)), _) => conn,
+        // Served without `ConnInfo`: each request counts as its own
+        // connection, with the peer address if there is one.
+        (None, addr) => ConnInfo::new(addr.map(|ConnectInfo(addr)| addr), None, "http"),
+    };
     let (mut parts, body) = req.into_parts();
 
+    if wire::is_ext_method(&parts.method) && !handshake::capabilities().ext_methods {
+        let message = format!("method {} is not implemented", parts.method);
+        return error::error_response(StatusCode::NOT_IMPLEMENTED, "not_implemented", &message)
+            .into_response();
+    }
+
+    let client = forwarded::resolve(forwarded::config(), &conn, &mut parts.headers);
+    let body_limit = state.body_limits.limit_for(parts.uri.path());
+    if body_limit.is_some_and(|limit| body_limit::declared_too_large(&parts.headers, limit)) {
+        return body_limit::too_large(parts.version);
+    }
+    let deadline = deadline::config()
+        .budget(&parts.headers)
+        .map(|budget| tokio::time::Instant::now() + budget);
+
     if let Ok(ws) = WebSocketUpgrade::from_request_parts(&mut parts, &state).await {
-        let headers = headers_to_vec(&parts.headers);
-        let remote = Some(addr.to_string());
-        let payload = match wire::encode_request(
-            &parts.method,
-            parts.uri.path(),
-            parts.uri.query().unwrap_or(""),
-            &headers,
-            &[],
-            remote.as_deref(),
-        ) {
+        let payload = match encode_wire_request(&parts, &conn, &client, deadline) {
             Ok(v) => v,
             Err(err) => {
                 warn!(error = %err, "failed to encode wire request");
                 return Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(Body::from("encode error"))
-                    .unwrap();
+                    .unwrap()
+                    .into_response();
             }
         };
 
-        let resp_bytes = handle_sync(state.app_id, &payload);
+        let resp_bytes = match handle_sync(state.app_id, payload).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                return lean_error_response(&err, &parts.method, parts.uri.path()).into_response();
+            }
+        };
         match wire::decode_response(&resp_bytes) {
             Ok(wire_resp) => {
-                if let Some(id_str) = header_value(&wire_resp.headers, "x-lithe-ws-id") {
-                    if let Ok(ws_id) = id_str.parse::<u64>() {
-                        let mut resp = ws
-                            .on_upgrade(move |socket| websocket::handle_socket(socket, ws_id))
-                            .into_response();
-                        for (k, v) in wire_resp.headers {
-                            if !ws_header_allowed(&k) {
-                                continue;
-                            }
-                            if let (Ok(name), Ok(value)) = (
-                                HeaderName::from_bytes(k.as_bytes()),
-                                HeaderValue::from_str(&v),
-                            ) {
-                                resp.headers_mut().insert(name, value);
-                            }
-                        }
-                        return resp;
+                let ws_id = wire_resp
+                    .headers
+                    .get("x-lithe-ws-id")
+                    .and_then(|v| v.to_str().ok()?.trim().parse::<u64>().ok());
+                if let Some(ws_id) = ws_id {
+                    conn.upgraded();
+                    let mut resp = ws
+                        .on_upgrade(move |socket| websocket::handle_socket(socket, state.app_id, ws_id))
+                        .into_response();
+                    let applied = apply_headers(
+                        resp.headers_mut(),
+                        &wire_resp.headers,
+                        ws_header_allowed,
+                        header_policy(),
+                    );
+                    if let Err(err) = applied {
+                        websocket::ws_close(ws_id);
+                        return invalid_headers_response(&err).into_response();
                     }
+                    return resp;
                 }
-                return head_to_response(wire_resp.status, wire_resp.headers, Body::from(wire_resp.body));
+                return head_to_response(wire_resp.status, wire_resp.headers, Body::from(wire_resp.body))
+                    .into_response();
             }
             Err(err) => {
                 warn!(error = %err, "failed to decode wire response");
                 return Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(Body::from("decode error"))
-                    .unwrap();
+                    .unwrap()
+                    .into_response();
             }
         }
     }
-    let headers = headers_to_vec(&parts.headers);
-    let remote = Some(addr.to_string());
-    let payload = match wire::encode_request(
-        &parts.method,
-        parts.uri.path(),
-        parts.uri.query().unwrap_or(""),
-        &headers,
-        &[],
-        remote.as_deref(),
-    ) {
+    let payload = match encode_wire_request(&parts, &conn, &client, deadline) {
         Ok(v) => v,
         Err(err) => {
             warn!(error = %err, "failed to encode wire request");
             return Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Body::from("encode error"))
-                .unwrap();
+                .unwrap()
+                .into_response();
         }
     };
 
-    let req_id = stream_start(state.app_id, &payload);
-    let mut guard = StreamGuard::new(req_id);
-    tokio::spawn(push_request_body(req_id, body));
-    let started = Instant::now();
-    let timeout = rust_timeout();
+    let first_buf = body_buf_len(&body, BODY_BUF_BYTES);
+    let (req_id, buf) = match stream_start(state.app_id, payload, first_buf).await {
+        Ok(started) => started,
+        Err(err) => {
+            return lean_error_response(&err, &parts.method, parts.uri.path()).into_response();
+        }
+    };
+    let mut guard = StreamGuard::new(state.app_id, req_id);
+    let waiter = notify::Waiter::new(notify::STREAM_RESPONSE, req_id);
+    let (aborted_tx, mut aborted) = watch::channel(None);
+    tokio::spawn(push_request_body(
+        req_id,
+        body,
+        buf,
+        body_limit,
+        state.timeouts.body_idle,
+        aborted_tx,
+    ));
+    let mut poller = StreamPoller::new(req_id);
+    let mut phase = drain::phase(state.app_id);
     let (status, headers, is_stream, head_body) = loop {
-        if let Some(bytes) = stream_poll_response(req_id) {
-            match wire::decode_stream_msg(&bytes) {
-                Ok(wire::StreamMsg::Head {
-                    status,
-                    headers,
-                    is_stream,
-                    body,
-                }) => break (status, headers, is_stream, body),
-                Ok(_) => {}
-                Err(err) => {
-                    warn!(error = %err, "failed to decode stream response");
-                    stream_cancel(req_id);
-                    guard.complete();
-                    return Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::from("decode error"))
-                        .unwrap();
-                }
+        match poller.next().await {
+            Ok(Some(wire::StreamMsg::Head {
+                status,
+                headers,
+                is_stream,
+                body,
+            })) => break (status, headers, is_stream, body),
+            Ok(Some(_)) => continue,
+            Ok(None) => {}
+            Err(PollError::Lean(err)) => {
+                stream_cancel(req_id);
+                guard.complete();
+                return lean_error_response(&err, &parts.method, parts.uri.path()).into_response();
             }
-        }
-        if let Some(limit) = timeout {
-            if started.elapsed() >= limit {
+            Err(PollError::Decode(err)) => {
+                warn!(error = %err, "failed to decode stream response");
                 stream_cancel(req_id);
                 guard.complete();
                 return Response::builder()
-                    .status(StatusCode::GATEWAY_TIMEOUT)
-                    .body(Body::from("request timed out"))
-                    .unwrap();
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("decode error"))
+                    .unwrap()
+                    .into_response();
+            }
+        }
+        let woke = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, waiter.wait()).await.is_ok(),
+                None => {
+                    waiter.wait().await;
+                    true
+                }
+            }
+        };
+        let woke = tokio::select! {
+            woke = woke => woke,
+            Ok(abort) = aborted.wait_for(Option::is_some) => {
+                guard.complete();
+                return match *abort {
+                    Some(BodyAbort::Idle) => timeouts::body_timed_out(parts.version),
+                    _ => body_limit::too_large(parts.version),
+                };
             }
+            _ = phase.wait_for(|phase| *phase == drain::Phase::Forced) => {
+                stream_cancel(req_id);
+                guard.complete();
+                return error::error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "shutting_down",
+                    "server is shutting down",
+                )
+                .into_response();
+            }
+        };
+        if !woke {
+            stream_cancel(req_id);
+            guard.complete();
+            return Response::builder()
+                .status(StatusCode::GATEWAY_TIMEOUT)
+                .body(Body::from("request timed out"))
+                .unwrap()
+                .into_response();
         }
-        tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
     };
 
     if !is_stream {
         guard.complete();
-        return head_to_response(status, headers, Body::from(head_body));
+        return head_to_response(status, headers, Body::from(head_body)).into_response();
     }
 
     let (mut sender, stream_body) = Body::channel();
+    let (dropped_tx, mut dropped) = oneshot::channel();
+    let stream_body = Body::wrap_stream(StreamBody {
+        inner: stream_body,
+        _dropped: dropped_tx,
+    });
     let mut stream_guard = guard;
+    let method = parts.method.clone();
+    let path = parts.uri.path().to_string();
+    // On shutdown SSE streams end at once, since clients reconnect; other
+    // streams run until the drain deadline and are then cut off.
+    let is_sse = headers
+        .get("content-type")
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    let stop_at = if is_sse { drain::Phase::Draining } else { drain::Phase::Forced };
+    let limits = state.timeouts;
+    let expires_at = limits.stream_lifetime.map(|limit| tokio::time::Instant::now() + limit);
     tokio::spawn(async move {
-        if !head_body.is_empty() {
-            if sender.send_data(Bytes::from(head_body)).await.is_err() {
+        if !head_body.is_empty() && !send_chunk(req_id, &mut sender, head_body, &limits).await {
+            stream_cancel(req_id);
+            stream_guard.complete();
+            sender.abort();
+            return;
+        }
+        let mut idle_at = limits.stream_idle.map(|idle| tokio::time::Instant::now() + idle);
+        loop {
+            // Checked here too, since a stream that never pauses never
+            // reaches the select below.
+            let expired = expires_at.is_some_and(|at| at <= tokio::time::Instant::now());
+            if expired {
+                debug!(req_id, %method, path, "response stream ran past its maximum duration");
+                metrics::inc_stream_lifetime_timeouts();
+            }
+            if expired || *phase.borrow() >= stop_at {
                 stream_cancel(req_id);
                 stream_guard.complete();
+                if !is_sse {
+                    sender.abort();
+                }
                 return;
             }
-        }
-        loop {
-            if let Some(bytes) = stream_poll_response(req_id) {
-                match wire::decode_stream_msg(&bytes) {
-                    Ok(wire::StreamMsg::Chunk(chunk)) => {
-                        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+            let msg = match poller.next().await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    tokio::select! {
+                        _ = waiter.wait() => continue,
+                        _ = phase.wait_for(|phase| *phase >= stop_at) => continue,
+                        _ = timeouts::until(expires_at) => continue,
+                        _ = timeouts::until(idle_at) => {
+                            debug!(req_id, %method, path, "response stream idle");
+                            metrics::inc_stream_idle_timeouts();
+                            stream_cancel(req_id);
+                            stream_guard.complete();
+                            if !is_sse {
+                                sender.abort();
+                            }
+                            return;
+                        }
+                        _ = &mut dropped => {
                             stream_cancel(req_id);
                             stream_guard.complete();
                             return;
                         }
                     }
-                    Ok(wire::StreamMsg::End) => {
-                        stream_guard.complete();
-                        return;
-                    }
-                    Ok(wire::StreamMsg::Head { .. }) => {}
-                    Err(err) => {
-                        warn!(error = %err, "failed to decode stream chunk");
+                }
+                Err(PollError::Lean(err)) => {
+                    // The head is already sent; abort so the client sees a truncated body.
+                    metrics::inc_lean_errors();
+                    error!(req_id, %method, path, error = %err.message, "lean stream failed");
+                    stream_cancel(req_id);
+                    stream_guard.complete();
+                    sender.abort();
+                    return;
+                }
+                Err(PollError::Decode(err)) => {
+                    warn!(error = %err, "failed to decode stream chunk");
+                    stream_cancel(req_id);
+                    stream_guard.complete();
+                    return;
+                }
+            };
+            match msg {
+                wire::StreamMsg::Chunk(chunk) => {
+                    if !send_chunk(req_id, &mut sender, chunk, &limits).await {
                         stream_cancel(req_id);
                         stream_guard.complete();
+                        sender.abort();
                         return;
                     }
+                    idle_at = limits.stream_idle.map(|idle| tokio::time::Instant::now() + idle);
+                }
+                wire::StreamMsg::End => {
+                    stream_guard.complete();
+                    return;
                 }
-            } else {
-                tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+                wire::StreamMsg::Head { .. } => {}
             }
         }
     });
 
-    head_to_response(status, headers, stream_body)
+    head_to_response(status, headers, stream_body).into_response()
 }
 
-pub fn new_app_id(name: &str) -> u64 {
-    unsafe {
-        init_lean();
+/// Creates an instance of the registered Lean app `name`.
+pub fn try_new_app_id(name: &str) -> Result<u64, LeanError> {
+    let name = name.to_string();
+    lean_pool::run_blocking(move || unsafe {
         let name_arr = ffi::mk_byte_array(name.as_bytes());
         let res = ffi::lithe_new_app_named(name_arr);
         ffi::lithe_lean_dec(name_arr);
-        ffi::unwrap_io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
-    }
+        ffi::io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
+    })
+}
+
+/// Like [`try_new_app_id`], panicking if Lean raises.
+pub fn new_app_id(name: &str) -> u64 {
+    try_new_app_id(name).unwrap_or_else(|err| panic!("failed to create app {name:?}: {err}"))
 }
 
 pub fn shutdown_lean(app_id: u64) {
-    unsafe {
+    drain::forget(app_id);
+    let freed = lean_pool::run_blocking(move || unsafe {
         let res = ffi::lithe_free_app(app_id);
-        ffi::unwrap_io_result(res, |_| ());
+        if let Err(err) = ffi::io_result(res, |_| ()) {
+            warn!(app_id, error = %err.message, "failed to free lean app");
+        }
         ffi::lean_finalize_task_manager();
+        Ok(())
+    });
+    if let Err(err) = freed {
+        warn!(app_id, error = %err.message, "failed to shut down lean");
     }
 }
 
+/// Serve the router with `into_make_service_with_connect_info::<ConnInfo>()`
+/// so Lean sees each connection's details. With `SocketAddr` connect info
+/// it only learns the peer address, and with `into_make_service()` nothing.
+/// `Upgrade: h2c` is only answered on the shim's own listeners.
 pub fn make_router(app_id: u64) -> Router {
-    let app_state = AppState { app_id };
-    Router::new()
+    router(AppState::new(app_id))
+}
+
+fn router(app_state: AppState) -> Router {
+    let router = Router::new()
         .route("/", any(handle))
         .fallback(handle)
-        .with_state(app_state)
+        .with_state(app_state);
+    let router = match limit::global() {
+        Some(limiter) => {
+            router.layer(axum::middleware::from_fn_with_state(limiter, limit::middleware))
+        }
+        None => router,
+    };
+    let router = router.layer(axum::middleware::from_fn(hardening::middleware));
+    #[cfg(feature = "http3")]
+    let router = router.layer(axum::middleware::from_fn(http3::alt_svc));
+    router
 }
 
 pub async fn serve_with_shutdown<F>(
@@ -456,12 +962,8 @@ pub async fn serve_with_shutdown<F>(
 where
     F: std::future::Future<Output = ()> + Send + 'static,
 {
-    let app = make_router(app_id);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shutdown)
-        .await?;
-    Ok(())
+    let listener = Listener::bind(&Bind::Tcp(addr), None)?;
+    serve_listeners_with_shutdown(vec![(Role::Http, listener)], app_id, None, shutdown).await
 }
 
 pub async fn serve_with_listener<F>(
@@ -472,11 +974,442 @@ pub async fn serve_with_listener<F>(
 where
     F: std::future::Future<Output = ()> + Send + 'static,
 {
-    listener.set_nonblocking(true)?;
+    let listeners = vec![(Role::Http, Listener::Tcp(listener))];
+    serve_listeners_with_shutdown(listeners, app_id, None, shutdown).await
+}
+
+/// Like [`serve_with_shutdown`], with TLS terminated in the shim. Certificates
+/// are reloaded when their files change or on SIGHUP; open connections keep
+/// the certificate they were accepted with.
+pub async fn serve_tls_with_shutdown<F>(
+    addr: SocketAddr,
+    app_id: u64,
+    tls: TlsConfig,
+    shutdown: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let listener = Listener::bind(&Bind::Tcp(addr), None)?;
+    serve_listeners_with_shutdown(vec![(Role::Https, listener)], app_id, Some(tls), shutdown).await
+}
+
+// Applies the HTTP/2 settings and protocol limits, and times out slow or idle
+// HTTP/1 connections.
+fn server<I>(incoming: I) -> hyper::server::Builder<hardening::Incoming<I>> {
+    hardening::configure(http2::configure(axum::Server::builder(hardening::incoming(incoming))))
+}
+
+type ServeFuture = Pin<Box<dyn std::future::Future<Output = hyper::Result<()>> + Send>>;
+
+/// Serves one app on every listener at once, each in its [`Role`]. `tls`
+/// is required when any listener is [`Role::Https`]. `shutdown`, or any
+/// listener failing, drains all of them together.
+pub async fn serve_listeners_with_shutdown<F>(
+    listeners: Vec<(Role, Listener)>,
+    app_id: u64,
+    tls: Option<TlsConfig>,
+    shutdown: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    check_env()?;
+    init_lean()?;
+    protocol().map_err(Clone::clone)?;
+    let tls = tls.map(tls::TlsState::new).transpose()?.map(Arc::new);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let stop_tx = Arc::new(stop_tx);
     let app = make_router(app_id);
-    axum::Server::from_tcp(listener)?
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shutdown)
-        .await?;
+
+    let mut servers: Vec<ServeFuture> = Vec::with_capacity(listeners.len());
+    for (role, listener) in listeners {
+        let mut stop_rx = stop_rx.clone();
+        let stopped = async move {
+            let _ = stop_rx.wait_for(|stop| *stop).await;
+        };
+        let (router, tls) = match role {
+            Role::Http => (app.clone(), None),
+            Role::Https => match &tls {
+                Some(state) => (app.clone(), Some(state)),
+                None => return Err("an https listener needs a TLS configuration".into()),
+            },
+            Role::Admin => (admin::router(), None),
+        };
+        let router = match limit::listener().filter(|_| role != Role::Admin) {
+            Some(limiter) => {
+                router.layer(axum::middleware::from_fn_with_state(limiter, limit::middleware))
+            }
+            None => router,
+        };
+        let make_service = http2::MakeService(router);
+        // Admin listeners are reached directly, never through the balancer.
+        let proxy = proxy::config().filter(|_| role != Role::Admin);
+        let server: ServeFuture = match (listener, tls, proxy) {
+            (Listener::Tcp(l), Some(state), proxy) => {
+                l.set_nonblocking(true)?;
+                let incoming = state.incoming(tokio::net::TcpListener::from_std(l)?, proxy);
+                Box::pin(
+                    server(incoming)
+                        .serve(make_service)
+                        .with_graceful_shutdown(stopped),
+                )
+            }
+            (Listener::Tcp(l), None, Some(proxy)) => {
+                l.set_nonblocking(true)?;
+                let incoming = proxy::incoming(proxy, tokio::net::TcpListener::from_std(l)?);
+                Box::pin(
+                    server(incoming)
+                        .serve(make_service)
+                        .with_graceful_shutdown(stopped),
+                )
+            }
+            (Listener::Tcp(l), None, None) => {
+                l.set_nonblocking(true)?;
+                let incoming = AddrIncoming::from_listener(tokio::net::TcpListener::from_std(l)?)?;
+                Box::pin(
+                    server(incoming)
+                        .serve(make_service)
+                        .with_graceful_shutdown(stopped),
+                )
+            }
+            #[cfg(unix)]
+            (Listener::Unix(_), Some(_), _) => {
+                return Err("https listeners must be TCP sockets".into());
+            }
+            #[cfg(unix)]
+            (Listener::Unix(l), None, _) => {
+                l.set_nonblocking(true)?;
+                let incoming = listener::unix_incoming(tokio::net::UnixListener::from_std(l)?);
+                Box::pin(
+                    server(incoming)
+                        .serve(make_service)
+                        .with_graceful_shutdown(stopped),
+                )
+            }
+        };
+        let stop_tx = stop_tx.clone();
+        servers.push(Box::pin(async move {
+            let res = server.await;
+            if res.is_err() {
+                let _ = stop_tx.send(true);
+            }
+            res
+        }));
+    }
+
+    let watcher = tls.clone().map(|state| tokio::spawn(state.watch()));
+    let signal = {
+        let stop_tx = stop_tx.clone();
+        tokio::spawn(async move {
+            shutdown.await;
+            let _ = stop_tx.send(true);
+        })
+    };
+    // Servers only finish once stopped. Then open streams and WebSockets get
+    // until the drain deadline, and the app has no sessions when this returns.
+    let mut servers = futures_util::future::join_all(servers);
+    let mut stopped = stop_rx.clone();
+    let results = tokio::select! {
+        results = &mut servers => Some(results),
+        _ = stopped.wait_for(|stop| *stop) => None,
+    };
+    drain::begin(app_id);
+    let deadline = tokio::time::Instant::now() + drain::drain_timeout();
+    let results = match results {
+        Some(results) => results,
+        None => match tokio::time::timeout_at(deadline, servers).await {
+            Ok(results) => results,
+            Err(_) => {
+                warn!("drain deadline passed with connections open; closing them");
+                Vec::new()
+            }
+        },
+    };
+    drain::finish(app_id, deadline).await;
+    signal.abort();
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    results.into_iter().collect::<hyper::Result<Vec<()>>>()?;
     Ok(())
 }
+
+/// Serves the app over HTTP/3 on UDP `addr`, alongside a TCP listener started
+/// separately. Uses the first certificate in `tls`, without reloading. TCP
+/// responses advertise the endpoint with `Alt-Svc` once it is bound.
+#[cfg(feature = "http3")]
+pub async fn serve_http3_with_shutdown<F>(
+    addr: SocketAddr,
+    app_id: u64,
+    tls: TlsConfig,
+    shutdown: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    check_env()?;
+    init_lean()?;
+    protocol().map_err(Clone::clone)?;
+    let endpoint = http3::bind(addr, &tls)?;
+    http3::serve(endpoint, make_router(app_id), shutdown).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_lean::{eventually, respond, started};
+    use hyper::Client;
+
+    // Short enough for the tests to wait out.
+    const TIMEOUTS: TimeoutConfig = TimeoutConfig {
+        body_idle: Some(Duration::from_millis(500)),
+        stream_idle: Some(Duration::from_secs(1)),
+        stream_lifetime: Some(Duration::from_secs(3)),
+        min_write_rate: None,
+        write_grace: Duration::from_secs(5),
+    };
+
+    // Serves a new app over the fake Lean library on a local port, with a
+    // 16-byte body limit under `/small` and the timeouts above.
+    async fn serve_app() -> (u64, SocketAddr) {
+        init_lean().unwrap();
+        let app_id = new_app_id("test");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        let state = AppState {
+            body_limits: Arc::new(BodyLimits::parse(None, "/small=16").unwrap()),
+            timeouts: TIMEOUTS,
+            ..AppState::new(app_id)
+        };
+        let service = http2::MakeService(router(state));
+        tokio::spawn(server(incoming).serve(service));
+        (app_id, addr)
+    }
+
+    async fn get_status(addr: SocketAddr, app: u64) -> StatusCode {
+        let uri = format!("http://{addr}/").parse().unwrap();
+        let client = tokio::spawn(Client::new().get(uri));
+        respond(started(app).await, 200, &[], false, b"");
+        client.await.unwrap().unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn router_serves_without_conn_info() {
+        init_lean().unwrap();
+        let app = new_app_id("test");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let by_addr = make_router(app).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(by_addr));
+        assert_eq!(get_status(addr, app).await, StatusCode::OK);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let plain = make_router(app).into_make_service();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(plain));
+        assert_eq!(get_status(addr, app).await, StatusCode::OK);
+    }
+
+    fn post(addr: SocketAddr, path: &str, body: Body) -> Request<Body> {
+        Request::post(format!("http://{addr}{path}")).body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_bodies_reach_lean_whole_and_in_order() {
+        let (app, addr) = serve_app().await;
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let (mut sender, body) = Body::channel();
+        let client = tokio::spawn(Client::new().request(post(addr, "/upload", body)));
+        let id = started(app).await;
+        // Unknown length, in chunks larger than one Lean buffer.
+        for chunk in data.chunks(70_000) {
+            sender.send_data(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        drop(sender);
+        eventually("the whole body", || fake_lean::stream(id, |s| s.body_done)).await;
+        assert!(fake_lean::stream(id, |s| s.body == data));
+
+        respond(id, 201, &[], false, b"");
+        let resp = client.await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn http2_stream_resets_cancel_the_lean_stream() {
+        let (app, addr) = serve_app().await;
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let uri = format!("http://{addr}/events").parse().unwrap();
+        let pending = tokio::spawn(client.get(uri));
+        let id = started(app).await;
+        respond(id, 200, &[], true, b"");
+        fake_lean::send(id, b"first");
+        let mut resp = pending.await.unwrap().unwrap();
+        assert_eq!(resp.body_mut().data().await.unwrap().unwrap(), "first");
+        // Dropping the body resets the stream; the connection stays open.
+        drop(resp);
+        let cancelled = eventually("a cancelled stream", || fake_lean::stream(id, |s| s.cancelled));
+        // Sooner than the stream idle timeout would have.
+        let soon = TIMEOUTS.stream_idle.unwrap() / 2;
+        tokio::time::timeout(soon, cancelled).await.unwrap();
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn stalled_request_bodies_get_408() {
+        let (app, addr) = serve_app().await;
+        let (mut sender, body) = Body::channel();
+        let client = tokio::spawn(Client::new().request(post(addr, "/upload", body)));
+        let id = started(app).await;
+        sender.send_data(Bytes::from_static(b"first")).await.unwrap();
+        let resp = client.await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        eventually("a cancelled stream", || fake_lean::stream(id, |s| s.cancelled)).await;
+        assert!(fake_lean::stream(id, |s| !s.body_done));
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_path_limit_get_413_mid_stream() {
+        let (app, addr) = serve_app().await;
+        let (mut sender, body) = Body::channel();
+        // No Content-Length, so the limit is only crossed while reading.
+        let client = tokio::spawn(Client::new().request(post(addr, "/small/x", body)));
+        let id = started(app).await;
+        sender.send_data(Bytes::from_static(b"0123456789")).await.unwrap();
+        sender.send_data(Bytes::from_static(b"0123456789")).await.unwrap();
+        let resp = client.await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        eventually("a cancelled stream", || fake_lean::stream(id, |s| s.cancelled)).await;
+        assert!(fake_lean::stream(id, |s| s.body.len() <= 16));
+    }
+
+    #[tokio::test]
+    async fn forced_drain_answers_waiting_requests_with_503() {
+        let (app, addr) = serve_app().await;
+        let uri = format!("http://{addr}/").parse().unwrap();
+        let client = tokio::spawn(Client::new().get(uri));
+        let id = started(app).await;
+        drain::begin(app);
+        tokio::spawn(drain::finish(app, tokio::time::Instant::now()));
+        let resp = client.await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(fake_lean::stream(id, |s| s.cancelled));
+    }
+
+    // Reads `resp`'s body until it ends or fails; whether it ended cleanly.
+    async fn read_to_end(resp: &mut Response<Body>) -> bool {
+        loop {
+            match resp.body_mut().data().await {
+                Some(Ok(_)) => continue,
+                Some(Err(_)) => return false,
+                None => return true,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_response_streams_are_cut_off_and_cancelled() {
+        let (app, addr) = serve_app().await;
+        let uri = format!("http://{addr}/events").parse().unwrap();
+        let pending = tokio::spawn(Client::new().get(uri));
+        let id = started(app).await;
+        respond(id, 200, &[], true, b"");
+        let sent = tokio::time::Instant::now();
+        fake_lean::send(id, b"first");
+        let mut resp = pending.await.unwrap().unwrap();
+        assert!(!read_to_end(&mut resp).await);
+        assert!(sent.elapsed() >= TIMEOUTS.stream_idle.unwrap());
+        eventually("a cancelled stream", || fake_lean::stream(id, |s| s.cancelled)).await;
+    }
+
+    #[tokio::test]
+    async fn response_streams_are_cut_off_at_their_maximum_duration() {
+        let (app, addr) = serve_app().await;
+        let uri = format!("http://{addr}/events").parse().unwrap();
+        let pending = tokio::spawn(Client::new().get(uri));
+        let id = started(app).await;
+        respond(id, 200, &[], true, b"");
+        let started_at = tokio::time::Instant::now();
+        // Never idle for long enough to hit the idle timeout.
+        let feeder = tokio::spawn(async move {
+            while !fake_lean::stream(id, |s| s.cancelled) {
+                fake_lean::send(id, b"tick");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        });
+        let mut resp = pending.await.unwrap().unwrap();
+        assert!(!read_to_end(&mut resp).await);
+        assert!(started_at.elapsed() >= TIMEOUTS.stream_lifetime.unwrap());
+        eventually("a cancelled stream", || fake_lean::stream(id, |s| s.cancelled)).await;
+        feeder.await.unwrap();
+    }
+
+    // Headers as they arrive from Lean, in a v1 response.
+    fn wire_headers(headers: &[(&str, &str)]) -> wire::WireHeaders {
+        let mut buf = vec![wire::WIRE_VERSION_V1, 0, 200];
+        buf.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+        for part in headers.iter().flat_map(|(name, value)| [name, value]) {
+            buf.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            buf.extend_from_slice(part.as_bytes());
+        }
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        wire::decode_response(&Bytes::from(buf)).unwrap().headers
+    }
+
+    #[test]
+    fn repeated_headers_are_kept_and_invalid_ones_follow_the_policy() {
+        let headers = wire_headers(&[
+            ("set-cookie", "a=1"),
+            ("x-id", "7"),
+            ("bad name", "x"),
+            ("set-cookie", "b=2"),
+        ]);
+        for policy in [HeaderPolicy::Drop, HeaderPolicy::Log] {
+            let before = metrics::snapshot().invalid_headers;
+            let mut target = HeaderMap::new();
+            apply_headers(&mut target, &headers, |_| true, policy).unwrap();
+            let cookies: Vec<_> = target.get_all("set-cookie").iter().collect();
+            assert_eq!(cookies, ["a=1", "b=2"]);
+            assert_eq!(target["x-id"], "7");
+            assert_eq!(target.len(), 3);
+            assert!(metrics::snapshot().invalid_headers > before);
+        }
+
+        let mut target = HeaderMap::new();
+        let err = apply_headers(&mut target, &headers, |_| true, HeaderPolicy::Reject).unwrap_err();
+        assert_eq!(err, "invalid header name");
+        let resp = invalid_headers_response(&err);
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn websocket_responses_keep_only_app_headers() {
+        let headers = wire_headers(&[
+            ("x-lithe-ws-id", "3"),
+            ("sec-websocket-accept", "forged"),
+            ("set-cookie", "a=1"),
+            ("set-cookie", "b=2"),
+        ]);
+        let mut target = HeaderMap::new();
+        apply_headers(&mut target, &headers, ws_header_allowed, HeaderPolicy::Reject).unwrap();
+        assert_eq!(target.len(), 2);
+        assert_eq!(target.get_all("set-cookie").iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn lean_errors_become_generic_json_500s() {
+        let before = metrics::snapshot().lean_errors;
+        let err = LeanError {
+            message: "app 7: invariant broken in Secret.step".to_string(),
+        };
+        let resp = lean_error_response(&err, &Method::GET, "/state");
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["code"], "internal_error");
+        assert_eq!(value["message"], "Internal Server Error");
+        assert!(metrics::snapshot().lean_errors > before);
+    }
+}
@@ -0,0 +1,347 @@
+use axum::body::{boxed, Body, BoxBody, HttpBody};
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use bytes::Bytes;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::{error, metrics};
+
+static CONFIG: OnceLock<LimitConfig> = OnceLock::new();
+static GLOBAL: OnceLock<Option<Arc<Limiter>>> = OnceLock::new();
+
+// Gradient tuning: how far the latency may rise above its long-run average
+// before the limit shrinks, and how quickly each average follows samples.
+const TOLERANCE: f64 = 1.5;
+const LONG_WEIGHT: f64 = 0.01;
+const SHORT_WEIGHT: f64 = 0.3;
+const SMOOTHING: f64 = 0.2;
+
+/// Concurrency limits, read once from the environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LimitConfig {
+    /// Requests in flight across every listener (`LITHE_MAX_INFLIGHT`).
+    global: Option<usize>,
+    /// Requests in flight on each listener (`LITHE_LISTENER_MAX_INFLIGHT`).
+    listener: Option<usize>,
+    /// Requests that may wait for a slot before new ones are shed.
+    queue: usize,
+    queue_timeout: Duration,
+    /// Let the global limit follow Lean's latency, up to `global`.
+    adaptive: bool,
+}
+
+impl LimitConfig {
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let limit = |name: &str| var(name).filter(|n| *n > 0).map(|n| n as usize);
+        LimitConfig {
+            global: limit("LITHE_MAX_INFLIGHT"),
+            listener: limit("LITHE_LISTENER_MAX_INFLIGHT"),
+            queue: var("LITHE_QUEUE_SIZE").map_or(64, |n| n as usize),
+            queue_timeout: Duration::from_millis(var("LITHE_QUEUE_TIMEOUT_MS").unwrap_or(1000)),
+            adaptive: std::env::var("LITHE_ADAPTIVE_LIMIT").is_ok_and(|v| v == "1"),
+        }
+    }
+}
+
+fn config() -> &'static LimitConfig {
+    CONFIG.get_or_init(LimitConfig::from_env)
+}
+
+/// The limiter shared by every listener and HTTP/3, if one is configured.
+pub(crate) fn global() -> Option<Arc<Limiter>> {
+    GLOBAL
+        .get_or_init(|| {
+            let cfg = config();
+            let max = cfg.global?;
+            let limiter = Limiter::new(max, cfg.queue, cfg.queue_timeout);
+            Some(Arc::new(if cfg.adaptive { limiter.adaptive() } else { limiter }))
+        })
+        .clone()
+}
+
+/// A fresh limiter for one listener, if one is configured.
+pub(crate) fn listener() -> Option<Arc<Limiter>> {
+    let cfg = config();
+    let max = cfg.listener?;
+    Some(Arc::new(Limiter::new(max, cfg.queue, cfg.queue_timeout)))
+}
+
+struct Slots {
+    inflight: usize,
+    waiting: usize,
+    limit: usize,
+    gradient: Option<Gradient>,
+}
+
+/// Caps requests in flight, holding a few more in a bounded queue.
+pub(crate) struct Limiter {
+    state: Mutex<Slots>,
+    released: Notify,
+    queue: usize,
+    queue_timeout: Duration,
+}
+
+impl Limiter {
+    fn new(limit: usize, queue: usize, queue_timeout: Duration) -> Self {
+        Limiter {
+            state: Mutex::new(Slots {
+                inflight: 0,
+                waiting: 0,
+                limit,
+                gradient: None,
+            }),
+            released: Notify::new(),
+            queue,
+            queue_timeout,
+        }
+    }
+
+    fn adaptive(self) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.gradient = Some(Gradient::new(state.limit));
+        }
+        self
+    }
+
+    // Newcomers only take a free slot when nobody is queued ahead of them.
+    fn try_acquire(self: &Arc<Self>, queued: bool) -> Option<Permit> {
+        let mut state = self.state.lock().ok()?;
+        if state.inflight >= state.limit || (!queued && state.waiting > 0) {
+            return None;
+        }
+        state.inflight += 1;
+        Some(Permit {
+            limiter: self.clone(),
+        })
+    }
+
+    /// Waits up to the queue timeout for a slot. `None` means shed the request.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> Option<Permit> {
+        if let Some(permit) = self.try_acquire(false) {
+            return Some(permit);
+        }
+        {
+            let mut state = self.state.lock().ok()?;
+            if state.waiting >= self.queue {
+                return None;
+            }
+            state.waiting += 1;
+        }
+        let _queued = Queued(self);
+        let wait = async {
+            loop {
+                let released = self.released.notified();
+                tokio::pin!(released);
+                released.as_mut().enable();
+                if let Some(permit) = self.try_acquire(true) {
+                    return permit;
+                }
+                released.await;
+            }
+        };
+        tokio::time::timeout(self.queue_timeout, wait).await.ok()
+    }
+
+    fn retry_after(&self) -> HeaderValue {
+        let secs = self.queue_timeout.as_secs_f64().ceil().max(1.0) as u64;
+        HeaderValue::from(secs)
+    }
+}
+
+struct Queued<'a>(&'a Limiter);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.state.lock() {
+            state.waiting -= 1;
+        }
+    }
+}
+
+/// One request's slot, released when the response body is done.
+pub(crate) struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Permit {
+    // Lean's time to the response head, for the adaptive limit.
+    fn sample(&self, latency: Duration) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            let inflight = state.inflight;
+            if let Some(gradient) = state.gradient.as_mut() {
+                state.limit = gradient.update(latency, inflight);
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.inflight -= 1;
+        }
+        self.limiter.released.notify_waiters();
+    }
+}
+
+// Gradient-based limit: while latency stays near its long-run average the
+// limit grows by about its square root; as latency climbs it shrinks in
+// proportion to the rise.
+struct Gradient {
+    limit: f64,
+    max: f64,
+    long_rtt: f64,
+    short_rtt: f64,
+}
+
+impl Gradient {
+    fn new(max: usize) -> Self {
+        Gradient {
+            limit: max as f64,
+            max: max as f64,
+            long_rtt: 0.0,
+            short_rtt: 0.0,
+        }
+    }
+
+    fn update(&mut self, latency: Duration, inflight: usize) -> usize {
+        let rtt = latency.as_secs_f64().max(1e-6);
+        if self.long_rtt == 0.0 {
+            self.long_rtt = rtt;
+            self.short_rtt = rtt;
+        }
+        self.long_rtt += (rtt - self.long_rtt) * LONG_WEIGHT;
+        self.short_rtt += (rtt - self.short_rtt) * SHORT_WEIGHT;
+        let gradient = (TOLERANCE * self.long_rtt / self.short_rtt).clamp(0.5, 1.0);
+        let target = self.limit * gradient + self.limit.sqrt();
+        let next = self.limit * (1.0 - SMOOTHING) + target * SMOOTHING;
+        // Idle capacity says nothing about whether more would be served well.
+        if next < self.limit || (inflight as f64) * 2.0 >= self.limit {
+            self.limit = next.clamp(1.0, self.max);
+        }
+        self.limit as usize
+    }
+}
+
+fn overloaded(limiter: &Limiter) -> Response {
+    metrics::inc_shed_requests();
+    let mut resp = error::error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "overloaded",
+        "server is over capacity",
+    );
+    resp.headers_mut().insert(header::RETRY_AFTER, limiter.retry_after());
+    resp.map(boxed)
+}
+
+/// Middleware that sheds requests beyond `limiter`'s capacity with a 503
+/// before they reach Lean. The slot is held until the body is sent, so
+/// streamed responses count for as long as they run.
+pub(crate) async fn middleware<B>(
+    State(limiter): State<Arc<Limiter>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(permit) = limiter.acquire().await else {
+        return overloaded(&limiter);
+    };
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    permit.sample(started.elapsed());
+    hold(resp, permit)
+}
+
+/// Keeps `guard` alive until `resp`'s body has been sent or dropped.
+pub(crate) fn hold<G: Send + Unpin + 'static>(resp: Response, guard: G) -> Response {
+    resp.map(|inner| boxed(Body::wrap_stream(Held { inner, _guard: guard })))
+}
+
+struct Held<G> {
+    inner: BoxBody,
+    _guard: G,
+}
+
+impl<G: Unpin> futures_util::Stream for Held<G> {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    fn limiter(limit: usize, queue: usize) -> Arc<Limiter> {
+        Arc::new(Limiter::new(limit, queue, Duration::from_millis(200)))
+    }
+
+    #[tokio::test]
+    async fn queues_then_sheds() {
+        let limiter = limiter(1, 1);
+        let first = limiter.acquire().await.unwrap();
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // The queue is full, so this one is shed at once.
+        assert!(limiter.acquire().await.is_none());
+        drop(first);
+        assert!(queued.await.unwrap());
+
+        let held = limiter.acquire().await.unwrap();
+        let started = Instant::now();
+        assert!(limiter.acquire().await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        drop(held);
+    }
+
+    #[test]
+    fn gradient_shrinks_as_latency_rises() {
+        let mut gradient = Gradient::new(100);
+        for _ in 0..20 {
+            assert_eq!(gradient.update(Duration::from_millis(10), 100), 100);
+        }
+        let mut limit = 100;
+        for _ in 0..20 {
+            limit = gradient.update(Duration::from_millis(100), limit);
+        }
+        assert!(limit < 50, "{limit}");
+        for _ in 0..200 {
+            limit = gradient.update(Duration::from_millis(10), limit);
+        }
+        assert!(limit > 90, "{limit}");
+    }
+
+    #[tokio::test]
+    async fn over_capacity_is_503_with_retry_after() {
+        let limiter = limiter(1, 0);
+        let router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(limiter.clone(), middleware));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()));
+
+        let client = hyper::Client::new();
+        let uri: hyper::Uri = format!("http://{addr}/").parse().unwrap();
+        assert_eq!(client.get(uri.clone()).await.unwrap().status(), 200);
+        let held = limiter.acquire().await.unwrap();
+        let resp = client.get(uri.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        drop(held);
+        assert_eq!(client.get(uri).await.unwrap().status(), 200);
+    }
+}
@@ -0,0 +1,364 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::conn::ConnInfo;
+
+/// Where to listen: a TCP address, or `unix:<path>` for a Unix domain socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    /// Parses a comma-separated list, e.g. `LITHE_BIND`'s
+    /// `0.0.0.0:3000,[::]:3000,unix:/run/lithe.sock`.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty unix socket path".to_string()),
+            Some(path) => Ok(Bind::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Bind::Tcp)
+                .map_err(|_| format!("invalid bind address {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{addr}"),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What a listener serves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// The app, in plaintext.
+    Http,
+    /// The app over TLS; needs a `TlsConfig` and a TCP socket.
+    Https,
+    /// Shim health and metrics, never the app.
+    Admin,
+}
+
+impl Role {
+    /// Maps a socket name (systemd's `FileDescriptorName=`) of `http`,
+    /// `https` or `admin` to its role.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "http" => Some(Role::Http),
+            "https" => Some(Role::Https),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Http => "http",
+            Role::Https => "https",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// A bound socket, ready to serve.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    /// Binds `bind`. A stale Unix socket file is replaced unless something is
+    /// still accepting on it, and `unix_mode` (e.g. `0o660`) sets the new
+    /// socket's permissions.
+    pub fn bind(bind: &Bind, unix_mode: Option<u32>) -> io::Result<Self> {
+        match bind {
+            Bind::Tcp(addr) => Ok(Listener::Tcp(std::net::TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Bind::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if !meta.file_type().is_socket() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display()),
+                        ));
+                    }
+                    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{} is in use", path.display()),
+                        ));
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                let listener = std::os::unix::net::UnixListener::bind(path)?;
+                if let Some(mode) = unix_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+                Ok(Listener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            Bind::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Adopts an inherited listening socket, checking that it is a stream
+    /// socket in the listening state.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open socket that nothing else in the process owns.
+    #[cfg(unix)]
+    pub unsafe fn from_fd(fd: std::os::unix::io::RawFd) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        if sockopt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} is not a stream socket"),
+            ));
+        }
+        if sockopt(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} is not listening"),
+            ));
+        }
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => {
+                Ok(Listener::Tcp(std::net::TcpListener::from_raw_fd(fd)))
+            }
+            libc::AF_UNIX => Ok(Listener::Unix(std::os::unix::net::UnixListener::from_raw_fd(fd))),
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} has unsupported address family {family}"),
+            )),
+        }
+    }
+
+    /// The role of a listener without a recognised name: HTTPS on TCP when
+    /// TLS is configured, HTTP otherwise.
+    pub fn default_role(&self, tls: bool) -> Role {
+        match self {
+            Listener::Tcp(_) if tls => Role::Https,
+            _ => Role::Http,
+        }
+    }
+
+    /// Where the listener is bound.
+    pub fn local(&self) -> io::Result<Bind> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(Bind::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(l) => Ok(Bind::Unix(
+                l.local_addr()?
+                    .as_pathname()
+                    .map(PathBuf::from)
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sockopt(fd: std::os::unix::io::RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `val` and `len` describe a valid c_int buffer.
+    let rc = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, name, &mut val as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(val)
+}
+
+/// Listening sockets passed in by a service manager: `LISTEN_FDS` sockets
+/// starting at fd 3, each with its `LISTEN_FDNAMES` entry. Empty when none
+/// were passed, or when `LISTEN_PID` names another process. The variables
+/// are cleared, as `sd_listen_fds(1)` does, so child processes don't adopt
+/// the same fds; call this before starting any threads.
+#[cfg(unix)]
+pub fn inherited() -> io::Result<Vec<(Option<String>, Listener)>> {
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let Some(fds) = fds else {
+        return Ok(Vec::new());
+    };
+    if pid.is_some_and(|pid| pid.trim().parse::<u32>().ok() != Some(std::process::id())) {
+        return Ok(Vec::new());
+    }
+    let count: i32 = fds.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid LISTEN_FDS {fds:?}"))
+    })?;
+    let names: Vec<&str> = names.as_deref().map(|n| n.split(':').collect()).unwrap_or_default();
+    (0..count)
+        .map(|i| {
+            let fd = LISTEN_FDS_START + i;
+            // SAFETY: the service manager hands these fds to this process alone.
+            let listener = unsafe { Listener::from_fd(fd)? };
+            let name = names.get(i as usize).filter(|n| !n.is_empty()).map(|n| n.to_string());
+            Ok((name, listener))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn inherited() -> io::Result<Vec<(Option<String>, Listener)>> {
+    Ok(Vec::new())
+}
+
+/// Parses `LITHE_UNIX_MODE`, an octal permission mode such as `660`.
+pub fn unix_mode_from_env() -> Result<Option<u32>, String> {
+    match std::env::var("LITHE_UNIX_MODE") {
+        Ok(v) => u32::from_str_radix(v.trim_start_matches("0o"), 8)
+            .map(Some)
+            .map_err(|_| format!("invalid LITHE_UNIX_MODE {v:?}")),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+impl Connected<&tokio::net::UnixStream> for ConnInfo {
+    fn connect_info(target: &tokio::net::UnixStream) -> Self {
+        let path = target
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(PathBuf::from));
+        let info = ConnInfo::new(None, None, "http");
+        match path {
+            Some(path) => info.with_unix_path(path),
+            None => info,
+        }
+    }
+}
+
+/// Accepts connections on a Unix socket; errors are logged and retried
+/// rather than ending the server.
+#[cfg(unix)]
+pub(crate) fn unix_incoming(
+    listener: tokio::net::UnixListener,
+) -> impl Accept<Conn = tokio::net::UnixStream, Error = io::Error> {
+    let conns = futures_util::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(err) => {
+                    warn!(error = %err, "failed to accept unix connection");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+    hyper::server::accept::from_stream(conns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bind_lists() {
+        let binds = Bind::parse_list("127.0.0.1:3000, [::1]:3000,unix:/tmp/lithe.sock").unwrap();
+        assert_eq!(
+            binds,
+            [
+                Bind::Tcp("127.0.0.1:3000".parse().unwrap()),
+                Bind::Tcp("[::1]:3000".parse().unwrap()),
+                Bind::Unix(PathBuf::from("/tmp/lithe.sock")),
+            ]
+        );
+        assert_eq!(binds[2].to_string(), "unix:/tmp/lithe.sock");
+        assert!(Bind::parse_list("localhost:3000").is_err());
+        assert!(Bind::parse_list("unix:").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_bind_replaces_stale_socket_and_sets_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("lithe-{}.sock", std::process::id()));
+        let bind = Bind::Unix(path.clone());
+        let first = Listener::bind(&bind, None).unwrap();
+        assert!(Listener::bind(&bind, None).is_err(), "socket in use");
+        drop(first);
+        let listener = Listener::bind(&bind, Some(0o600)).unwrap();
+        assert_eq!(listener.local().unwrap(), bind);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(&path).ok();
+
+        let file = std::env::temp_dir().join(format!("lithe-{}.notsock", std::process::id()));
+        std::fs::write(&file, "x").unwrap();
+        assert!(Listener::bind(&Bind::Unix(file.clone()), None).is_err());
+        std::fs::remove_file(file).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopts_listening_stream_sockets_only() {
+        use std::os::unix::io::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = unsafe { Listener::from_fd(tcp.into_raw_fd()) }.unwrap();
+        assert_eq!(listener.local().unwrap(), Bind::Tcp(addr));
+        assert_eq!(listener.default_role(true), Role::Https);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        assert!(unsafe { Listener::from_fd(udp) }.is_err());
+        drop(unsafe { <std::net::UdpSocket as std::os::unix::io::FromRawFd>::from_raw_fd(udp) });
+
+        assert_eq!(Role::from_name("admin"), Some(Role::Admin));
+        assert_eq!(Role::from_name("lithe"), None);
+    }
+}
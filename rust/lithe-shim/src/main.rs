@@ -1,6 +1,15 @@
-use lithe_shim::{new_app_id, shutdown_lean, serve_with_shutdown};
+use lithe_shim::{
+    check_env, inherited, init_lean, protocol, serve_listeners_with_shutdown, shutdown_lean,
+    try_new_app_id, unix_mode_from_env, Bind, Listener, Role, TlsConfig,
+};
+#[cfg(unix)]
+use lithe_shim::{upgrade_ready, Handoff, UpgradeReady};
+#[cfg(feature = "http3")]
 use std::net::SocketAddr;
-use tracing::{info, warn};
+#[cfg(unix)]
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
 
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -25,25 +34,276 @@ async fn shutdown_signal() {
     }
 }
 
-#[tokio::main]
-async fn main() {
+// Resolves on SIGUSR2 once a new binary, started with the same arguments,
+// is serving on the inherited listeners. A failed upgrade leaves this
+// process serving.
+#[cfg(unix)]
+async fn upgrade_signal(handoff: Option<Handoff>) {
+    let Some(handoff) = handoff else {
+        return std::future::pending().await;
+    };
+    let mut sigusr2 =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())
+            .expect("failed to install SIGUSR2 handler");
+    let timeout = std::env::var("LITHE_UPGRADE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(30));
+    while sigusr2.recv().await.is_some() {
+        let exe = std::env::var_os("LITHE_UPGRADE_EXE")
+            .or_else(|| std::env::args_os().next())
+            .unwrap_or_default();
+        let mut cmd = std::process::Command::new(&exe);
+        cmd.args(std::env::args_os().skip(1));
+        info!(exe = ?exe, "upgrade requested");
+        match handoff.spawn(cmd, timeout).await {
+            Ok(pid) => {
+                info!(pid, "new process is serving; draining");
+                return;
+            }
+            Err(err) => error!(error = %err, "upgrade failed; still serving"),
+        }
+    }
+    std::future::pending().await
+}
+
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+fn bind_from_env(tls: bool) -> Vec<(Role, Listener)> {
+    let binds = match Bind::parse_list(
+        &std::env::var("LITHE_BIND").unwrap_or_else(|_| "127.0.0.1:3000".to_string()),
+    ) {
+        Ok(binds) if !binds.is_empty() => binds,
+        Ok(_) => {
+            error!("LITHE_BIND has no addresses");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!(error = %err, "invalid LITHE_BIND");
+            std::process::exit(1);
+        }
+    };
+    let unix_mode = match unix_mode_from_env() {
+        Ok(mode) => mode,
+        Err(err) => {
+            error!(error = %err, "invalid unix socket configuration");
+            std::process::exit(1);
+        }
+    };
+    binds
+        .iter()
+        .map(|bind| match Listener::bind(bind, unix_mode) {
+            Ok(listener) => (listener.default_role(tls), listener),
+            Err(err) => {
+                error!(addr = %bind, error = %err, "failed to bind");
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+fn main() {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let addr: SocketAddr = std::env::var("LITHE_BIND")
-        .unwrap_or_else(|_| "127.0.0.1:3000".to_string())
-        .parse()
-        .expect("invalid bind address");
+    // Sockets from a service manager replace LITHE_BIND entirely. Taken
+    // before the runtime starts any threads, since it clears LISTEN_FDS.
+    let inherited = match inherited() {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!(error = %err, "invalid inherited listeners");
+            std::process::exit(1);
+        }
+    };
+    // Likewise for the readiness socket of an upgrade.
+    #[cfg(unix)]
+    let ready = match upgrade_ready() {
+        Ok(ready) => ready,
+        Err(err) => {
+            warn!(error = %err, "cannot tell the previous process we are ready");
+            None
+        }
+    };
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!(error = %err, "failed to start the async runtime");
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(serve(
+        inherited,
+        #[cfg(unix)]
+        ready,
+    ));
+}
+
+async fn serve(
+    inherited: Vec<(Option<String>, Listener)>,
+    #[cfg(unix)] ready: Option<UpgradeReady>,
+) {
     let app_name = std::env::var("LITHE_APP").unwrap_or_else(|_| "hello".to_string());
+    let tls = match TlsConfig::from_env() {
+        Ok(tls) => tls,
+        Err(err) => {
+            error!(error = %err, "invalid TLS configuration");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = check_env() {
+        error!(error = %err, "invalid configuration");
+        std::process::exit(1);
+    }
+
+    if let Err(err) = init_lean() {
+        error!(error = %err.message, "failed to initialize lean");
+        std::process::exit(1);
+    }
+    match protocol() {
+        Ok(p) => info!(
+            lean_version = %p.lean_version,
+            wire = p.wire_version,
+            stream_wire = p.stream_wire_version,
+            "lean handshake complete"
+        ),
+        Err(err) => {
+            error!(error = %err, "incompatible lean library");
+            std::process::exit(1);
+        }
+    }
+
+    let app_id = match try_new_app_id(&app_name) {
+        Ok(id) => id,
+        Err(err) => {
+            error!(app = %app_name, error = %err.message, "failed to create app");
+            std::process::exit(1);
+        }
+    };
+
+    let listeners = if inherited.is_empty() {
+        bind_from_env(tls.is_some())
+    } else {
+        inherited
+            .into_iter()
+            .map(|(name, listener)| {
+                let role = match name.as_deref().map(|name| (name, Role::from_name(name))) {
+                    Some((_, Some(role))) => role,
+                    Some((name, None)) => {
+                        let role = listener.default_role(tls.is_some());
+                        warn!(name, %role, "unrecognised inherited socket name");
+                        role
+                    }
+                    None => listener.default_role(tls.is_some()),
+                };
+                (role, listener)
+            })
+            .collect()
+    };
+    for (role, listener) in &listeners {
+        match listener.local() {
+            Ok(addr) => info!(%addr, %role, app = %app_name, "lithe-shim listening"),
+            Err(err) => warn!(%role, error = %err, "listening on an unknown address"),
+        }
+    }
 
-    let app_id = new_app_id(&app_name);
+    // Stops every listener, on SIGTERM/ctrl-c or after a successful upgrade.
+    let (stop_tx, stop_rx) = watch::channel(false);
+    #[cfg(unix)]
+    let handoff = match Handoff::new(&listeners) {
+        Ok(handoff) => Some(handoff),
+        Err(err) => {
+            warn!(error = %err, "cannot keep listeners for upgrades");
+            None
+        }
+    };
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = shutdown_signal() => {}
+            _ = upgrade_signal(handoff) => {}
+        }
+        #[cfg(not(unix))]
+        shutdown_signal().await;
+        let _ = stop_tx.send(true);
+    });
 
-    info!(%addr, app = %app_name, "lithe-shim listening");
+    let http3_bind = std::env::var("LITHE_HTTP3_BIND").ok();
+    #[cfg(feature = "http3")]
+    let http3 = match (http3_bind, &tls) {
+        (Some(bind), Some(tls)) => {
+            let addr: SocketAddr = match bind.parse() {
+                Ok(addr) => addr,
+                Err(err) => {
+                    error!(addr = %bind, error = %err, "invalid LITHE_HTTP3_BIND");
+                    std::process::exit(1);
+                }
+            };
+            let tls = tls.clone();
+            let stop_rx = stop_rx.clone();
+            // Long enough for a previous process to drain and let go of the
+            // port, with a little time to close its endpoint.
+            let give_up = tokio::time::Instant::now()
+                + lithe_shim::drain_timeout()
+                + std::time::Duration::from_secs(5);
+            Some(tokio::spawn(async move {
+                loop {
+                    let served = lithe_shim::serve_http3_with_shutdown(
+                        addr,
+                        app_id,
+                        tls.clone(),
+                        stopped(stop_rx.clone()),
+                    )
+                    .await;
+                    match served {
+                        Ok(()) => break,
+                        // During an upgrade the old process keeps the UDP port
+                        // until it has drained; QUIC sockets are not handed over.
+                        Err(err)
+                            if !*stop_rx.borrow()
+                                && err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+                                    err.kind() == std::io::ErrorKind::AddrInUse
+                                }) =>
+                        {
+                            if tokio::time::Instant::now() >= give_up {
+                                error!(%addr, "http/3 port is still in use; not serving HTTP/3");
+                                break;
+                            }
+                            warn!(%addr, "http/3 port is in use; retrying");
+                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        }
+                        Err(err) => {
+                            error!(error = %err, "http/3 listener failed");
+                            break;
+                        }
+                    }
+                }
+            }))
+        }
+        (Some(_), None) => {
+            warn!("LITHE_HTTP3_BIND needs LITHE_TLS_CERT; not serving HTTP/3");
+            None
+        }
+        (None, _) => None,
+    };
+    #[cfg(not(feature = "http3"))]
+    if http3_bind.is_some() {
+        warn!("LITHE_HTTP3_BIND is set but lithe-shim was built without the http3 feature");
+    }
 
-    serve_with_shutdown(addr, app_id, shutdown_signal())
+    #[cfg(unix)]
+    if let Some(Err(err)) = ready.map(UpgradeReady::notify) {
+        warn!(error = %err, "failed to tell the previous process we are ready");
+    }
+    serve_listeners_with_shutdown(listeners, app_id, tls, stopped(stop_rx))
         .await
         .expect("server failed");
-
+    #[cfg(feature = "http3")]
+    if let Some(http3) = http3 {
+        let _ = http3.await;
+    }
     shutdown_lean(app_id);
 }
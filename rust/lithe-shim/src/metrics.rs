@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Process-wide counters; cheap enough to bump on every request.
+struct Counters {
+    lean_errors: AtomicU64,
+    invalid_headers: AtomicU64,
+    shed_requests: AtomicU64,
+    body_idle_timeouts: AtomicU64,
+    stream_idle_timeouts: AtomicU64,
+    stream_lifetime_timeouts: AtomicU64,
+    slow_client_writes: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    lean_errors: AtomicU64::new(0),
+    invalid_headers: AtomicU64::new(0),
+    shed_requests: AtomicU64::new(0),
+    body_idle_timeouts: AtomicU64::new(0),
+    stream_idle_timeouts: AtomicU64::new(0),
+    stream_lifetime_timeouts: AtomicU64::new(0),
+    slow_client_writes: AtomicU64::new(0),
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Exceptions that escaped a Lean export.
+    pub lean_errors: u64,
+    /// Response headers from Lean with an invalid name or value.
+    pub invalid_headers: u64,
+    /// Requests refused with 503 because the shim was over capacity.
+    pub shed_requests: u64,
+    /// Requests cancelled because the client stopped sending the body.
+    pub body_idle_timeouts: u64,
+    /// Streamed responses cancelled because Lean stopped sending chunks.
+    pub stream_idle_timeouts: u64,
+    /// Streamed responses cancelled for running past their maximum duration.
+    pub stream_lifetime_timeouts: u64,
+    /// Streamed responses cancelled because the client read them too slowly.
+    pub slow_client_writes: u64,
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    MetricsSnapshot {
+        lean_errors: COUNTERS.lean_errors.load(Ordering::Relaxed),
+        invalid_headers: COUNTERS.invalid_headers.load(Ordering::Relaxed),
+        shed_requests: COUNTERS.shed_requests.load(Ordering::Relaxed),
+        body_idle_timeouts: COUNTERS.body_idle_timeouts.load(Ordering::Relaxed),
+        stream_idle_timeouts: COUNTERS.stream_idle_timeouts.load(Ordering::Relaxed),
+        stream_lifetime_timeouts: COUNTERS.stream_lifetime_timeouts.load(Ordering::Relaxed),
+        slow_client_writes: COUNTERS.slow_client_writes.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn inc_lean_errors() {
+    COUNTERS.lean_errors.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_invalid_headers() {
+    COUNTERS.invalid_headers.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_shed_requests() {
+    COUNTERS.shed_requests.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_body_idle_timeouts() {
+    COUNTERS.body_idle_timeouts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_stream_idle_timeouts() {
+    COUNTERS.stream_idle_timeouts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_stream_lifetime_timeouts() {
+    COUNTERS.stream_lifetime_timeouts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn inc_slow_client_writes() {
+    COUNTERS.slow_client_writes.fetch_add(1, Ordering::Relaxed);
+}
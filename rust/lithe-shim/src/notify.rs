@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::{ffi, handshake};
+
+// Wakeup kinds; must match `Lithe/Runtime/Notify.lean`.
+pub const STREAM_RESPONSE: u8 = 1;
+pub const STREAM_BODY: u8 = 2;
+pub const WS_OUT: u8 = 3;
+pub const WS_IN: u8 = 4;
+
+// Re-poll interval when the Lean library does not send wakeups.
+const FALLBACK_POLL: Duration = Duration::from_millis(5);
+
+type WaiterMap = Mutex<HashMap<(u8, u64), Arc<Notify>>>;
+
+static WAITERS: OnceLock<WaiterMap> = OnceLock::new();
+
+fn waiters() -> &'static WaiterMap {
+    WAITERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Invoked by Lean (on any of its threads) through `lithe_notify`.
+extern "C" fn on_notify(kind: u8, id: u64) {
+    let notify = match waiters().lock() {
+        Ok(map) => map.get(&(kind, id)).cloned(),
+        Err(_) => None,
+    };
+    if let Some(notify) = notify {
+        notify.notify_one();
+    }
+}
+
+pub(crate) fn install() {
+    unsafe {
+        ffi::lithe_set_notify_callback(on_notify);
+    }
+}
+
+// Registration for wakeups on one (kind, id) pair. Register before the first
+// poll/push so a wakeup that races the registration is never lost; a stale
+// permit only costs one extra poll.
+pub(crate) struct Waiter {
+    key: (u8, u64),
+    notify: Arc<Notify>,
+}
+
+impl Waiter {
+    pub(crate) fn new(kind: u8, id: u64) -> Self {
+        let notify = Arc::new(Notify::new());
+        if let Ok(mut map) = waiters().lock() {
+            map.insert((kind, id), notify.clone());
+        }
+        Self {
+            key: (kind, id),
+            notify,
+        }
+    }
+
+    pub(crate) async fn wait(&self) {
+        if handshake::capabilities().notify {
+            self.notify.notified().await;
+        } else {
+            tokio::time::sleep(FALLBACK_POLL).await;
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Ok(mut map) = waiters().lock() {
+            map.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These wait on the `Notify` itself: `Waiter::wait` only uses it once the
+    // handshake has advertised wakeups.
+    #[tokio::test]
+    async fn notify_wakes_registered_waiter() {
+        let waiter = Waiter::new(WS_OUT, 42);
+        on_notify(WS_OUT, 42);
+        tokio::time::timeout(Duration::from_secs(1), waiter.notify.notified())
+            .await
+            .expect("waiter woken");
+    }
+
+    #[tokio::test]
+    async fn notify_ignores_other_keys() {
+        let waiter = Waiter::new(WS_IN, 7);
+        on_notify(WS_OUT, 7);
+        on_notify(WS_IN, 8);
+        let res = tokio::time::timeout(Duration::from_millis(20), waiter.notify.notified()).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn waiter_unregisters_on_drop() {
+        let waiter = Waiter::new(STREAM_BODY, 99);
+        drop(waiter);
+        assert!(!waiters().lock().unwrap().contains_key(&(STREAM_BODY, 99)));
+    }
+}
@@ -0,0 +1,420 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use std::fmt::Write as _;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::conn::{ConnInfo, ProxyInfo};
+
+static CONFIG: OnceLock<Result<Option<ProxyConfig>, String>> = OnceLock::new();
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+// Time a trusted peer gets to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An address range such as `10.0.0.0/8`; a bare address matches only itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses a comma-separated list, e.g. `10.0.0.0/8,fd00::/8`.
+    pub(crate) fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid CIDR {s:?}");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// PROXY protocol settings, read once from the environment.
+#[derive(Clone, Debug)]
+pub(crate) struct ProxyConfig {
+    /// Peers that must send a PROXY header; everyone else is taken as the
+    /// client itself.
+    trusted: Vec<Cidr>,
+}
+
+impl ProxyConfig {
+    fn from_env() -> Result<Option<Self>, String> {
+        let Ok(list) = std::env::var("LITHE_PROXY_PROTOCOL") else {
+            return Ok(None);
+        };
+        let trusted = Cidr::parse_list(&list)
+            .map_err(|err| format!("invalid LITHE_PROXY_PROTOCOL: {err}"))?;
+        Ok(Some(ProxyConfig { trusted }).filter(|cfg| !cfg.trusted.is_empty()))
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// `None` unless `LITHE_PROXY_PROTOCOL` lists trusted upstreams. Serving
+/// fails on an invalid list first (see [`check`]).
+pub(crate) fn config() -> Option<&'static ProxyConfig> {
+    CONFIG.get_or_init(ProxyConfig::from_env).as_ref().ok()?.as_ref()
+}
+
+/// Why `LITHE_PROXY_PROTOCOL` cannot be used, if it cannot.
+pub(crate) fn check() -> Result<(), String> {
+    let cfg = CONFIG.get_or_init(ProxyConfig::from_env);
+    cfg.as_ref().map(drop).map_err(Clone::clone)
+}
+
+/// What a PROXY header says about the connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    /// The client; `None` for `LOCAL` (e.g. health checks) and `UNKNOWN`.
+    pub(crate) source: Option<SocketAddr>,
+    pub(crate) authority: Option<String>,
+    pub(crate) unique_id: Option<String>,
+}
+
+/// Reads a v1 or v2 header, consuming exactly its bytes.
+pub(crate) async fn read_header<IO>(io: &mut IO) -> io::Result<ProxyHeader>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut start = [0u8; 5];
+    io.read_exact(&mut start).await?;
+    if start == V1_PREFIX {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(io.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if start == V2_SIGNATURE[..5] {
+        let mut rest = [0u8; 11];
+        io.read_exact(&mut rest).await?;
+        if rest[..7] != V2_SIGNATURE[5..] {
+            return Err(invalid("bad PROXY v2 signature"));
+        }
+        let len = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+        let mut body = vec![0u8; len];
+        io.read_exact(&mut body).await?;
+        parse_v2(rest[7], rest[8], &body)
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip, port)),
+                ..ProxyHeader::default()
+            })
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> io::Result<ProxyHeader> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+    let local = match ver_cmd & 0x0f {
+        0 => true,
+        1 => false,
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    };
+    let (source, addr_len) = match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            (Some(SocketAddr::new(ip.into(), port)), 12)
+        }
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            (Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)), 36)
+        }
+        1 | 2 => return Err(invalid("truncated PROXY v2 address")),
+        // AF_UNSPEC or AF_UNIX: nothing to report about the client.
+        3 if body.len() >= 216 => (None, 216),
+        _ => (None, 0),
+    };
+    let mut header = ProxyHeader {
+        source: if local { None } else { source },
+        ..ProxyHeader::default()
+    };
+    let mut tlvs = &body[addr_len.min(body.len())..];
+    while tlvs.len() >= 3 {
+        let kind = tlvs[0];
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        let Some(value) = tlvs.get(3..3 + len) else {
+            return Err(invalid("truncated PROXY v2 TLV"));
+        };
+        match kind {
+            PP2_TYPE_AUTHORITY => header.authority = Some(String::from_utf8_lossy(value).into_owned()),
+            PP2_TYPE_UNIQUE_ID => header.unique_id = Some(unique_id(value)),
+            _ => {}
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(header)
+}
+
+// Balancers usually send text ids; anything else is passed on as hex.
+fn unique_id(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(s) => s.to_string(),
+        Err(_) => value.iter().fold(String::new(), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        }),
+    }
+}
+
+/// Reads the PROXY header when the peer is trusted and returns the details
+/// Lean should see: the client it reports as `remote`, the balancer in
+/// `proxy`. Connections from anyone else are returned untouched.
+pub(crate) async fn accept(
+    cfg: &ProxyConfig,
+    stream: &mut TcpStream,
+    info: ConnInfo,
+) -> io::Result<ConnInfo> {
+    let Some(peer) = info.remote.filter(|peer| cfg.trusts(peer.ip())) else {
+        return Ok(info);
+    };
+    let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timed out"))??;
+    Ok(info.with_proxy(
+        header.source,
+        ProxyInfo {
+            peer,
+            authority: header.authority,
+            unique_id: header.unique_id,
+        },
+    ))
+}
+
+/// Accepts plain TCP connections, reading PROXY headers off the accept path.
+pub(crate) fn incoming(
+    cfg: &'static ProxyConfig,
+    listener: TcpListener,
+) -> impl Accept<Conn = ProxiedConn, Error = io::Error> {
+    let (tx, rx) = mpsc::channel::<ProxiedConn>(64);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, remote) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn!(error = %err, "failed to accept connection");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                // The server stopped accepting.
+                _ = tx.closed() => return,
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let info = ConnInfo::new(Some(remote), stream.local_addr().ok(), "http");
+                match accept(cfg, &mut stream, info).await {
+                    Ok(info) => {
+                        let _ = tx.send(ProxiedConn { stream, info }).await;
+                    }
+                    Err(err) => debug!(%remote, error = %err, "rejected PROXY connection"),
+                }
+            });
+        }
+    });
+    let conns = futures_util::stream::unfold(rx, |mut rx| async move {
+        let conn = rx.recv().await?;
+        Some((Ok(conn), rx))
+    });
+    hyper::server::accept::from_stream(conns)
+}
+
+/// A plain TCP connection whose details came partly from a PROXY header.
+pub struct ProxiedConn {
+    stream: TcpStream,
+    info: ConnInfo,
+}
+
+impl Connected<&ProxiedConn> for ConnInfo {
+    fn connect_info(target: &ProxiedConn) -> Self {
+        target.info.clone()
+    }
+}
+
+impl AsyncRead for ProxiedConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(bytes: &[u8]) -> io::Result<(ProxyHeader, Vec<u8>)> {
+        let mut reader = bytes;
+        let header = read_header(&mut reader).await?;
+        Ok((header, reader.to_vec()))
+    }
+
+    #[test]
+    fn cidrs_match_v4_v6_and_mapped_addresses() {
+        let nets = Cidr::parse_list("10.0.0.0/8, fd00::/8,192.0.2.7").unwrap();
+        let trusts = |ip: &str| nets.iter().any(|n| n.contains(ip.parse().unwrap()));
+        assert!(trusts("10.1.2.3"));
+        assert!(trusts("::ffff:10.1.2.3"));
+        assert!(trusts("fd12::1"));
+        assert!(trusts("192.0.2.7"));
+        assert!(!trusts("192.0.2.8"));
+        assert!(!trusts("11.0.0.1"));
+        assert!(Cidr::parse_list("10.0.0.0/33").is_err());
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers_and_leaves_the_request() {
+        let (header, rest) =
+            parse(b"PROXY TCP4 203.0.113.9 10.0.0.1 51000 443\r\nGET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(header.source, Some("203.0.113.9:51000".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (header, _) = parse(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(header.source, None);
+        assert!(parse(b"PROXY TCP4 ::1 ::1 1 2\r\n").await.is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_addresses_and_tlvs() {
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.extend([0x21, 0x11]);
+        let mut body = vec![203, 0, 113, 9, 10, 0, 0, 1];
+        body.extend(51000u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        for (kind, value) in [(PP2_TYPE_AUTHORITY, &b"example.com"[..]), (PP2_TYPE_UNIQUE_ID, &[0xff, 0x01])] {
+            body.push(kind);
+            body.extend((value.len() as u16).to_be_bytes());
+            body.extend(value);
+        }
+        bytes.extend((body.len() as u16).to_be_bytes());
+        bytes.extend(&body);
+        bytes.extend(b"GET");
+
+        let (header, rest) = parse(&bytes).await.unwrap();
+        assert_eq!(header.source, Some("203.0.113.9:51000".parse().unwrap()));
+        assert_eq!(header.authority.as_deref(), Some("example.com"));
+        assert_eq!(header.unique_id.as_deref(), Some("ff01"));
+        assert_eq!(rest, b"GET");
+
+        // LOCAL: a health check from the balancer itself.
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(parse(&local).await.unwrap().0.source, None);
+    }
+}
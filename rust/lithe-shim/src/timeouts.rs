@@ -0,0 +1,147 @@
+use axum::http::{StatusCode, Version};
+use axum::response::Response;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::{error, hardening};
+
+static CONFIG: OnceLock<TimeoutConfig> = OnceLock::new();
+
+/// Timeouts on a streamed request or response once Lean has it, read once
+/// from the environment. Each one cancels the Lean stream when it fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimeoutConfig {
+    /// Between chunks of the request body.
+    pub(crate) body_idle: Option<Duration>,
+    /// Between chunks of a streamed response.
+    pub(crate) stream_idle: Option<Duration>,
+    /// From the response head to the end of a streamed response.
+    pub(crate) stream_lifetime: Option<Duration>,
+    /// Bytes per second a client must read a streamed response at.
+    pub(crate) min_write_rate: Option<u64>,
+    /// Allowed on top of the minimum rate for each chunk.
+    pub(crate) write_grace: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            body_idle: None,
+            stream_idle: None,
+            stream_lifetime: None,
+            min_write_rate: None,
+            write_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    fn from_env() -> Self {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let num = |key: &str| var(key).and_then(|v| v.parse::<u64>().ok());
+        let timeout = |key: &str, unit: fn(u64) -> Duration| num(key).filter(|n| *n > 0).map(unit);
+        Self {
+            body_idle: timeout("LITHE_BODY_IDLE_TIMEOUT_MS", Duration::from_millis),
+            stream_idle: timeout("LITHE_STREAM_IDLE_TIMEOUT_MS", Duration::from_millis),
+            stream_lifetime: timeout("LITHE_STREAM_MAX_DURATION_SECS", Duration::from_secs),
+            min_write_rate: num("LITHE_MIN_WRITE_RATE").filter(|n| *n > 0),
+            write_grace: Duration::from_millis(num("LITHE_WRITE_GRACE_MS").unwrap_or(5000)),
+        }
+    }
+
+    /// How long the client may take to accept a chunk of `len` bytes.
+    pub(crate) fn write_timeout(&self, len: usize) -> Option<Duration> {
+        let rate = self.min_write_rate?;
+        Some(self.write_grace + Duration::from_secs_f64(len as f64 / rate as f64))
+    }
+}
+
+pub(crate) fn config() -> &'static TimeoutConfig {
+    CONFIG.get_or_init(TimeoutConfig::from_env)
+}
+
+/// `fut`'s output, or `None` if `limit` passes first.
+pub(crate) async fn within<F: Future>(limit: Option<Duration>, fut: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// Completes at `at`, or never when there is no deadline.
+pub(crate) async fn until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 408 for a request body that stopped arriving, closing HTTP/1 connections.
+pub(crate) fn body_timed_out(version: Version) -> Response {
+    let resp = error::error_response(
+        StatusCode::REQUEST_TIMEOUT,
+        "request_timeout",
+        "request body timed out",
+    );
+    hardening::close(version, resp.map(axum::body::boxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use bytes::Bytes;
+    use hyper::body::HttpBody;
+
+    #[test]
+    fn write_timeout_grows_with_chunk_size() {
+        let cfg = TimeoutConfig::default();
+        assert_eq!(cfg.write_timeout(1 << 20), None);
+        let cfg = TimeoutConfig {
+            min_write_rate: Some(1000),
+            ..cfg
+        };
+        assert_eq!(cfg.write_timeout(0), Some(Duration::from_secs(5)));
+        assert_eq!(cfg.write_timeout(2500), Some(Duration::from_millis(7500)));
+    }
+
+    #[test]
+    fn timeouts_are_read_from_the_environment() {
+        assert_eq!(TimeoutConfig::from_vars(|_| None), TimeoutConfig::default());
+        let cfg = TimeoutConfig::from_vars(|key| {
+            let value = match key {
+                "LITHE_BODY_IDLE_TIMEOUT_MS" => "250",
+                "LITHE_STREAM_IDLE_TIMEOUT_MS" => "0",
+                "LITHE_STREAM_MAX_DURATION_SECS" => "60",
+                "LITHE_MIN_WRITE_RATE" => "4096",
+                "LITHE_WRITE_GRACE_MS" => "100",
+                _ => return None,
+            };
+            Some(value.to_string())
+        });
+        assert_eq!(cfg.body_idle, Some(Duration::from_millis(250)));
+        assert_eq!(cfg.stream_idle, None);
+        assert_eq!(cfg.stream_lifetime, Some(Duration::from_secs(60)));
+        assert_eq!(cfg.write_timeout(4096), Some(Duration::from_millis(1100)));
+    }
+
+    #[tokio::test]
+    async fn stalled_readers_and_writers_time_out() {
+        let limit = Some(Duration::from_millis(50));
+        let (mut sender, mut body) = Body::channel();
+        // The channel holds one chunk; the next waits for the reader.
+        assert!(matches!(within(limit, sender.send_data(Bytes::from("a"))).await, Some(Ok(()))));
+        assert!(within(limit, sender.send_data(Bytes::from("b"))).await.is_none());
+
+        let first = within(limit, body.data()).await.unwrap().unwrap().unwrap();
+        assert_eq!(first, "a");
+        assert!(within(limit, body.data()).await.is_none());
+        drop(sender);
+        assert!(within(limit, body.data()).await.unwrap().is_none());
+    }
+}
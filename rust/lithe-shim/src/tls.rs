@@ -0,0 +1,638 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::conn::{ClientCert, ConnInfo, TlsInfo};
+use crate::http2;
+use crate::proxy::{self, ProxyConfig};
+
+/// A PEM certificate chain and the PEM private key for its leaf.
+#[derive(Clone, Debug)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Served certificates. SNI picks the one whose DNS names match; the first
+    /// is used when the client sends no name or an unknown one.
+    pub certs: Vec<CertPaths>,
+    /// How often to check the files for changes; `None` reloads only on SIGHUP.
+    pub reload_interval: Option<Duration>,
+    /// Time a client gets to complete the handshake.
+    pub handshake_timeout: Duration,
+    /// PEM bundle of CAs trusted for client certificates. When set, every
+    /// client must present a certificate that chains to one of them, and
+    /// the handshake fails otherwise. Read once at startup.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new(certs: Vec<CertPaths>) -> Self {
+        Self {
+            certs,
+            reload_interval: Some(Duration::from_secs(10)),
+            handshake_timeout: Duration::from_secs(10),
+            client_ca: None,
+        }
+    }
+
+    /// Reads `LITHE_TLS_CERT` and `LITHE_TLS_KEY` (comma-separated, paired by
+    /// position), `LITHE_TLS_RELOAD_SECS` (`0` disables polling) and
+    /// `LITHE_TLS_CLIENT_CA`. Returns `None` when no certificate is configured.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Ok(certs) = std::env::var("LITHE_TLS_CERT") else {
+            return Ok(None);
+        };
+        let keys = std::env::var("LITHE_TLS_KEY").unwrap_or_default();
+        let certs: Vec<&str> = certs.split(',').map(str::trim).collect();
+        let keys: Vec<&str> = keys.split(',').map(str::trim).collect();
+        if certs.len() != keys.len() || keys.iter().any(|k| k.is_empty()) {
+            return Err(invalid("LITHE_TLS_CERT and LITHE_TLS_KEY must list the same number of files"));
+        }
+        let mut cfg = Self::new(
+            certs
+                .iter()
+                .zip(&keys)
+                .map(|(cert, key)| CertPaths {
+                    cert: cert.into(),
+                    key: key.into(),
+                })
+                .collect(),
+        );
+        if let Ok(secs) = std::env::var("LITHE_TLS_RELOAD_SECS") {
+            let secs: u64 = secs
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("invalid LITHE_TLS_RELOAD_SECS {secs:?}")))?;
+            cfg.reload_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        cfg.client_ca = std::env::var_os("LITHE_TLS_CLIENT_CA")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+        Ok(Some(cfg))
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub(crate) fn load_certs(path: &PathBuf) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_key(path: &PathBuf) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(format!("no private key in {}", path.display())))
+}
+
+// DNS names a certificate is valid for: its SANs, or the subject CN if it has none.
+fn dns_names(cert: &Certificate) -> io::Result<Vec<String>> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|err| invalid(format!("invalid certificate: {err}")))?;
+    let mut names = Vec::new();
+    if let Ok(Some(san)) = parsed.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let x509_parser::extensions::GeneralName::DNSName(dns) = name {
+                names.push(dns.to_ascii_lowercase());
+            }
+        }
+    }
+    if names.is_empty() {
+        if let Some(cn) = parsed.subject().iter_common_name().next() {
+            if let Ok(cn) = cn.as_str() {
+                names.push(cn.to_ascii_lowercase());
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn client_roots(path: &PathBuf) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|err| invalid(format!("invalid CA certificate in {}: {err}", path.display())))?;
+    }
+    Ok(roots)
+}
+
+// Identity of a client certificate rustls has already verified.
+pub(crate) fn client_cert(cert: &Certificate) -> Option<ClientCert> {
+    use x509_parser::extensions::GeneralName;
+
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let mut sans = Vec::new();
+    if let Ok(Some(san)) = parsed.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(v) => sans.push(format!("DNS:{v}")),
+                GeneralName::URI(v) => sans.push(format!("URI:{v}")),
+                GeneralName::RFC822Name(v) => sans.push(format!("email:{v}")),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => <[u8; 4]>::try_from(*bytes).ok().map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+                        16 => <[u8; 16]>::try_from(*bytes).ok().map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+                        _ => None,
+                    };
+                    if let Some(ip) = ip {
+                        sans.push(format!("IP:{ip}"));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.0);
+    Some(ClientCert {
+        subject: parsed.subject().to_string(),
+        sans,
+        fingerprint: digest.as_ref().iter().map(|b| format!("{b:02x}")).collect(),
+    })
+}
+
+// One loaded generation of certificates, indexed by DNS name.
+struct CertSet {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// `*.example.com` is stored under `example.com`.
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl CertSet {
+    fn load(paths: &[CertPaths]) -> io::Result<Self> {
+        let mut exact = HashMap::new();
+        let mut wildcard = HashMap::new();
+        let mut default = None;
+        for p in paths {
+            let chain = load_certs(&p.cert)?;
+            let key = load_key(&p.key)?;
+            let signing_key = rustls::sign::any_supported_type(&key)
+                .map_err(|_| invalid(format!("unsupported private key in {}", p.key.display())))?;
+            let names = dns_names(&chain[0])?;
+            let certified = Arc::new(CertifiedKey::new(chain, signing_key));
+            for name in names {
+                match name.strip_prefix("*.") {
+                    Some(base) => wildcard.entry(base.to_string()),
+                    None => exact.entry(name),
+                }
+                .or_insert_with(|| certified.clone());
+            }
+            default.get_or_insert(certified);
+        }
+        let default = default.ok_or_else(|| invalid("no TLS certificates configured"))?;
+        Ok(Self {
+            exact,
+            wildcard,
+            default,
+        })
+    }
+
+    fn resolve(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+        if let Some(key) = self.exact.get(&name) {
+            return key.clone();
+        }
+        name.split_once('.')
+            .and_then(|(_, base)| self.wildcard.get(base))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+// Swapped wholesale on reload; handshakes in progress keep the set they started with.
+struct CertResolver {
+    current: RwLock<Arc<CertSet>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let set = self.current.read().ok()?.clone();
+        Some(set.resolve(hello.server_name()))
+    }
+}
+
+/// Loaded certificates plus the rustls config that serves them.
+pub(crate) struct TlsState {
+    config: TlsConfig,
+    resolver: Arc<CertResolver>,
+    server_config: Arc<ServerConfig>,
+}
+
+impl TlsState {
+    pub(crate) fn new(config: TlsConfig) -> io::Result<Self> {
+        let resolver = Arc::new(CertResolver {
+            current: RwLock::new(Arc::new(CertSet::load(&config.certs)?)),
+        });
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &config.client_ca {
+            Some(path) => builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots(path)?).boxed()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = if http2::config().enabled {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        Ok(Self {
+            config,
+            resolver,
+            server_config: Arc::new(server_config),
+        })
+    }
+
+    fn reload(&self) {
+        match CertSet::load(&self.config.certs) {
+            Ok(set) => {
+                if let Ok(mut current) = self.resolver.current.write() {
+                    *current = Arc::new(set);
+                }
+                info!("reloaded TLS certificates");
+            }
+            Err(err) => error!(error = %err, "failed to reload TLS certificates; keeping the old ones"),
+        }
+    }
+
+    fn mtimes(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .certs
+            .iter()
+            .flat_map(|p| [&p.cert, &p.key])
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Reloads certificates when the files change or on SIGHUP, until dropped.
+    pub(crate) async fn watch(self: Arc<Self>) {
+        let mut stamps = self.mtimes();
+        let mut tick = self.config.reload_interval.map(tokio::time::interval);
+        #[cfg(unix)]
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+        loop {
+            let poll = async {
+                match tick.as_mut() {
+                    Some(tick) => {
+                        tick.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(unix)]
+            let sighup = async {
+                match hangup.as_mut() {
+                    Some(sig) => {
+                        sig.recv().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let sighup = std::future::pending::<()>();
+
+            let forced = tokio::select! {
+                _ = poll => false,
+                _ = sighup => true,
+            };
+            let current = self.mtimes();
+            if forced || current != stamps {
+                stamps = current;
+                self.reload();
+            }
+        }
+    }
+
+    /// Accepts TCP connections and runs TLS handshakes off the accept path, so
+    /// a slow client does not hold up others. With `proxy`, trusted peers'
+    /// PROXY headers are read before the handshake.
+    pub(crate) fn incoming(
+        &self,
+        listener: TcpListener,
+        proxy: Option<&'static ProxyConfig>,
+    ) -> impl Accept<Conn = TlsConn, Error = io::Error> {
+        let acceptor = TlsAcceptor::from(self.server_config.clone());
+        let timeout = self.config.handshake_timeout;
+        let (tx, rx) = mpsc::channel::<TlsConn>(64);
+        tokio::spawn(async move {
+            loop {
+                let (tcp, remote) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            warn!(error = %err, "failed to accept connection");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                    // The server stopped accepting.
+                    _ = tx.closed() => return,
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut tcp = tcp;
+                    let mut info = ConnInfo::new(Some(remote), tcp.local_addr().ok(), "https");
+                    if let Some(proxy) = proxy {
+                        info = match proxy::accept(proxy, &mut tcp, info).await {
+                            Ok(info) => info,
+                            Err(err) => {
+                                debug!(%remote, error = %err, "rejected PROXY connection");
+                                return;
+                            }
+                        };
+                    }
+                    match tokio::time::timeout(timeout, acceptor.accept(tcp)).await {
+                        Ok(Ok(stream)) => {
+                            let info = info.with_tls(tls_info(stream.get_ref().1));
+                            let _ = tx.send(TlsConn { stream, info }).await;
+                        }
+                        Ok(Err(err)) => debug!(%remote, error = %err, "TLS handshake failed"),
+                        Err(_) => debug!(%remote, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        let conns = futures_util::stream::unfold(rx, |mut rx| async move {
+            let conn = rx.recv().await?;
+            Some((Ok(conn), rx))
+        });
+        hyper::server::accept::from_stream(conns)
+    }
+}
+
+fn tls_info(conn: &ServerConnection) -> TlsInfo {
+    TlsInfo {
+        version: conn.protocol_version().map(|v| match v {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2",
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3",
+            _ => "unknown",
+        }),
+        cipher: conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite())),
+        sni: conn.server_name().map(str::to_string),
+        alpn: conn
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        client: conn
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(client_cert),
+    }
+}
+
+/// An accepted TLS connection and the details Lean sees for it.
+pub struct TlsConn {
+    stream: TlsStream<TcpStream>,
+    info: ConnInfo,
+}
+
+impl Connected<&TlsConn> for ConnInfo {
+    fn connect_info(target: &TlsConn) -> Self {
+        target.info.clone()
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &std::path::Path, name: &str, sans: &[&str]) -> CertPaths {
+        let cert = rcgen::generate_simple_self_signed(
+            sans.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let paths = CertPaths {
+            cert: dir.join(format!("{name}.pem")),
+            key: dir.join(format!("{name}.key")),
+        };
+        std::fs::write(&paths.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&paths.key, cert.serialize_private_key_pem()).unwrap();
+        paths
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lithe-tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn signed_cert(
+        ca: &rcgen::Certificate,
+        dir: &std::path::Path,
+        name: &str,
+        sans: &[&str],
+        client: bool,
+    ) -> CertPaths {
+        let mut params = rcgen::CertificateParams::new(
+            sans.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+        );
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        if client {
+            params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        }
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let paths = CertPaths {
+            cert: dir.join(format!("{name}.pem")),
+            key: dir.join(format!("{name}.key")),
+        };
+        std::fs::write(&paths.cert, cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+        std::fs::write(&paths.key, cert.serialize_private_key_pem()).unwrap();
+        paths
+    }
+
+    fn test_ca(dir: &std::path::Path) -> (rcgen::Certificate, PathBuf) {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "lithe test ca");
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let path = dir.join("ca.pem");
+        std::fs::write(&path, ca.serialize_pem().unwrap()).unwrap();
+        (ca, path)
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        ca: &PathBuf,
+        client: Option<&CertPaths>,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(client_roots(ca)?);
+        let config = match client {
+            Some(paths) => builder
+                .with_client_auth_cert(load_certs(&paths.cert)?, load_key(&paths.key)?)
+                .map_err(|err| invalid(err.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+        let tcp = TcpStream::connect(addr).await?;
+        let name = rustls::ServerName::try_from("localhost").unwrap();
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_requires_a_verified_client_certificate() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = temp_dir("mtls");
+        let (ca, ca_path) = test_ca(&dir);
+        let server = signed_cert(&ca, &dir, "localhost", &["localhost"], false);
+        let client = signed_cert(&ca, &dir, "svc-a", &["svc-a.internal", "10.0.0.1"], true);
+        let other_dir = temp_dir("mtls-other");
+        let (other_ca, _) = test_ca(&other_dir);
+        let stranger = signed_cert(&other_ca, &other_dir, "svc-b", &["svc-b.internal"], true);
+
+        let mut config = TlsConfig::new(vec![server]);
+        config.client_ca = Some(ca_path.clone());
+        let state = TlsState::new(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = std::pin::pin!(state.incoming(listener, None));
+
+        // The server aborts these handshakes; under TLS 1.3 the client only
+        // sees it on its first read.
+        for cert in [None, Some(&stranger)] {
+            if let Ok(mut stream) = connect(addr, &ca_path, cert).await {
+                assert!(stream.read(&mut [0u8; 1]).await.is_err());
+            }
+        }
+
+        let _stream = connect(addr, &ca_path, Some(&client)).await.unwrap();
+        let conn = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures_util::future::poll_fn(|cx| incoming.as_mut().poll_accept(cx)),
+        )
+        .await
+        .expect("accepted connection")
+        .unwrap()
+        .unwrap();
+        let identity = conn.info.tls.as_ref().unwrap().client.clone().unwrap();
+        assert_eq!(identity.subject, "CN=svc-a");
+        assert_eq!(identity.sans, ["DNS:svc-a.internal", "IP:10.0.0.1"]);
+        let der = load_certs(&client.cert).unwrap().remove(0);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &der.0);
+        let hex: String = digest.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(identity.fingerprint, hex);
+        std::fs::remove_dir_all(dir).ok();
+        std::fs::remove_dir_all(other_dir).ok();
+    }
+
+    #[test]
+    fn resolves_by_sni_with_wildcards_and_default() {
+        let dir = temp_dir("sni");
+        let d = write_cert(&dir, "d", &["default.example"]);
+        let a = write_cert(&dir, "a", &["a.example"]);
+        let b = write_cert(&dir, "b", &["*.b.example"]);
+        let set = CertSet::load(&[d, a, b]).unwrap();
+
+        let default = set.resolve(None);
+        let exact = set.resolve(Some("A.example"));
+        assert!(!Arc::ptr_eq(&exact, &default));
+        let wild = set.resolve(Some("x.b.example"));
+        assert!(!Arc::ptr_eq(&wild, &default));
+        assert!(!Arc::ptr_eq(&wild, &exact));
+        assert!(Arc::ptr_eq(&set.resolve(Some("a.example")), &exact));
+        assert!(Arc::ptr_eq(&set.resolve(Some("unknown.example")), &default));
+        assert!(Arc::ptr_eq(&set.resolve(Some("b.example")), &default));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn reload_keeps_old_certificates_on_error() {
+        let dir = temp_dir("reload");
+        let a = write_cert(&dir, "a", &["a.example"]);
+        let state = TlsState::new(TlsConfig::new(vec![a.clone()])).unwrap();
+        let before = state.resolver.current.read().unwrap().default.clone();
+
+        std::fs::write(&a.cert, "not a certificate").unwrap();
+        state.reload();
+        let after = state.resolver.current.read().unwrap().default.clone();
+        assert!(Arc::ptr_eq(&before, &after));
+
+        write_cert(&dir, "a", &["a.example"]);
+        state.reload();
+        let reloaded = state.resolver.current.read().unwrap().default.clone();
+        assert!(!Arc::ptr_eq(&before, &reloaded));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
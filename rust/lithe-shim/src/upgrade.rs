@@ -0,0 +1,163 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::listener::{Listener, Role};
+
+// Where the new process finds the write end of the readiness socket.
+const READY_FD_VAR: &str = "LITHE_UPGRADE_READY_FD";
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Duplicates of the listening sockets, kept so a new binary can take them
+/// over while this process drains.
+#[derive(Debug)]
+pub struct Handoff {
+    fds: Vec<(Role, OwnedFd)>,
+}
+
+impl Handoff {
+    pub fn new(listeners: &[(Role, Listener)]) -> io::Result<Self> {
+        let fds = listeners
+            .iter()
+            .map(|(role, listener)| {
+                let fd = match listener {
+                    Listener::Tcp(l) => l.as_fd().try_clone_to_owned()?,
+                    Listener::Unix(l) => l.as_fd().try_clone_to_owned()?,
+                };
+                Ok((*role, fd))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Handoff { fds })
+    }
+
+    /// Runs `cmd` with the listeners as `LISTEN_FDS`, named by role, and
+    /// waits up to `timeout` for it to call [`UpgradeReady::notify`]. Returns
+    /// the new process id; on failure the new process is killed and this one
+    /// should keep serving.
+    pub async fn spawn(&self, mut cmd: Command, timeout: Duration) -> io::Result<u32> {
+        let (mut ready, ready_tx) = UnixStream::pair()?;
+        let sources: Vec<RawFd> = self
+            .fds
+            .iter()
+            .map(|(_, fd)| fd.as_raw_fd())
+            .chain([ready_tx.as_raw_fd()])
+            .collect();
+        let names: Vec<String> = self.fds.iter().map(|(role, _)| role.to_string()).collect();
+        cmd.env("LISTEN_FDS", self.fds.len().to_string())
+            .env("LISTEN_FDNAMES", names.join(":"))
+            .env_remove("LISTEN_PID")
+            .env(READY_FD_VAR, (LISTEN_FDS_START + self.fds.len() as RawFd).to_string());
+        let mut staged = vec![-1; sources.len()];
+        // SAFETY: the hook only calls fcntl/dup2, which are async-signal-safe,
+        // and writes into buffers allocated before the fork.
+        unsafe {
+            cmd.pre_exec(move || place_fds(&sources, &mut staged));
+        }
+        let mut child = cmd.spawn()?;
+        drop(ready_tx);
+
+        let wait = tokio::task::spawn_blocking(move || {
+            let mut byte = [0u8; 1];
+            ready.read(&mut byte)
+        });
+        let err = match tokio::time::timeout(timeout, wait).await {
+            Ok(Ok(Ok(1))) => return Ok(child.id()),
+            Ok(Ok(Ok(_))) => io::Error::new(io::ErrorKind::UnexpectedEof, "new process exited"),
+            Ok(Ok(Err(err))) => err,
+            Ok(Err(err)) => io::Error::other(err),
+            Err(_) => io::Error::new(io::ErrorKind::TimedOut, "new process did not become ready"),
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(err)
+    }
+}
+
+// Runs in the forked child: moves `sources` to fds 3, 4, ... Everything is
+// first copied above the target range so no dup2 overwrites a later source.
+fn place_fds(sources: &[RawFd], staged: &mut [RawFd]) -> io::Result<()> {
+    let floor = LISTEN_FDS_START + sources.len() as RawFd;
+    for (slot, &fd) in staged.iter_mut().zip(sources) {
+        // SAFETY: plain fd syscalls on descriptors this process owns.
+        *slot = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, floor) };
+        if *slot < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    for (target, &fd) in (LISTEN_FDS_START..).zip(staged.iter()) {
+        // dup2 clears close-on-exec on the target; the staged copies keep it.
+        if unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The readiness socket of the process that started this one for an
+/// upgrade, or `None` unless started by [`Handoff::spawn`]. The variable
+/// naming it is cleared so child processes never take an unrelated fd for
+/// it; call this before starting any threads.
+pub fn upgrade_ready() -> io::Result<Option<UpgradeReady>> {
+    let Ok(fd) = std::env::var(READY_FD_VAR) else {
+        return Ok(None);
+    };
+    std::env::remove_var(READY_FD_VAR);
+    let fd: RawFd = fd.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {READY_FD_VAR} {fd:?}"))
+    })?;
+    // SAFETY: the old process placed its readiness socket at `fd` for us alone.
+    let ready = unsafe { UnixStream::from_raw_fd(fd) };
+    // dup2 left it inheritable; children must not hold it open.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(UpgradeReady(ready)))
+}
+
+/// The write end of an upgrade's readiness socket, from [`upgrade_ready`].
+#[derive(Debug)]
+pub struct UpgradeReady(UnixStream);
+
+impl UpgradeReady {
+    /// Tells the previous process that the listeners are about to be served,
+    /// so it can drain and exit.
+    pub fn notify(mut self) -> io::Result<()> {
+        self.0.write_all(&[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Bind;
+
+    fn handoff() -> Handoff {
+        let listener = Listener::bind(&Bind::Tcp("127.0.0.1:0".parse().unwrap()), None).unwrap();
+        Handoff::new(&[(Role::Http, listener)]).unwrap()
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[tokio::test]
+    async fn new_process_gets_listeners_and_signals_ready() {
+        let script = r#"test "$LISTEN_FDS" = 1 && test "$LISTEN_FDNAMES" = http \
+            && test "$LITHE_UPGRADE_READY_FD" = 4 && printf x >&4"#;
+        let pid = handoff().spawn(sh(script), Duration::from_secs(10)).await;
+        assert!(pid.is_ok(), "{pid:?}");
+    }
+
+    #[tokio::test]
+    async fn failed_upgrade_reports_an_error() {
+        let err = handoff().spawn(sh("exit 1"), Duration::from_secs(10)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = handoff().spawn(sh("exec sleep 5"), Duration::from_millis(100)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,8 +1,15 @@
 use axum::extract::ws::{Message, WebSocket};
+use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
-use std::time::Duration;
+use axum::extract::ws::CloseFrame;
+use std::sync::Arc;
+use tracing::{error, warn};
 
-use crate::{ffi, init_lean, POLL_INTERVAL_MS};
+use crate::ffi::{self, LeanError};
+use crate::lean_bytes::LeanBytes;
+use crate::notify::{self, Waiter};
+use crate::drain::{self, Phase};
+use crate::{handshake, lean_pool, metrics, wire, POLL_BATCH_BYTES};
 
 const WS_PUSH_CLOSED: u64 = 0;
 const WS_PUSH_OK: u64 = 1;
@@ -14,39 +21,55 @@ const WS_KIND_CLOSE: u8 = 2;
 const WS_KIND_PING: u8 = 3;
 const WS_KIND_PONG: u8 = 4;
 
-fn ws_push(ws_id: u64, msg: &[u8]) -> u64 {
-    unsafe {
-        init_lean();
-        let msg_arr = ffi::mk_byte_array(msg);
+async fn ws_push(ws_id: u64, msg: Arc<Vec<u8>>) -> Result<u64, LeanError> {
+    lean_pool::run(move || unsafe {
+        let msg_arr = ffi::mk_byte_array(&msg);
         let res = ffi::lithe_ws_push(ws_id, msg_arr);
         ffi::lithe_lean_dec(msg_arr);
-        ffi::unwrap_io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
-    }
+        ffi::io_result(res, |val| ffi::lithe_lean_unbox_uint64(val))
+    })
+    .await
 }
 
-fn ws_poll(ws_id: u64) -> Option<Vec<u8>> {
-    unsafe {
-        init_lean();
-        let res = ffi::lithe_ws_poll(ws_id);
-        ffi::unwrap_io_result(res, |val| {
-            let bytes = ffi::byte_array_to_vec(val);
-            if bytes.is_empty() {
-                None
-            } else {
-                Some(bytes)
-            }
-        })
-    }
+async fn ws_poll_batch(ws_id: u64) -> Result<Bytes, LeanError> {
+    let batch_poll = handshake::capabilities().batch_poll;
+    lean_pool::run(move || unsafe {
+        if batch_poll {
+            let res = ffi::lithe_ws_poll_batch(ws_id, POLL_BATCH_BYTES);
+            ffi::io_result(res, |val| LeanBytes::from_borrowed(val))
+        } else {
+            let res = ffi::lithe_ws_poll(ws_id);
+            ffi::io_result(res, |val| ffi::byte_array_to_vec(val)).and_then(|msg| {
+                wire::single_frame(&msg).map_err(|message| LeanError { message })
+            })
+        }
+    })
+    .await
 }
 
-fn ws_close(ws_id: u64) {
+pub(crate) fn ws_close(ws_id: u64) {
+    lean_pool::spawn(move || ws_close_now(ws_id));
+}
+
+// Must run on a Lean thread.
+pub(crate) fn ws_close_now(ws_id: u64) {
     unsafe {
-        init_lean();
         let res = ffi::lithe_ws_close(ws_id);
-        ffi::unwrap_io_result(res, |_| ());
+        if let Err(err) = ffi::io_result(res, |_| ()) {
+            metrics::inc_lean_errors();
+            error!(ws_id, error = %err.message, "lean websocket close failed");
+        }
     }
 }
 
+// Sent when the server starts draining, so clients reconnect elsewhere.
+fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: axum::extract::ws::close_code::AWAY,
+        reason: "server shutting down".into(),
+    }))
+}
+
 fn encode_message(msg: Message) -> Option<Vec<u8>> {
     let mut out = Vec::new();
     match msg {
@@ -92,47 +115,92 @@ fn decode_message(bytes: &[u8]) -> Option<Message> {
     }
 }
 
-async fn push_with_backpressure(ws_id: u64, msg: &[u8]) -> bool {
+async fn push_with_backpressure(ws_id: u64, msg: Vec<u8>, waiter: &Waiter) -> bool {
+    let msg = Arc::new(msg);
     loop {
-        match ws_push(ws_id, msg) {
-            WS_PUSH_OK => return true,
-            WS_PUSH_FULL => {
-                tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+        match ws_push(ws_id, msg.clone()).await {
+            Ok(WS_PUSH_OK) => return true,
+            Ok(WS_PUSH_FULL) => waiter.wait().await,
+            Ok(WS_PUSH_CLOSED) => return false,
+            Ok(_) => return false,
+            Err(err) => {
+                metrics::inc_lean_errors();
+                error!(ws_id, error = %err.message, "lean websocket push failed");
+                return false;
             }
-            WS_PUSH_CLOSED => return false,
-            _ => return false,
         }
     }
 }
 
-pub async fn handle_socket(socket: WebSocket, ws_id: u64) {
-    init_lean();
+pub async fn handle_socket(socket: WebSocket, app_id: u64, ws_id: u64) {
+    let _session = drain::register(app_id, drain::Session::WebSocket(ws_id));
     let (mut sender, mut receiver) = socket.split();
+    let out_waiter = Waiter::new(notify::WS_OUT, ws_id);
+    let in_waiter = Waiter::new(notify::WS_IN, ws_id);
+    let mut phase = drain::phase(app_id);
+    let mut send_phase = phase.clone();
 
     let send_task = tokio::spawn(async move {
-        loop {
-            if let Some(bytes) = ws_poll(ws_id) {
+        'poll: loop {
+            if *send_phase.borrow() >= Phase::Draining {
+                // The receive loop keeps running so the client's close reply
+                // still reaches Lean.
+                let _ = sender.send(going_away()).await;
+                break;
+            }
+            let batch = match ws_poll_batch(ws_id).await {
+                Ok(batch) => batch,
+                Err(err) => {
+                    metrics::inc_lean_errors();
+                    error!(ws_id, error = %err.message, "lean websocket poll failed");
+                    break;
+                }
+            };
+            let frames = match wire::decode_frames(&batch) {
+                Ok(frames) => frames,
+                Err(err) => {
+                    warn!(ws_id, error = %err, "failed to decode websocket batch");
+                    break;
+                }
+            };
+            if frames.is_empty() {
+                tokio::select! {
+                    _ = out_waiter.wait() => {}
+                    _ = send_phase.wait_for(|phase| *phase >= Phase::Draining) => {}
+                }
+                continue;
+            }
+            for bytes in frames {
                 if let Some(msg) = decode_message(&bytes) {
                     let is_close = matches!(msg, Message::Close(_));
-                    if sender.send(msg).await.is_err() {
-                        break;
+                    if sender.feed(msg).await.is_err() {
+                        break 'poll;
                     }
                     if is_close {
-                        break;
+                        let _ = sender.flush().await;
+                        break 'poll;
                     }
                 }
-            } else {
-                tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+            }
+            if sender.flush().await.is_err() {
+                break;
             }
         }
     });
 
-    while let Some(msg) = receiver.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => msg,
+            _ = phase.wait_for(|phase| *phase == Phase::Forced) => break,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         match msg {
             Ok(msg) => {
                 let is_close = matches!(msg, Message::Close(_));
                 if let Some(encoded) = encode_message(msg) {
-                    if !push_with_backpressure(ws_id, &encoded).await {
+                    if !push_with_backpressure(ws_id, encoded, &in_waiter).await {
                         break;
                     }
                 }
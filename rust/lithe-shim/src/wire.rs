@@ -1,43 +1,233 @@
-use hyper::http::Method;
+use bytes::Bytes;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue, Method, Uri, Version};
+use std::net::SocketAddr;
+use std::path::Path;
 
-pub const WIRE_VERSION: u8 = 1;
-pub const STREAM_WIRE_VERSION: u8 = 1;
+use crate::conn::{ProxyInfo, TlsInfo};
+
+/// Original encoding: `u32` length prefixes and literal header names.
+pub const WIRE_VERSION_V1: u8 = 1;
+/// Compact encoding: varint length prefixes and indexed common header names.
+pub const WIRE_VERSION: u8 = 2;
+pub const STREAM_WIRE_VERSION_V1: u8 = 1;
+pub const STREAM_WIRE_VERSION: u8 = 2;
 pub const STREAM_MSG_HEAD: u8 = 1;
 pub const STREAM_MSG_CHUNK: u8 = 2;
 pub const STREAM_MSG_END: u8 = 3;
 
+/// Header names sent as an index in v2: `i + 1` names `HEADER_TABLE[i]` and
+/// `0` introduces a literal name. Must match `headerTable` in
+/// `Lithe/Codec/Wire.lean`.
+pub const HEADER_TABLE: [&str; 32] = [
+    "host",
+    "user-agent",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "connection",
+    "content-type",
+    "content-length",
+    "cookie",
+    "set-cookie",
+    "cache-control",
+    "authorization",
+    "referer",
+    "origin",
+    "upgrade",
+    "location",
+    "date",
+    "etag",
+    "last-modified",
+    "if-none-match",
+    "if-modified-since",
+    "x-forwarded-for",
+    "x-request-id",
+    "transfer-encoding",
+    "content-encoding",
+    "vary",
+    "server",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-accept",
+    "access-control-allow-origin",
+    "x-lithe-ws-id",
+];
+
+// Tags of the v2 request metadata section. Must match `meta*` in
+// `Lithe/Codec/Wire.lean`; Lean skips tags it does not know.
+const META_CONN_ID: u64 = 1;
+const META_LOCAL_ADDR: u64 = 2;
+const META_SCHEME: u64 = 3;
+const META_HTTP_VERSION: u64 = 4;
+const META_RAW_URI: u64 = 5;
+const META_TLS_VERSION: u64 = 6;
+const META_TLS_CIPHER: u64 = 7;
+const META_TLS_SNI: u64 = 8;
+const META_TLS_ALPN: u64 = 9;
+const META_CLIENT_SUBJECT: u64 = 10;
+// Repeated once per subject alternative name.
+const META_CLIENT_SAN: u64 = 11;
+const META_CLIENT_FINGERPRINT: u64 = 12;
+const META_PROXY_PEER: u64 = 13;
+const META_PROXY_AUTHORITY: u64 = 14;
+const META_PROXY_UNIQUE_ID: u64 = 15;
+const META_DEADLINE: u64 = 16;
+
+/// Layout version of the `lithe_handshake` payload; must match Lean's
+/// `handshakeVersion`.
+pub const HANDSHAKE_VERSION: u8 = 1;
+// Capability bits; must match `cap*` in `Lithe/Codec/Wire.lean`.
+pub const CAP_BATCH_POLL: u32 = 1;
+pub const CAP_NOTIFY: u32 = 2;
+pub const CAP_EXT_METHODS: u32 = 8;
+pub const CAP_RAW_HEADERS: u32 = 16;
+
+/// What the Lean library reports from `lithe_handshake`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeOffer {
+    pub library_version: String,
+    pub wire_min: u8,
+    pub wire_max: u8,
+    pub stream_wire_min: u8,
+    pub stream_wire_max: u8,
+    pub capabilities: u32,
+}
+
+/// Transport details sent after the request body; `None` fields are omitted.
+#[derive(Clone, Debug, Default)]
+pub struct RequestMeta<'a> {
+    pub conn_id: Option<u64>,
+    pub local: Option<SocketAddr>,
+    /// Sent as the local address, `unix:<path>`, when `local` is `None`.
+    pub local_path: Option<&'a Path>,
+    pub scheme: Option<&'a str>,
+    pub version: Option<Version>,
+    pub raw_uri: Option<&'a Uri>,
+    pub tls: Option<&'a TlsInfo>,
+    pub proxy: Option<&'a ProxyInfo>,
+    /// When the shim gives up on the response, on Lean's `IO.monoNanosNow` clock.
+    pub deadline_nanos: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct WireResponse {
     pub status: u16,
-    pub headers: Vec<(String, String)>,
-    pub body: Vec<u8>,
+    pub headers: WireHeaders,
+    pub body: Bytes,
 }
 
 #[derive(Debug)]
 pub enum StreamMsg {
     Head {
         status: u16,
-        headers: Vec<(String, String)>,
+        headers: WireHeaders,
         is_stream: bool,
-        body: Vec<u8>,
+        body: Bytes,
     },
-    Chunk(Vec<u8>),
+    Chunk(Bytes),
     End,
 }
 
-fn method_to_u8(method: &Method) -> Result<u8, String> {
+/// A header block left encoded in the wire buffer. Names and values are
+/// produced on iteration; values share the buffer instead of copying it.
+#[derive(Clone, Debug)]
+pub struct WireHeaders {
+    block: Bytes,
+    count: usize,
+    compact: bool,
+}
+
+impl WireHeaders {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> WireHeaderIter<'_> {
+        WireHeaderIter {
+            headers: self,
+            reader: Reader {
+                data: &self.block,
+                pos: 0,
+                compact: self.compact,
+            },
+            remaining: self.count,
+        }
+    }
+
+    /// Returns the first valid value for `name`, which must be lowercase.
+    pub fn get(&self, name: &str) -> Option<HeaderValue> {
+        self.iter()
+            .filter_map(Result::ok)
+            .find(|(k, _)| k.as_str() == name)
+            .map(|(_, v)| v)
+    }
+}
+
+pub struct WireHeaderIter<'a> {
+    headers: &'a WireHeaders,
+    reader: Reader<'a>,
+    remaining: usize,
+}
+
+impl WireHeaderIter<'_> {
+    fn read_header(&mut self) -> Result<(HeaderName, HeaderValue), String> {
+        let name = self.reader.read_header_name()?;
+        let value = self.reader.read_bytes()?;
+        let name = match name {
+            RawName::Indexed(name) => HeaderName::from_static(name),
+            RawName::Literal(name) => {
+                HeaderName::from_bytes(name).map_err(|_| "invalid header name".to_string())?
+            }
+        };
+        let value = HeaderValue::from_maybe_shared(self.headers.block.slice_ref(value))
+            .map_err(|_| format!("invalid value for header {name}"))?;
+        Ok((name, value))
+    }
+}
+
+impl Iterator for WireHeaderIter<'_> {
+    /// Invalid names or values are reported per header; the rest still decode.
+    type Item = Result<(HeaderName, HeaderValue), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.read_header())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// Method code for any method outside `Lithe.Method`'s fixed variants; the
+/// name follows as a string. Must match `Method.extCode`.
+pub const METHOD_EXT: u8 = 255;
+
+fn method_to_u8(method: &Method) -> u8 {
     match *method {
-        Method::GET => Ok(0),
-        Method::POST => Ok(1),
-        Method::PUT => Ok(2),
-        Method::PATCH => Ok(3),
-        Method::DELETE => Ok(4),
-        Method::OPTIONS => Ok(5),
-        Method::HEAD => Ok(6),
-        _ => Err(format!("unsupported method {}", method)),
+        Method::GET => 0,
+        Method::POST => 1,
+        Method::PUT => 2,
+        Method::PATCH => 3,
+        Method::DELETE => 4,
+        Method::OPTIONS => 5,
+        Method::HEAD => 6,
+        _ => METHOD_EXT,
     }
 }
 
+/// Whether `method` is sent as an extension method (by name).
+pub fn is_ext_method(method: &Method) -> bool {
+    method_to_u8(method) == METHOD_EXT
+}
+
 fn write_u8(buf: &mut Vec<u8>, v: u8) {
     buf.push(v);
 }
@@ -51,67 +241,216 @@ fn write_u32(buf: &mut Vec<u8>, v: u32) {
     buf.extend_from_slice(&v.to_be_bytes());
 }
 
-fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), String> {
-    let len = bytes.len();
-    if len > u32::MAX as usize {
-        return Err("value too large".to_string());
-    }
-    write_u32(buf, len as u32);
+fn write_bytes_v1(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), String> {
+    let len = u32::try_from(bytes.len()).map_err(|_| "value too large".to_string())?;
+    write_u32(buf, len);
     buf.extend_from_slice(bytes);
     Ok(())
 }
 
-fn write_string(buf: &mut Vec<u8>, s: &str) -> Result<(), String> {
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
     write_bytes(buf, s.as_bytes())
 }
 
-fn write_opt_string(buf: &mut Vec<u8>, s: Option<&str>) -> Result<(), String> {
+fn write_opt_string(buf: &mut Vec<u8>, s: Option<&str>) {
     match s {
+        None => write_u8(buf, 0),
+        Some(v) => {
+            write_u8(buf, 1);
+            write_string(buf, v);
+        }
+    }
+}
+
+fn write_header_name(buf: &mut Vec<u8>, name: &HeaderName) {
+    match HEADER_TABLE.iter().position(|n| *n == name.as_str()) {
+        Some(i) => write_varint(buf, i as u64 + 1),
         None => {
-            write_u8(buf, 0);
-            Ok(())
+            write_varint(buf, 0);
+            write_string(buf, name.as_str());
         }
+    }
+}
+
+fn write_request_meta(buf: &mut Vec<u8>, meta: &RequestMeta<'_>) {
+    let mut entries: Vec<(u64, Vec<u8>)> = Vec::with_capacity(9);
+    if let Some(id) = meta.conn_id {
+        let mut value = Vec::new();
+        write_varint(&mut value, id);
+        entries.push((META_CONN_ID, value));
+    }
+    if let Some(local) = meta.local {
+        entries.push((META_LOCAL_ADDR, local.to_string().into_bytes()));
+    } else if let Some(path) = meta.local_path {
+        entries.push((META_LOCAL_ADDR, format!("unix:{}", path.display()).into_bytes()));
+    }
+    if let Some(scheme) = meta.scheme {
+        entries.push((META_SCHEME, scheme.as_bytes().to_vec()));
+    }
+    if let Some(version) = meta.version {
+        entries.push((META_HTTP_VERSION, format!("{version:?}").into_bytes()));
+    }
+    if let Some(uri) = meta.raw_uri {
+        entries.push((META_RAW_URI, uri.to_string().into_bytes()));
+    }
+    if let Some(tls) = meta.tls {
+        let fields = [
+            (META_TLS_VERSION, tls.version),
+            (META_TLS_CIPHER, tls.cipher.as_deref()),
+            (META_TLS_SNI, tls.sni.as_deref()),
+            (META_TLS_ALPN, tls.alpn.as_deref()),
+        ];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                entries.push((tag, value.as_bytes().to_vec()));
+            }
+        }
+        if let Some(client) = &tls.client {
+            entries.push((META_CLIENT_SUBJECT, client.subject.as_bytes().to_vec()));
+            for san in &client.sans {
+                entries.push((META_CLIENT_SAN, san.as_bytes().to_vec()));
+            }
+            entries.push((META_CLIENT_FINGERPRINT, client.fingerprint.as_bytes().to_vec()));
+        }
+    }
+    if let Some(proxy) = meta.proxy {
+        entries.push((META_PROXY_PEER, proxy.peer.to_string().into_bytes()));
+        let fields = [
+            (META_PROXY_AUTHORITY, proxy.authority.as_deref()),
+            (META_PROXY_UNIQUE_ID, proxy.unique_id.as_deref()),
+        ];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                entries.push((tag, value.as_bytes().to_vec()));
+            }
+        }
+    }
+    if let Some(deadline) = meta.deadline_nanos {
+        let mut value = Vec::new();
+        write_varint(&mut value, deadline);
+        entries.push((META_DEADLINE, value));
+    }
+    write_varint(buf, entries.len() as u64);
+    for (tag, value) in entries {
+        write_varint(buf, tag);
+        write_bytes(buf, &value);
+    }
+}
+
+// The v1 layout, for Lean libraries that predate v2; `meta` is not sent.
+fn encode_request_v1(
+    method: &Method,
+    path: &str,
+    query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    remote: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    write_u8(&mut buf, WIRE_VERSION_V1);
+    write_u8(&mut buf, method_to_u8(method));
+    if is_ext_method(method) {
+        write_bytes_v1(&mut buf, method.as_str().as_bytes())?;
+    }
+    write_bytes_v1(&mut buf, path.as_bytes())?;
+    write_bytes_v1(&mut buf, query.as_bytes())?;
+    write_u32(&mut buf, u32::try_from(headers.len()).map_err(|_| "too many headers".to_string())?);
+    for (k, v) in headers {
+        write_bytes_v1(&mut buf, k.as_str().as_bytes())?;
+        write_bytes_v1(&mut buf, v.as_bytes())?;
+    }
+    write_bytes_v1(&mut buf, body)?;
+    match remote {
+        None => write_u8(&mut buf, 0),
         Some(v) => {
-            write_u8(buf, 1);
-            write_string(buf, v)
+            write_u8(&mut buf, 1);
+            write_bytes_v1(&mut buf, v.as_bytes())?;
         }
     }
+    Ok(buf)
 }
 
+/// Encodes a request in wire `version` (v1 or v2). Every header is sent, in
+/// `headers`' iteration order, with its value as raw bytes.
+#[allow(clippy::too_many_arguments)]
 pub fn encode_request(
+    version: u8,
     method: &Method,
     path: &str,
     query: &str,
-    headers: &[(String, String)],
+    headers: &HeaderMap,
     body: &[u8],
     remote: Option<&str>,
+    meta: &RequestMeta<'_>,
 ) -> Result<Vec<u8>, String> {
-    let mut buf = Vec::new();
+    match version {
+        WIRE_VERSION_V1 => return encode_request_v1(method, path, query, headers, body, remote),
+        WIRE_VERSION => {}
+        _ => return Err(format!("unsupported wire version {version}")),
+    }
+    let mut buf = Vec::with_capacity(64 + path.len() + query.len() + headers.len() * 32 + body.len());
     write_u8(&mut buf, WIRE_VERSION);
-    write_u8(&mut buf, method_to_u8(method)?);
-    write_string(&mut buf, path)?;
-    write_string(&mut buf, query)?;
-    if headers.len() > u32::MAX as usize {
-        return Err("too many headers".to_string());
+    write_u8(&mut buf, method_to_u8(method));
+    if is_ext_method(method) {
+        write_string(&mut buf, method.as_str());
     }
-    write_u32(&mut buf, headers.len() as u32);
+    write_string(&mut buf, path);
+    write_string(&mut buf, query);
+    write_varint(&mut buf, headers.len() as u64);
     for (k, v) in headers {
-        write_string(&mut buf, k)?;
-        write_string(&mut buf, v)?;
+        write_header_name(&mut buf, k);
+        write_bytes(&mut buf, v.as_bytes());
     }
-    write_bytes(&mut buf, body)?;
-    write_opt_string(&mut buf, remote)?;
+    write_bytes(&mut buf, body);
+    write_opt_string(&mut buf, remote);
+    write_request_meta(&mut buf, meta);
     Ok(buf)
 }
 
+enum RawName<'a> {
+    Indexed(&'static str),
+    Literal(&'a [u8]),
+}
+
 struct Reader<'a> {
     data: &'a [u8],
     pos: usize,
+    // v2 encoding; set from the version byte.
+    compact: bool,
 }
 
 impl<'a> Reader<'a> {
     fn new(data: &'a [u8]) -> Self {
-        Self { data, pos: 0 }
+        Self {
+            data,
+            pos: 0,
+            compact: false,
+        }
+    }
+
+    fn read_version(&mut self, v1: u8, v2: u8, what: &str) -> Result<u8, String> {
+        let ver = self.read_u8()?;
+        if ver == v1 {
+            self.compact = false;
+        } else if ver == v2 {
+            self.compact = true;
+        } else {
+            return Err(format!("unsupported {what} version {ver}"));
+        }
+        Ok(ver)
     }
 
     fn read_u8(&mut self) -> Result<u8, String> {
@@ -137,37 +476,79 @@ impl<'a> Reader<'a> {
         Ok(u32::from_be_bytes([b1, b2, b3, b4]))
     }
 
-    fn read_bytes(&mut self) -> Result<Vec<u8>, String> {
-        let len = self.read_u32()? as usize;
-        if self.pos + len > self.data.len() {
+    fn read_varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.read_u8()?;
+            value |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long".to_string())
+    }
+
+    // A length or count: `u32` in v1, varint in v2.
+    fn read_len(&mut self) -> Result<usize, String> {
+        if self.compact {
+            let n = self.read_varint()?;
+            usize::try_from(n).map_err(|_| "length too large".to_string())
+        } else {
+            Ok(self.read_u32()? as usize)
+        }
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.read_len()?;
+        if len > self.data.len() - self.pos {
             return Err("unexpected eof".to_string());
         }
-        let out = self.data[self.pos..self.pos + len].to_vec();
+        let out = &self.data[self.pos..self.pos + len];
         self.pos += len;
         Ok(out)
     }
 
-    fn read_string(&mut self) -> Result<String, String> {
-        let bytes = self.read_bytes()?;
-        String::from_utf8(bytes).map_err(|_| "invalid utf-8".to_string())
+    fn read_header_name(&mut self) -> Result<RawName<'a>, String> {
+        if !self.compact {
+            return self.read_bytes().map(RawName::Literal);
+        }
+        match self.read_varint()? {
+            0 => self.read_bytes().map(RawName::Literal),
+            idx => HEADER_TABLE
+                .get(idx as usize - 1)
+                .map(|name| RawName::Indexed(name))
+                .ok_or_else(|| format!("unknown header index {idx}")),
+        }
+    }
+
+    // Checks the framing of `count` headers and returns the bytes they span.
+    fn read_header_block(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let start = self.pos;
+        for _ in 0..count {
+            self.read_header_name()?;
+            self.read_bytes()?;
+        }
+        Ok(&self.data[start..self.pos])
+    }
+
+    fn read_headers(&mut self, src: &Bytes) -> Result<WireHeaders, String> {
+        let count = self.read_len()?;
+        let block = self.read_header_block(count)?;
+        Ok(WireHeaders {
+            block: src.slice_ref(block),
+            count,
+            compact: self.compact,
+        })
     }
 }
 
-pub fn decode_response(bytes: &[u8]) -> Result<WireResponse, String> {
+/// Accepts v1 and v2. Headers and body stay in `bytes`; nothing is copied.
+pub fn decode_response(bytes: &Bytes) -> Result<WireResponse, String> {
     let mut r = Reader::new(bytes);
-    let ver = r.read_u8()?;
-    if ver != WIRE_VERSION {
-        return Err(format!("unsupported wire version {ver}"));
-    }
+    r.read_version(WIRE_VERSION_V1, WIRE_VERSION, "wire")?;
     let status = r.read_u16()?;
-    let header_count = r.read_u32()? as usize;
-    let mut headers = Vec::with_capacity(header_count);
-    for _ in 0..header_count {
-        let k = r.read_string()?;
-        let v = r.read_string()?;
-        headers.push((k, v));
-    }
-    let body = r.read_bytes()?;
+    let headers = r.read_headers(bytes)?;
+    let body = bytes.slice_ref(r.read_bytes()?);
     Ok(WireResponse {
         status,
         headers,
@@ -175,25 +556,17 @@ pub fn decode_response(bytes: &[u8]) -> Result<WireResponse, String> {
     })
 }
 
-pub fn decode_stream_msg(bytes: &[u8]) -> Result<StreamMsg, String> {
+/// Accepts v1 and v2. Headers and bodies stay in `bytes`; nothing is copied.
+pub fn decode_stream_msg(bytes: &Bytes) -> Result<StreamMsg, String> {
     let mut r = Reader::new(bytes);
-    let ver = r.read_u8()?;
-    if ver != STREAM_WIRE_VERSION {
-        return Err(format!("unsupported stream wire version {ver}"));
-    }
+    r.read_version(STREAM_WIRE_VERSION_V1, STREAM_WIRE_VERSION, "stream wire")?;
     let kind = r.read_u8()?;
     match kind {
         STREAM_MSG_HEAD => {
             let status = r.read_u16()?;
             let is_stream = r.read_u8()? != 0;
-            let header_count = r.read_u32()? as usize;
-            let mut headers = Vec::with_capacity(header_count);
-            for _ in 0..header_count {
-                let k = r.read_string()?;
-                let v = r.read_string()?;
-                headers.push((k, v));
-            }
-            let body = r.read_bytes()?;
+            let headers = r.read_headers(bytes)?;
+            let body = bytes.slice_ref(r.read_bytes()?);
             Ok(StreamMsg::Head {
                 status,
                 headers,
@@ -202,7 +575,7 @@ pub fn decode_stream_msg(bytes: &[u8]) -> Result<StreamMsg, String> {
             })
         }
         STREAM_MSG_CHUNK => {
-            let body = r.read_bytes()?;
+            let body = bytes.slice_ref(r.read_bytes()?);
             Ok(StreamMsg::Chunk(body))
         }
         STREAM_MSG_END => Ok(StreamMsg::End),
@@ -210,39 +583,306 @@ pub fn decode_stream_msg(bytes: &[u8]) -> Result<StreamMsg, String> {
     }
 }
 
+/// The handshake always uses the v1 layout, whatever versions it offers.
+pub fn decode_handshake(bytes: &[u8]) -> Result<HandshakeOffer, String> {
+    let mut r = Reader::new(bytes);
+    let version = r.read_u8()?;
+    if version != HANDSHAKE_VERSION {
+        return Err(format!("unsupported handshake version {version}"));
+    }
+    let library_version = std::str::from_utf8(r.read_bytes()?)
+        .map_err(|_| "invalid utf-8".to_string())?
+        .to_string();
+    Ok(HandshakeOffer {
+        library_version,
+        wire_min: r.read_u8()?,
+        wire_max: r.read_u8()?,
+        stream_wire_min: r.read_u8()?,
+        stream_wire_max: r.read_u8()?,
+        capabilities: r.read_u32()?,
+    })
+}
+
+/// Frames a single polled message like a batched poll; empty stays empty.
+pub fn single_frame(msg: &[u8]) -> Result<Bytes, String> {
+    if msg.is_empty() {
+        return Ok(Bytes::new());
+    }
+    let mut buf = Vec::with_capacity(msg.len() + 4);
+    write_bytes_v1(&mut buf, msg)?;
+    Ok(Bytes::from(buf))
+}
+
+/// Splits a batched poll into its `u32`-length-prefixed frames, without copying.
+pub fn decode_frames(bytes: &Bytes) -> Result<Vec<Bytes>, String> {
+    let mut r = Reader::new(bytes);
+    let mut frames = Vec::new();
+    while r.pos < r.data.len() {
+        frames.push(bytes.slice_ref(r.read_bytes()?));
+    }
+    Ok(frames)
+}
+
+/// Decodes every message of a batched stream poll, in order.
+pub fn decode_stream_msgs(bytes: &Bytes) -> Result<Vec<StreamMsg>, String> {
+    decode_frames(bytes)?.iter().map(decode_stream_msg).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn header_pairs(headers: &WireHeaders) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|h| {
+                let (k, v) = h.expect("valid header");
+                (k.to_string(), v.to_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
     #[test]
     fn decode_response_roundtrip() {
         let mut buf = Vec::new();
-        write_u8(&mut buf, WIRE_VERSION);
+        write_u8(&mut buf, WIRE_VERSION_V1);
         write_u16(&mut buf, 201);
         write_u32(&mut buf, 1);
-        write_string(&mut buf, "x-test").unwrap();
-        write_string(&mut buf, "true").unwrap();
-        write_bytes(&mut buf, b"ok").unwrap();
+        write_bytes_v1(&mut buf, b"x-test").unwrap();
+        write_bytes_v1(&mut buf, b"true").unwrap();
+        write_bytes_v1(&mut buf, b"ok").unwrap();
 
-        let resp = decode_response(&buf).expect("decode response");
+        let resp = decode_response(&Bytes::from(buf)).expect("decode response");
         assert_eq!(resp.status, 201);
-        assert_eq!(resp.headers, vec![("x-test".to_string(), "true".to_string())]);
-        assert_eq!(resp.body, b"ok");
+        assert_eq!(
+            header_pairs(&resp.headers),
+            vec![("x-test".to_string(), "true".to_string())]
+        );
+        assert_eq!(resp.body, &b"ok"[..]);
+    }
+
+    #[test]
+    fn decode_response_v2() {
+        let mut buf = Vec::new();
+        write_u8(&mut buf, WIRE_VERSION);
+        write_u16(&mut buf, 200);
+        write_varint(&mut buf, 3);
+        write_varint(&mut buf, 7);
+        write_string(&mut buf, "text/plain");
+        write_varint(&mut buf, 0);
+        write_string(&mut buf, "x-custom");
+        write_string(&mut buf, "1");
+        write_varint(&mut buf, 10);
+        write_string(&mut buf, "a=b");
+        write_bytes(&mut buf, &vec![b'x'; 300]);
+        let buf = Bytes::from(buf);
+
+        let resp = decode_response(&buf).expect("decode response");
+        assert_eq!(resp.status, 200);
+        assert_eq!(
+            header_pairs(&resp.headers),
+            vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("x-custom".to_string(), "1".to_string()),
+                ("set-cookie".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert_eq!(resp.headers.get("x-custom").unwrap(), "1");
+        assert!(resp.headers.get("x-missing").is_none());
+        assert_eq!(resp.body.len(), 300);
+        assert_eq!(resp.body.as_ptr(), buf[buf.len() - 300..].as_ptr());
+    }
+
+    #[test]
+    fn decode_response_v2_rejects_unknown_index() {
+        let mut buf = Vec::new();
+        write_u8(&mut buf, WIRE_VERSION);
+        write_u16(&mut buf, 200);
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, HEADER_TABLE.len() as u64 + 1);
+        write_string(&mut buf, "v");
+        write_bytes(&mut buf, b"");
+        assert!(decode_response(&Bytes::from(buf)).is_err());
+    }
+
+    #[test]
+    fn invalid_header_value_is_reported_per_header() {
+        let mut buf = Vec::new();
+        write_u8(&mut buf, WIRE_VERSION);
+        write_u16(&mut buf, 200);
+        write_varint(&mut buf, 2);
+        write_varint(&mut buf, 0);
+        write_string(&mut buf, "x-bad");
+        write_string(&mut buf, "a\nb");
+        write_varint(&mut buf, 0);
+        write_string(&mut buf, "x-good");
+        write_string(&mut buf, "ok");
+        write_bytes(&mut buf, b"");
+
+        let resp = decode_response(&Bytes::from(buf)).expect("decode response");
+        let decoded: Vec<_> = resp.headers.iter().collect();
+        assert!(decoded[0].is_err());
+        assert_eq!(decoded[1].as_ref().unwrap().1, "ok");
+    }
+
+    #[test]
+    fn encode_request_v2() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        headers.insert("x-custom", HeaderValue::from_static("1"));
+        headers.append("x-custom", HeaderValue::from_static("2"));
+        headers.insert("x-binary", HeaderValue::from_bytes(b"\xff").unwrap());
+        let uri: Uri = "/p?q=1".parse().unwrap();
+        let meta = RequestMeta {
+            conn_id: Some(300),
+            scheme: Some("https"),
+            version: Some(Version::HTTP_2),
+            raw_uri: Some(&uri),
+            deadline_nanos: Some(5_000_000_000),
+            ..Default::default()
+        };
+        let buf = encode_request(
+            WIRE_VERSION,
+            &Method::POST,
+            "/p",
+            "q=1",
+            &headers,
+            b"body",
+            Some("1.2.3.4:5"),
+            &meta,
+        )
+        .expect("encode request");
+
+        let mut r = Reader::new(&buf);
+        r.read_version(WIRE_VERSION_V1, WIRE_VERSION, "wire").unwrap();
+        assert!(r.compact);
+        assert_eq!(r.read_u8().unwrap(), 1);
+        assert_eq!(r.read_bytes().unwrap(), b"/p");
+        assert_eq!(r.read_bytes().unwrap(), b"q=1");
+        assert_eq!(r.read_len().unwrap(), 4);
+        assert!(matches!(r.read_header_name().unwrap(), RawName::Indexed("content-type")));
+        assert_eq!(r.read_bytes().unwrap(), b"text/plain");
+        assert!(matches!(r.read_header_name().unwrap(), RawName::Literal(b"x-custom")));
+        assert_eq!(r.read_bytes().unwrap(), b"1");
+        assert!(matches!(r.read_header_name().unwrap(), RawName::Literal(b"x-custom")));
+        assert_eq!(r.read_bytes().unwrap(), b"2");
+        assert!(matches!(r.read_header_name().unwrap(), RawName::Literal(b"x-binary")));
+        assert_eq!(r.read_bytes().unwrap(), b"\xff");
+        assert_eq!(r.read_bytes().unwrap(), b"body");
+        assert_eq!(r.read_u8().unwrap(), 1);
+        assert_eq!(r.read_bytes().unwrap(), b"1.2.3.4:5");
+        assert_eq!(r.read_varint().unwrap(), 5);
+        assert_eq!(r.read_varint().unwrap(), META_CONN_ID);
+        assert_eq!(Reader::new(r.read_bytes().unwrap()).read_varint().unwrap(), 300);
+        assert_eq!(r.read_varint().unwrap(), META_SCHEME);
+        assert_eq!(r.read_bytes().unwrap(), b"https");
+        assert_eq!(r.read_varint().unwrap(), META_HTTP_VERSION);
+        assert_eq!(r.read_bytes().unwrap(), b"HTTP/2.0");
+        assert_eq!(r.read_varint().unwrap(), META_RAW_URI);
+        assert_eq!(r.read_bytes().unwrap(), b"/p?q=1");
+        assert_eq!(r.read_varint().unwrap(), META_DEADLINE);
+        let deadline = r.read_bytes().unwrap();
+        assert_eq!(Reader::new(deadline).read_varint().unwrap(), 5_000_000_000);
+        assert_eq!(r.pos, buf.len());
+    }
+
+    #[test]
+    fn encode_request_v1_omits_meta() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        let meta = RequestMeta {
+            conn_id: Some(1),
+            ..Default::default()
+        };
+        let buf = encode_request(WIRE_VERSION_V1, &Method::GET, "/", "", &headers, b"", None, &meta)
+            .expect("encode request");
+
+        let mut r = Reader::new(&buf);
+        r.read_version(WIRE_VERSION_V1, WIRE_VERSION, "wire").unwrap();
+        assert!(!r.compact);
+        assert_eq!(r.read_u8().unwrap(), 0);
+        assert_eq!(r.read_bytes().unwrap(), b"/");
+        assert_eq!(r.read_bytes().unwrap(), b"");
+        assert_eq!(r.read_len().unwrap(), 1);
+        assert!(matches!(r.read_header_name().unwrap(), RawName::Literal(b"content-type")));
+        assert_eq!(r.read_bytes().unwrap(), b"text/plain");
+        assert_eq!(r.read_bytes().unwrap(), b"");
+        assert_eq!(r.read_u8().unwrap(), 0);
+        assert_eq!(r.pos, buf.len());
+
+        assert!(encode_request(9, &Method::GET, "/", "", &headers, b"", None, &meta).is_err());
+    }
+
+    #[test]
+    fn decode_handshake_v1_layout() {
+        let mut buf = Vec::new();
+        write_u8(&mut buf, HANDSHAKE_VERSION);
+        write_bytes_v1(&mut buf, b"0.1.0").unwrap();
+        buf.extend_from_slice(&[1, 2, 1, 2]);
+        write_u32(&mut buf, CAP_BATCH_POLL | CAP_NOTIFY);
+
+        let offer = decode_handshake(&buf).expect("decode handshake");
+        assert_eq!(offer.library_version, "0.1.0");
+        assert_eq!((offer.wire_min, offer.wire_max), (1, 2));
+        assert_eq!((offer.stream_wire_min, offer.stream_wire_max), (1, 2));
+        assert_eq!(offer.capabilities, CAP_BATCH_POLL | CAP_NOTIFY);
+
+        buf[0] = 9;
+        assert!(decode_handshake(&buf).is_err());
+        assert!(decode_handshake(&buf[..3]).is_err());
+    }
+
+    #[test]
+    fn single_frame_matches_batch_layout() {
+        assert!(single_frame(b"").unwrap().is_empty());
+        let framed = single_frame(b"abc").unwrap();
+        let frames = decode_frames(&framed).unwrap();
+        assert_eq!(frames, vec![Bytes::from_static(b"abc")]);
+    }
+
+    #[test]
+    fn encode_request_ext_method() {
+        let method = Method::from_bytes(b"PROPFIND").unwrap();
+        let meta = RequestMeta::default();
+        for version in [WIRE_VERSION_V1, WIRE_VERSION] {
+            let buf = encode_request(version, &method, "/dav", "", &HeaderMap::new(), b"", None, &meta)
+                .expect("encode request");
+            let mut r = Reader::new(&buf);
+            r.read_version(WIRE_VERSION_V1, WIRE_VERSION, "wire").unwrap();
+            assert_eq!(r.read_u8().unwrap(), METHOD_EXT);
+            assert_eq!(r.read_bytes().unwrap(), b"PROPFIND");
+            assert_eq!(r.read_bytes().unwrap(), b"/dav");
+        }
+        assert!(!is_ext_method(&Method::GET));
+        assert!(is_ext_method(&Method::TRACE));
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for v in [0u64, 1, 127, 128, 300, 16_383, 16_384, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            let mut r = Reader::new(&buf);
+            assert_eq!(r.read_varint().unwrap(), v);
+            assert_eq!(r.pos, buf.len());
+        }
+        let mut r = Reader::new(&[0xff; 11]);
+        assert!(r.read_varint().is_err());
     }
 
     #[test]
     fn decode_stream_messages() {
         let mut head = Vec::new();
-        write_u8(&mut head, STREAM_WIRE_VERSION);
+        write_u8(&mut head, STREAM_WIRE_VERSION_V1);
         write_u8(&mut head, STREAM_MSG_HEAD);
         write_u16(&mut head, 200);
         write_u8(&mut head, 1);
         write_u32(&mut head, 1);
-        write_string(&mut head, "x-stream").unwrap();
-        write_string(&mut head, "yes").unwrap();
-        write_bytes(&mut head, b"head").unwrap();
+        write_bytes_v1(&mut head, b"x-stream").unwrap();
+        write_bytes_v1(&mut head, b"yes").unwrap();
+        write_bytes_v1(&mut head, b"head").unwrap();
 
-        let msg = decode_stream_msg(&head).expect("decode head");
+        let msg = decode_stream_msg(&Bytes::from(head)).expect("decode head");
         match msg {
             StreamMsg::Head {
                 status,
@@ -251,9 +891,12 @@ mod tests {
                 body,
             } => {
                 assert_eq!(status, 200);
-                assert_eq!(headers, vec![("x-stream".to_string(), "yes".to_string())]);
+                assert_eq!(
+                    header_pairs(&headers),
+                    vec![("x-stream".to_string(), "yes".to_string())]
+                );
                 assert!(is_stream);
-                assert_eq!(body, b"head");
+                assert_eq!(body, &b"head"[..]);
             }
             _ => panic!("expected head"),
         }
@@ -261,18 +904,47 @@ mod tests {
         let mut chunk = Vec::new();
         write_u8(&mut chunk, STREAM_WIRE_VERSION);
         write_u8(&mut chunk, STREAM_MSG_CHUNK);
-        write_bytes(&mut chunk, b"chunk").unwrap();
+        write_bytes(&mut chunk, b"chunk");
+        let chunk = Bytes::from(chunk);
         let msg = decode_stream_msg(&chunk).expect("decode chunk");
         match msg {
-            StreamMsg::Chunk(body) => assert_eq!(body, b"chunk"),
+            StreamMsg::Chunk(body) => {
+                assert_eq!(body, &b"chunk"[..]);
+                assert_eq!(body.as_ptr(), chunk[3..].as_ptr());
+            }
             _ => panic!("expected chunk"),
         }
 
         let mut end = Vec::new();
         write_u8(&mut end, STREAM_WIRE_VERSION);
         write_u8(&mut end, STREAM_MSG_END);
-        let msg = decode_stream_msg(&end).expect("decode end");
-        matches!(msg, StreamMsg::End);
+        let msg = decode_stream_msg(&Bytes::from(end)).expect("decode end");
+        assert!(matches!(msg, StreamMsg::End));
+    }
+
+    #[test]
+    fn decode_batched_stream_messages() {
+        let mut chunk = Vec::new();
+        write_u8(&mut chunk, STREAM_WIRE_VERSION);
+        write_u8(&mut chunk, STREAM_MSG_CHUNK);
+        write_bytes(&mut chunk, b"event");
+        let mut end = Vec::new();
+        write_u8(&mut end, STREAM_WIRE_VERSION);
+        write_u8(&mut end, STREAM_MSG_END);
+
+        let mut batch = Vec::new();
+        write_bytes_v1(&mut batch, &chunk).unwrap();
+        write_bytes_v1(&mut batch, &chunk).unwrap();
+        write_bytes_v1(&mut batch, &end).unwrap();
+        let msgs = decode_stream_msgs(&Bytes::from(batch.clone())).expect("decode batch");
+        assert_eq!(msgs.len(), 3);
+        assert!(matches!(&msgs[0], StreamMsg::Chunk(b) if b == &b"event"[..]));
+        assert!(matches!(&msgs[1], StreamMsg::Chunk(b) if b == &b"event"[..]));
+        assert!(matches!(msgs[2], StreamMsg::End));
+
+        assert!(decode_stream_msgs(&Bytes::new()).unwrap().is_empty());
+        batch.pop();
+        assert!(decode_frames(&Bytes::from(batch)).is_err());
     }
 
     #[test]
@@ -284,7 +956,7 @@ mod tests {
                 seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
                 *b = (seed >> 24) as u8;
             }
-            let _ = decode_stream_msg(&data);
+            let _ = decode_stream_msg(&Bytes::from(data));
         }
     }
 }
@@ -121,6 +121,59 @@ async fn keep_alive_multiple_requests() {
     shutdown_lean(app_id);
 }
 
+#[cfg(all(lithe_example = "hello", unix))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn tcp_and_unix_listeners_share_one_app() {
+    use lithe_shim::{serve_listeners_with_shutdown, Bind, Listener, Role};
+
+    let path = std::env::temp_dir().join(format!("lithe-it-{}.sock", std::process::id()));
+    let tcp = Listener::bind(&Bind::Tcp("127.0.0.1:0".parse().unwrap()), None).expect("bind tcp");
+    let Ok(Bind::Tcp(addr)) = tcp.local() else { panic!("tcp listener addr") };
+    let unix = Listener::bind(&Bind::Unix(path.clone()), Some(0o600)).expect("bind unix");
+    let app_id = new_app_id("hello-test");
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        let listeners = vec![(Role::Http, tcp), (Role::Http, unix)];
+        let _ = serve_listeners_with_shutdown(listeners, app_id, None, async {
+            let _ = shutdown_rx.await;
+        })
+        .await;
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let res = Client::new()
+        .get(format!("http://{addr}/hello").parse().unwrap())
+        .await
+        .expect("tcp request");
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let stream = tokio::net::UnixStream::connect(&path).await.expect("unix connect");
+    let (mut sender, conn) = conn::Builder::new().handshake(stream).await.expect("handshake");
+    let conn_task = tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let req = Request::builder()
+        .uri("/hello")
+        .header("host", "localhost")
+        .body(Body::empty())
+        .unwrap();
+    let res = sender.send_request(req).await.expect("unix request");
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = to_bytes(res.into_body()).await.expect("unix body");
+    assert_eq!(body.as_ref(), b"hello");
+    drop(sender);
+
+    let _ = shutdown.send(());
+    timeout(Duration::from_secs(5), handle)
+        .await
+        .expect("all listeners stop together")
+        .ok();
+    let _ = conn_task.await;
+    std::fs::remove_file(&path).ok();
+    sleep(Duration::from_millis(200)).await;
+    shutdown_lean(app_id);
+}
+
 #[cfg(lithe_example = "websocket")]
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn websocket_echo() {
@@ -1,3 +1,9 @@
+// Each stress test needs its example app; other builds only compile the helpers.
+#![cfg_attr(
+    not(any(lithe_example = "streaming", lithe_example = "websocket", lithe_example = "sse")),
+    allow(dead_code, unused_imports)
+)]
+
 use hyper::{body::to_bytes, Client, StatusCode};
 use lithe_shim::{new_app_id, serve_with_listener, shutdown_lean};
 use std::net::TcpListener;
@@ -13,6 +13,18 @@ def testWireRequestRoundTrip : IO Unit := do
     , headers := #[("x-one", "1"), ("x-two", "2")]
     , body := Lithe.stringToBytes "payload"
     , remote := some "127.0.0.1"
+    , metadata :=
+        { connId := some 42
+        , localAddr := some "127.0.0.1:3000"
+        , scheme := "https"
+        , httpVersion := "HTTP/2.0"
+        , rawUri := some "/api/items?q=lean&tag=web"
+        , tlsVersion := some "TLSv1.3"
+        , tlsSni := some "example.com"
+        , clientCert := some { subject := "CN=svc-a", sans := #["DNS:a.internal", "IP:10.0.0.1"], fingerprint := "ab12" }
+        , proxy := some { peer := "10.0.0.2:41000", uniqueId := some "req-7" }
+        , deadlineNanos := some 123456789000
+        }
     }
   let bytes := Lithe.encodeWireRequest req
   match Lithe.decodeWireRequest bytes with
@@ -24,6 +36,28 @@ def testWireRequestRoundTrip : IO Unit := do
       assertEqHeaders decoded.headers req.headers
       assertEqBytes decoded.body req.body "body"
       assert (decide (decoded.remote = req.remote)) "remote mismatch"
+      assert (decide (decoded.metadata.connId = some 42)) "conn id mismatch"
+      assert (decide (decoded.metadata.localAddr = req.metadata.localAddr)) "local addr mismatch"
+      assertEqString decoded.metadata.scheme "https" "scheme"
+      assertEqString decoded.metadata.httpVersion "HTTP/2.0" "http version"
+      assert (decide (decoded.metadata.rawUri = req.metadata.rawUri)) "raw uri mismatch"
+      assert (decide (decoded.metadata.tlsVersion = some "TLSv1.3")) "tls version mismatch"
+      assert (decide (decoded.metadata.tlsSni = some "example.com")) "tls sni mismatch"
+      assert (decoded.metadata.tlsCipher.isNone) "tls cipher should be absent"
+      match decoded.metadata.clientCert with
+      | some cert =>
+          assertEqString cert.subject "CN=svc-a" "client subject"
+          assert (decide (cert.sans.toList = ["DNS:a.internal", "IP:10.0.0.1"])) "client sans mismatch"
+          assertEqString cert.fingerprint "ab12" "client fingerprint"
+      | none => throw (IO.userError "client cert missing")
+      match decoded.metadata.proxy with
+      | some proxy =>
+          assertEqString proxy.peer "10.0.0.2:41000" "proxy peer"
+          assert proxy.authority.isNone "proxy authority should be absent"
+          assert (decide (proxy.uniqueId = some "req-7")) "proxy unique id mismatch"
+      | none => throw (IO.userError "proxy info missing")
+      assert (decide (decoded.metadata.deadlineNanos = some 123456789000)) "deadline mismatch"
+      assert decoded.toRequest.isSecure "request should be secure"
 
 def testWireResponseRoundTrip : IO Unit := do
   let resp : Lithe.Response :=
@@ -39,6 +73,91 @@ def testWireResponseRoundTrip : IO Unit := do
       assertEqHeaders decoded.headers wire.headers
       assertEqBytes decoded.body wire.body "body"
 
+def testWireVersions : IO Unit := do
+  let wire : Lithe.WireResponse :=
+    { status := 200
+    , headers := #[("content-type", "text/plain"), ("x-custom", "1")]
+    , body := Lithe.stringToBytes "ok"
+    }
+  let v1 := Lithe.encodeWireResponse wire (version := Lithe.wireVersionV1)
+  let v2 := Lithe.encodeWireResponse wire
+  assert (v2.size < v1.size) s!"v2 should be smaller: v1={v1.size} v2={v2.size}"
+  for bytes in [v1, v2] do
+    match Lithe.decodeWireResponse bytes with
+    | .error err => throw (IO.userError s!"decodeWireResponse failed: {err}")
+    | .ok decoded =>
+        assertEqNat decoded.status wire.status "status"
+        assertEqHeaders decoded.headers wire.headers
+        assertEqBytes decoded.body wire.body "body"
+
+  let chunk := Lithe.encodeStreamChunk (Lithe.stringToBytes "chunk") (version := Lithe.streamWireVersionV1)
+  match Lithe.decodeStreamMsg chunk with
+  | .ok (Lithe.StreamMsg.chunk body) =>
+      assertEqBytes body (Lithe.stringToBytes "chunk") "v1 stream chunk body"
+  | _ => throw (IO.userError "v1 stream chunk decode mismatch")
+
+  let req : Lithe.WireRequest :=
+    { method := Lithe.Method.ext "PROPFIND"
+    , path := "/dav"
+    , query := ""
+    , headers := #[("accept", "*/*")]
+    , body := ByteArray.empty
+    , remote := none
+    }
+  for version in [Lithe.wireVersionV1, Lithe.wireVersion] do
+    match Lithe.decodeWireRequest (Lithe.encodeWireRequest req version) with
+    | .error err => throw (IO.userError s!"v{version} decodeWireRequest failed: {err}")
+    | .ok decoded =>
+        assert (decide (decoded.method = req.method)) s!"v{version} request method"
+        assert (decoded.path == "/dav") s!"v{version} request path"
+        assertEqHeaders decoded.headers req.headers
+  -- Extension names keep their case, even when they spell a standard method.
+  for name in ["propfind", "get"] do
+    let req := { req with method := Lithe.Method.ext name }
+    match Lithe.decodeWireRequest (Lithe.encodeWireRequest req) with
+    | .ok decoded => assert (decide (decoded.method = req.method)) s!"ext method {name}"
+    | .error err => throw (IO.userError s!"decodeWireRequest {name} failed: {err}")
+
+def testRawHeaders : IO Unit := do
+  let latin1 := ByteArray.mk #[0x63, 0x61, 0x66, 0xE9]
+  let req : Lithe.WireRequest :=
+    { method := Lithe.Method.GET
+    , path := "/"
+    , query := ""
+    , headers := #[]
+    , body := ByteArray.empty
+    , rawHeaders := #[("x-name", latin1), ("cookie", Lithe.stringToBytes "a=1"), ("cookie", Lithe.stringToBytes "b=2")]
+    }
+  match Lithe.decodeWireRequest (Lithe.encodeWireRequest req) with
+  | .error err => throw (IO.userError s!"decodeWireRequest failed: {err}")
+  | .ok decoded =>
+      assertEqNat decoded.rawHeaders.size 3 "raw header count"
+      assertEqBytes decoded.rawHeaders[0]!.snd latin1 "raw header value"
+      assertEqHeaders decoded.headers #[("cookie", "a=1"), ("cookie", "b=2")]
+      match decoded.toRequest.rawHeader? "X-Name" with
+      | some v => assertEqBytes v latin1 "rawHeader? lookup"
+      | none => throw (IO.userError "rawHeader? missing x-name")
+
+  let resp := (Lithe.Response.text "ok").withRawHeader "x-name" latin1
+  match Lithe.decodeWireResponse (Lithe.encodeWireResponse (Lithe.WireResponse.ofResponse resp)) with
+  | .error err => throw (IO.userError s!"decodeWireResponse failed: {err}")
+  | .ok decoded =>
+      assertEqHeaders decoded.headers resp.headers
+      assertEqNat decoded.rawHeaders.size 1 "response raw header count"
+      assertEqBytes decoded.rawHeaders[0]!.snd latin1 "response raw header value"
+
+def testHandshakeRoundTrip : IO Unit := do
+  let h : Lithe.Handshake :=
+    { libraryVersion := "1.2.3", capabilities := Lithe.capBatchPoll ||| Lithe.capNotify }
+  match Lithe.decodeHandshake (Lithe.encodeHandshake h) with
+  | .error err => throw (IO.userError s!"decodeHandshake failed: {err}")
+  | .ok decoded =>
+      assert (decoded.libraryVersion == "1.2.3") "handshake library version"
+      assertEqNat decoded.wireMin.toNat Lithe.wireVersionV1.toNat "handshake wire min"
+      assertEqNat decoded.wireMax.toNat Lithe.wireVersion.toNat "handshake wire max"
+      assertEqNat decoded.streamWireMax.toNat Lithe.streamWireVersion.toNat "handshake stream max"
+      assertEqNat decoded.capabilities.toNat 3 "handshake capabilities"
+
 def testStreamMessageRoundTrip : IO Unit := do
   let headBytes := Lithe.encodeStreamHead 200 #[("x-stream", "yes")] true (Lithe.stringToBytes "head")
   match Lithe.decodeStreamMsg headBytes with
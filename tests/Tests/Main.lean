@@ -23,22 +23,30 @@ def main : IO Unit := do
     [ ("router.parse", testRouteParse)
     , ("router.match.success", testRouteMatchSuccess)
     , ("router.match.failure", testRouteMatchFailure)
+    , ("router.method_not_allowed", testMethodNotAllowed)
+    , ("router.head_and_options", testHeadAndOptionsRouting)
     , ("request.query.parse", testQueryParse)
     , ("middleware.identity", testIdentity)
     , ("middleware.compose.headers", testComposeAddsHeaders)
     , ("middleware.error.propagation", testErrorPropagation)
+    , ("middleware.auth.client_cert", testClientCertAuth)
     , ("property.method.roundtrip", testMethodRoundTrip)
     , ("property.response.header", testResponseSetHeader)
     , ("codec.wire.request", testWireRequestRoundTrip)
     , ("codec.wire.response", testWireResponseRoundTrip)
+    , ("codec.wire.versions", testWireVersions)
+    , ("codec.wire.handshake", testHandshakeRoundTrip)
+    , ("codec.wire.raw_headers", testRawHeaders)
     , ("codec.stream", testStreamMessageRoundTrip)
     , ("codec.websocket", testWebSocketMessageRoundTrip)
     , ("stream.queue", testBodyStreamQueue)
     , ("stream.cancel", testBodyStreamCancel)
+    , ("stream.cancel.deadline", testCancelTokenDeadline)
     , ("writer.cancel", testBodyWriterCancel)
     , ("streamqueue.order", testStreamQueueOrder)
     , ("streamqueue.capacity", testStreamQueueCapacity)
     , ("streamqueue.close", testStreamQueueClose)
+    , ("streamqueue.hooks", testStreamQueueHooks)
     , ("form.parse", testFormParse)
     , ("form.invalid", testFormInvalid)
     , ("multipart.parse", testMultipartParse)
@@ -4,6 +4,7 @@ import Lithe.Http.Request
 import Lithe.Http.Method
 import Lithe.Http.Response
 import Lithe.Core.Error
+import Lithe.Middleware.Auth
 import Tests.Util
 
 def sampleRequest : Lithe.Request :=
@@ -52,3 +53,28 @@ def testErrorPropagation : IO Unit := do
   | .ok _ => throw (IO.userError "middleware should not swallow errors")
   | .error err =>
       assertEqString err.code "bad_request" "error code"
+
+def testClientCertAuth : IO Unit := do
+  let cert : Lithe.ClientCert :=
+    { subject := "CN=svc-a", sans := #["DNS:svc-a.internal"], fingerprint := "ab12" }
+  let allowed : Lithe.ClientCert → IO Bool := fun c => pure (c.subject == "CN=svc-a")
+  let subjectHandler : Lithe.Handler := fun ctx => do
+    match ctx.state.get? Lithe.authStateKey >>= Dynamic.get? Lithe.AuthInfo with
+    | some info => return Lithe.Response.text s!"{info.scheme}:{info.token}:{info.subject.getD ""}"
+    | none => throw (Lithe.HttpError.internal "auth info missing")
+  let secured := Lithe.clientCert allowed subjectHandler
+  let req := { sampleRequest with metadata := { scheme := "https", clientCert := some cert } }
+  match ← (secured (Lithe.RequestCtx.ofRequest req)).run with
+  | .ok resp =>
+      let body ← bodyString resp
+      assertEqString body "mtls:ab12:CN=svc-a" "client cert auth info"
+  | .error err => throw (IO.userError s!"client cert auth failed: {err.code}")
+
+  match ← (secured sampleCtx).run with
+  | .ok _ => throw (IO.userError "missing client cert should be rejected")
+  | .error err => assertEqNat err.status.toNat 401 "missing client cert status"
+
+  let other := { sampleRequest with metadata := { clientCert := some { cert with subject := "CN=svc-b" } } }
+  match ← (secured (Lithe.RequestCtx.ofRequest other)).run with
+  | .ok _ => throw (IO.userError "disallowed client cert should be rejected")
+  | .error err => assertEqNat err.status.toNat 403 "disallowed client cert status"
@@ -1,5 +1,7 @@
 import Lithe.Router.Path
 import Lithe.Router.Match
+import Lithe.Router.Builder
+import Lithe.Test.Harness
 import Lithe.Http.Request
 import Lithe.Http.Method
 import Tests.Util
@@ -25,6 +27,37 @@ def testRouteMatchFailure : IO Unit := do
   let result := Lithe.matchRoute pat "/users"
   assert (result.isNone) "route should not match missing param"
 
+def testMethodNotAllowed : IO Unit := do
+  let ok : Lithe.Handler := fun _ => pure (Lithe.Response.text "ok")
+  let router := Lithe.Router.empty
+    |> Lithe.Router.get "/items" ok
+    |> Lithe.Router.post "/items" ok
+    |> Lithe.Router.get "/items/:id" ok
+  let req (method : Lithe.Method) (path : String) : Lithe.Request :=
+    { method := method, path := path, query := "", headers := #[], body := ByteArray.empty }
+  let resp ← Lithe.run router (req .DELETE "/items")
+  assertEqNat resp.status.code.toNat 405 "unrouted method status"
+  assertHeader resp "allow" "GET, POST"
+  let resp ← Lithe.run router (req (.ext "PROPFIND") "/items/7")
+  assertHeader resp "allow" "GET"
+  let resp ← Lithe.run router (req .DELETE "/missing")
+  assertEqNat resp.status.code.toNat 404 "unknown path status"
+
+def testHeadAndOptionsRouting : IO Unit := do
+  let ok : Lithe.Handler := fun _ => pure (Lithe.Response.text "ok")
+  let preflight : Lithe.Handler := fun _ => pure (Lithe.Response.text "preflight")
+  let router := Lithe.Router.empty
+    |> Lithe.Router.get "/items" ok
+    |> Lithe.Router.withFallback preflight
+  let req (method : Lithe.Method) (path : String) : Lithe.Request :=
+    { method := method, path := path, query := "", headers := #[], body := ByteArray.empty }
+  let resp ← Lithe.run router (req .HEAD "/items")
+  assertEqString (← bodyString resp) "ok" "HEAD served by the GET route"
+  let resp ← Lithe.run router (req .OPTIONS "/items")
+  assertEqString (← bodyString resp) "preflight" "OPTIONS reaches the fallback"
+  let resp ← Lithe.run router (req .PUT "/items")
+  assertEqNat resp.status.code.toNat 405 "other methods still get 405"
+
 def testQueryParse : IO Unit := do
   let req : Lithe.Request :=
     { method := Lithe.Method.GET
@@ -45,3 +45,20 @@ def testStreamQueueClose : IO Unit := do
   assert (!ok2) "push after close should fail"
   let closed ← Lithe.StreamQueue.isClosed q
   assert closed "queue should be closed"
+
+def testStreamQueueHooks : IO Unit := do
+  let readable ← IO.mkRef 0
+  let writable ← IO.mkRef 0
+  let q ← Lithe.StreamQueue.new 4
+    (onReadable := readable.modify (· + 1))
+    (onWritable := writable.modify (· + 1))
+  let _ ← Lithe.StreamQueue.push q (Lithe.stringToBytes "a")
+  assertEqNat (← readable.get) 1 "readable after push"
+  assertEqNat (← writable.get) 0 "writable after push"
+  let _ ← Lithe.StreamQueue.pop? q
+  assertEqNat (← writable.get) 1 "writable after pop"
+  let _ ← Lithe.StreamQueue.pop? q
+  assertEqNat (← writable.get) 1 "writable after empty pop"
+  Lithe.StreamQueue.close q
+  assertEqNat (← readable.get) 2 "readable after close"
+  assertEqNat (← writable.get) 2 "writable after close"
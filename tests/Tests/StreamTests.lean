@@ -36,6 +36,14 @@ def testBodyStreamCancel : IO Unit := do
   | .error err =>
       assertEqNat err.status.toNat 499 "cancel status"
 
+def testCancelTokenDeadline : IO Unit := do
+  let now ← IO.monoNanosNow
+  let token ← Lithe.CancelToken.withDeadline Lithe.CancelToken.never (now + 20000000)
+  assert (!(← token.isCanceled)) "token canceled before its deadline"
+  IO.sleep 30
+  assert (← token.isCanceled) "token not canceled after its deadline"
+  assert (!(← Lithe.CancelToken.never.isCanceled)) "never token was canceled"
+
 def testBodyWriterCancel : IO Unit := do
   let token ← Lithe.CancelToken.new
   let writer := Lithe.BodyWriter.withCancel Lithe.BodyWriter.discard token